@@ -0,0 +1,144 @@
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use quarry::stores::{CompressedBlockstore, Compression};
+use quarry::{Blockstore, MemoryBlockstore, Quarry};
+use rand::prelude::*;
+use std::fs;
+use std::path::Path;
+use wiresaw::{ChunkReader, DagBuilder};
+
+const BLOCKS: usize = 10_000;
+
+fn prepare_store(path: &Path) -> (Quarry, Vec<Cid>) {
+    let _ = fs::remove_dir_all(path);
+    let quarry = Quarry::open(path).expect("failed to open store");
+    let blocks: Vec<(Cid, Vec<u8>)> = (0..BLOCKS)
+        .map(|_| {
+            let mut block = vec![0u8; 1024];
+            thread_rng().fill(&mut block[..]);
+            (Cid::new_v1(0x55, Code::Sha2_256.digest(&block)), block)
+        })
+        .collect();
+    let cids = blocks.iter().map(|(cid, _)| *cid).collect();
+    quarry.put_many_keyed(blocks).expect("failed to fill store");
+    (quarry, cids)
+}
+
+fn bench_block_size(c: &mut Criterion) {
+    let path = std::env::temp_dir().join("quarry_bench_block_size");
+    let (quarry, cids) = prepare_store(&path);
+
+    let mut group = c.benchmark_group("block_size");
+    group.bench_function("get_block_size", |b| {
+        b.iter(|| {
+            let cid = cids.choose(&mut thread_rng()).unwrap();
+            quarry.get_block_size(cid).expect("failed to read size")
+        })
+    });
+    group.bench_function("get", |b| {
+        b.iter(|| {
+            let cid = cids.choose(&mut thread_rng()).unwrap();
+            quarry
+                .get(cid)
+                .expect("failed to read block")
+                .map(|block| block.len())
+        })
+    });
+    group.finish();
+
+    drop(quarry);
+    let _ = fs::remove_dir_all(&path);
+}
+
+/// Blocks of a DAG built over English text: raw text leaves and dag-cbor
+/// nodes.
+fn prepare_text_blocks() -> Vec<(Cid, Vec<u8>)> {
+    let text = include_bytes!("../wiresaw/src/fixture.txt").repeat(64);
+    let mut reader = ChunkReader::with_chunk_size(16 << 10, &text[..]);
+    reader.set_content_size(text.len() as u64);
+    let store = MemoryBlockstore::new();
+    DagBuilder::new(reader, &store)
+        .trickle()
+        .expect("failed to build dag");
+    store
+        .cids()
+        .into_iter()
+        .map(|cid| (cid, Blockstore::get(&store, &cid).unwrap().unwrap()))
+        .collect()
+}
+
+fn prepare_random_blocks(count: usize) -> Vec<(Cid, Vec<u8>)> {
+    (0..count)
+        .map(|_| {
+            let mut block = vec![0u8; 16 << 10];
+            thread_rng().fill(&mut block[..]);
+            (Cid::new_v1(0x55, Code::Sha2_256.digest(&block)), block)
+        })
+        .collect()
+}
+
+/// Puts every block through `store` and returns how many bytes the
+/// wrapped store ended up holding.
+fn put_all<S: Blockstore>(store: &CompressedBlockstore<S>, blocks: &[(Cid, Vec<u8>)]) -> usize {
+    let mut stored = 0;
+    for (cid, block) in blocks {
+        store.put_keyed(cid, block).expect("failed to put block");
+        stored += Blockstore::get(store.inner(), cid).unwrap().unwrap().len();
+    }
+    stored
+}
+
+fn bench_compression(c: &mut Criterion) {
+    let text = prepare_text_blocks();
+    let random = prepare_random_blocks(text.len());
+    let path = std::env::temp_dir().join("quarry_bench_compression");
+
+    let mut group = c.benchmark_group("compression");
+    for (data, blocks) in [("text", &text), ("random", &random)] {
+        let bytes: usize = blocks.iter().map(|(_, block)| block.len()).sum();
+        group.throughput(Throughput::Bytes(bytes as u64));
+        for compression in [
+            Compression::None,
+            Compression::Zstd(3),
+            Compression::Zstd(19),
+            Compression::Lz4,
+        ] {
+            let memory = CompressedBlockstore::new(MemoryBlockstore::new(), compression);
+            let stored = put_all(&memory, blocks);
+            eprintln!(
+                "{} {:?}: {} bytes stored for {} ({:.1}%)",
+                data,
+                compression,
+                stored,
+                bytes,
+                stored as f64 * 100.0 / bytes as f64
+            );
+
+            let id = format!("{}/{:?}", data, compression);
+            group.bench_with_input(BenchmarkId::new("memory", &id), blocks, |b, blocks| {
+                b.iter_batched(
+                    || CompressedBlockstore::new(MemoryBlockstore::new(), compression),
+                    |store| put_all(&store, blocks),
+                    BatchSize::SmallInput,
+                );
+            });
+            group.bench_with_input(BenchmarkId::new("quarry", &id), blocks, |b, blocks| {
+                b.iter_batched(
+                    || {
+                        let _ = fs::remove_dir_all(&path);
+                        let quarry = Quarry::open(&path).expect("failed to open store");
+                        CompressedBlockstore::new(quarry, compression)
+                    },
+                    |store| put_all(&store, blocks),
+                    BatchSize::PerIteration,
+                );
+            });
+        }
+    }
+    group.finish();
+    let _ = fs::remove_dir_all(&path);
+}
+
+criterion_group!(benches, bench_block_size, bench_compression);
+criterion_main!(benches);
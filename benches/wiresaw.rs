@@ -1,4 +1,5 @@
 use anyhow::Result;
+use cid::multihash::Code;
 use cid::Cid;
 use criterion::BenchmarkId;
 use criterion::Criterion;
@@ -6,7 +7,9 @@ use criterion::{criterion_group, criterion_main, BatchSize, Throughput};
 use rand::prelude::*;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use wiresaw::{ChunkReader, DagBuilder, Storer};
+use std::fs::{self, File};
+use wiresaw::mmap::{MappedFile, MmapChunkReader};
+use wiresaw::{ChunkReader, DagBuilder, FastCdcChunker, Storer};
 
 #[derive(Debug, Default, Clone)]
 struct MemoryBlockstore {
@@ -56,5 +59,139 @@ fn bench_dag_builder(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_dag_builder);
+/// Prints the number of chunks and the spread of their sizes.
+fn report_chunks(name: &str, chunks: impl Iterator<Item = Vec<u8>>) {
+    let sizes: Vec<f64> = chunks.map(|chunk| chunk.len() as f64).collect();
+    let mean = sizes.iter().sum::<f64>() / sizes.len() as f64;
+    let variance = sizes.iter().map(|size| (size - mean).powi(2)).sum::<f64>() / sizes.len() as f64;
+    eprintln!(
+        "{}: {} chunks, mean {:.0} bytes, std dev {:.0} bytes",
+        name,
+        sizes.len(),
+        mean,
+        variance.sqrt()
+    );
+}
+
+fn bench_chunkers(c: &mut Criterion) {
+    static SIZE: usize = 64 * 1024 * 1024;
+    static AVG: usize = 1 << 18;
+
+    let data = prepare_rand_data(SIZE);
+    let fixed = || {
+        let mut reader = ChunkReader::with_chunk_size(AVG, &data[..]);
+        reader.set_content_size(SIZE as u64);
+        reader
+    };
+    let fastcdc = || FastCdcChunker::new(&data[..], AVG / 4, AVG, AVG * 4);
+    report_chunks("fixed", fixed());
+    report_chunks("fastcdc", fastcdc());
+
+    let mut group = c.benchmark_group("chunkers");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.bench_function("fixed", |b| {
+        b.iter(|| {
+            DagBuilder::new(fixed(), MemoryBlockstore::new())
+                .trickle()
+                .expect("failed to compute dag root")
+        });
+    });
+    group.bench_function("fastcdc", |b| {
+        b.iter(|| {
+            DagBuilder::new(fastcdc(), MemoryBlockstore::new())
+                .trickle()
+                .expect("failed to compute dag root")
+        });
+    });
+    group.finish();
+}
+
+/// Folds over every byte so both readers pay for touching the data.
+fn checksum(chunk: &[u8]) -> u8 {
+    chunk.iter().fold(0, |acc, byte| acc ^ byte)
+}
+
+fn bench_mmap(c: &mut Criterion) {
+    static SIZE: usize = 256 * 1024 * 1024;
+
+    let path = std::env::temp_dir().join("wiresaw_bench_mmap");
+    fs::write(&path, prepare_rand_data(SIZE)).expect("failed to write bench file");
+
+    let mut group = c.benchmark_group("file_chunking");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.bench_function("read", |b| {
+        b.iter(|| {
+            ChunkReader::<File>::from_file(&path)
+                .expect("failed to open bench file")
+                .fold(0, |acc, chunk| acc ^ checksum(&chunk))
+        });
+    });
+    group.bench_function("mmap", |b| {
+        b.iter(|| {
+            // nothing writes the bench file while it is mapped
+            let file = unsafe { MappedFile::open(&path) }.expect("failed to map bench file");
+            MmapChunkReader::new(&file).fold(0, |acc, chunk| acc ^ checksum(chunk))
+        });
+    });
+    group.finish();
+    let _ = fs::remove_file(&path);
+}
+
+fn bench_hash_fns(c: &mut Criterion) {
+    static SIZE: usize = 64 * 1024 * 1024;
+
+    let data = prepare_rand_data(SIZE);
+    let mut group = c.benchmark_group("hash_fns");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    for code in [Code::Sha2_256, Code::Sha3_256, Code::Blake3_256] {
+        group.bench_function(format!("{:?}", code), |b| {
+            b.iter(|| {
+                let mut reader = ChunkReader::new(&data[..]);
+                reader.set_content_size(SIZE as u64);
+                let mut dag = DagBuilder::new(reader, MemoryBlockstore::new());
+                dag.set_hash_fn(code);
+                dag.trickle().expect("failed to compute dag root")
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_parallel_hashing(c: &mut Criterion) {
+    static SIZE: usize = 256 * 1024 * 1024;
+
+    let data = prepare_rand_data(SIZE);
+    let mut group = c.benchmark_group("parallel_hashing");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            let mut reader = ChunkReader::new(&data[..]);
+            reader.set_content_size(SIZE as u64);
+            let mut dag = DagBuilder::new(reader, MemoryBlockstore::new());
+            dag.trickle().expect("failed to compute dag root")
+        });
+    });
+    group.bench_function("parallel", |b| {
+        b.iter(|| {
+            let mut reader = ChunkReader::new(&data[..]);
+            reader.set_content_size(SIZE as u64);
+            let mut dag = DagBuilder::new(reader, MemoryBlockstore::new());
+            dag.trickle_parallel().expect("failed to compute dag root")
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_dag_builder,
+    bench_chunkers,
+    bench_mmap,
+    bench_hash_fns,
+    bench_parallel_hashing
+);
 criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! An append-only log of block accesses, one JSON object per line, written
+//! from a background thread so operations never wait on it.
+
+use anyhow::Result;
+use cid::Cid;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) struct AccessLog {
+    sender: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    /// Opens `path` for appending and starts the thread writing to it.
+    pub(crate) fn open(path: &Path) -> Result<AccessLog> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::channel::<String>();
+        let writer = thread::spawn(move || {
+            for line in receiver {
+                // a failing log must not take the store down with it
+                let _ = file.write_all(line.as_bytes());
+            }
+        });
+        Ok(AccessLog {
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Queues a line recording `op` on `cid`, `hit` telling whether the
+    /// block was present.
+    pub(crate) fn record(&self, op: &str, cid: &Cid, hit: bool) {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let line = format!(
+            "{{\"ts\":{},\"op\":\"{}\",\"cid\":\"{}\",\"hit\":{}}}\n",
+            ts, op, cid, hit
+        );
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(line);
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    /// Waits for every queued line to be written.
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
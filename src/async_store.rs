@@ -0,0 +1,144 @@
+//! An asynchronous counterpart of `Blockstore`, for callers running on an
+//! executor that must not block on disk reads and writes.
+
+use crate::Blockstore;
+use anyhow::Result;
+use async_trait::async_trait;
+use cid::Cid;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::task::spawn_blocking;
+
+/// The methods of `Blockstore`, as async fns.
+#[async_trait]
+pub trait AsyncBlockstore: Send + Sync {
+    /// Delete a block from the blockstore.
+    async fn delete_block(&self, k: &Cid) -> Result<()>;
+
+    /// Gets the block from the blockstore.
+    async fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>>;
+
+    /// Put a block with a pre-computed cid.
+    async fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()>;
+
+    /// Checks if the blockstore has the specified block.
+    async fn has(&self, k: &Cid) -> Result<bool>;
+
+    /// Bulk-put pre-keyed blocks into the blockstore.
+    async fn put_many_keyed(&self, blocks: Vec<(Cid, Vec<u8>)>) -> Result<()>;
+}
+
+/// Runs every call on tokio's blocking thread pool. `spawn_blocking` only
+/// takes `'static` closures, so the store is shared with it through the
+/// `Arc` rather than borrowed.
+#[async_trait]
+impl<T> AsyncBlockstore for Arc<T>
+where
+    T: Blockstore + Send + Sync + 'static,
+{
+    async fn delete_block(&self, k: &Cid) -> Result<()> {
+        let (store, k) = (self.clone(), *k);
+        spawn_blocking(move || T::delete_block(&store, &k)).await?
+    }
+
+    async fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        let (store, k) = (self.clone(), *k);
+        spawn_blocking(move || T::get(&store, &k)).await?
+    }
+
+    async fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        let (store, k, block) = (self.clone(), *k, block.to_vec());
+        spawn_blocking(move || T::put_keyed(&store, &k, &block)).await?
+    }
+
+    async fn has(&self, k: &Cid) -> Result<bool> {
+        let (store, k) = (self.clone(), *k);
+        spawn_blocking(move || T::has(&store, &k)).await?
+    }
+
+    async fn put_many_keyed(&self, blocks: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+        let store = self.clone();
+        spawn_blocking(move || T::put_many_keyed(&store, blocks)).await?
+    }
+}
+
+/// A blockstore keeping every block in memory behind an async lock, so
+/// none of its methods ever block a thread.
+#[derive(Debug, Default)]
+pub struct AsyncMemoryBlockstore {
+    blocks: RwLock<HashMap<Cid, Vec<u8>>>,
+}
+
+impl AsyncMemoryBlockstore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl AsyncBlockstore for AsyncMemoryBlockstore {
+    async fn delete_block(&self, k: &Cid) -> Result<()> {
+        self.blocks.write().await.remove(k);
+        Ok(())
+    }
+
+    async fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.blocks.read().await.get(k).cloned())
+    }
+
+    async fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.blocks.write().await.insert(*k, block.into());
+        Ok(())
+    }
+
+    async fn has(&self, k: &Cid) -> Result<bool> {
+        Ok(self.blocks.read().await.contains_key(k))
+    }
+
+    async fn put_many_keyed(&self, blocks: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+        self.blocks.write().await.extend(blocks);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::with_instance;
+    use cid::multihash::{Code, MultihashDigest};
+    use std::future::Future;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    async fn round_trip(store: &impl AsyncBlockstore) {
+        let blocks: Vec<(Cid, Vec<u8>)> = (0..100u32)
+            .map(|i| {
+                let block = i.to_be_bytes().to_vec();
+                (Cid::new_v1(0x55, Code::Sha2_256.digest(&block)), block)
+            })
+            .collect();
+        store.put_many_keyed(blocks[1..].to_vec()).await.unwrap();
+        let (cid, block) = &blocks[0];
+        assert!(!store.has(cid).await.unwrap());
+        store.put_keyed(cid, block).await.unwrap();
+        for (cid, block) in &blocks {
+            assert_eq!(store.get(cid).await.unwrap().as_ref(), Some(block));
+        }
+        store.delete_block(cid).await.unwrap();
+        assert_eq!(store.get(cid).await.unwrap(), None);
+        assert!(store.has(&blocks[1].0).await.unwrap());
+    }
+
+    #[test]
+    fn async_round_trip() {
+        block_on(round_trip(&AsyncMemoryBlockstore::new()));
+
+        with_instance(|quarry| block_on(round_trip(&Arc::new(quarry))));
+    }
+}
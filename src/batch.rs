@@ -0,0 +1,179 @@
+use crate::{compression, storage_key, ObjectId, Page, Quarry};
+use anyhow::Result;
+use cid::Cid;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::Mutex;
+
+/// Accumulates puts and deletes in memory until they are committed together
+/// with `Quarry::commit_batch`. It can be shared between threads; when a key
+/// is written more than once the last write wins.
+#[derive(Debug, Default)]
+pub struct BatchWriter {
+    ops: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl BatchWriter {
+    pub fn put_keyed(&self, k: &Cid, block: &[u8]) {
+        self.ops
+            .lock()
+            .unwrap()
+            .insert(storage_key(k), Some(compression::encode_plain(block)));
+    }
+
+    /// Queues a value already in its stored form.
+    pub(crate) fn put_stored(&self, key: Vec<u8>, stored: Vec<u8>) {
+        self.ops.lock().unwrap().insert(key, Some(stored));
+    }
+
+    pub fn delete_block(&self, k: &Cid) {
+        self.ops.lock().unwrap().insert(storage_key(k), None);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Writes started with `Quarry::begin_batch`. They are buffered in a
+/// `BatchWriter` and applied in one marble write batch on `commit`, or every
+/// time `QuarryConfig::with_max_batch_ops` writes have queued up when a limit
+/// is configured. Dropping the handle discards whatever hasn't been flushed.
+pub struct BatchHandle<'a> {
+    quarry: &'a Quarry,
+    writer: BatchWriter,
+    stats: BatchStats,
+}
+
+impl BatchHandle<'_> {
+    pub fn put_keyed(&mut self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.writer.put_keyed(k, block);
+        self.maybe_flush()
+    }
+
+    pub fn delete_block(&mut self, k: &Cid) -> Result<()> {
+        self.writer.delete_block(k);
+        self.maybe_flush()
+    }
+
+    /// Writes queued since the last flush.
+    pub fn pending(&self) -> usize {
+        self.writer.len()
+    }
+
+    /// Counters summed over every flush so far.
+    pub fn stats(&self) -> &BatchStats {
+        &self.stats
+    }
+
+    /// Applies the remaining writes.
+    pub fn commit(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    fn maybe_flush(&mut self) -> Result<()> {
+        match self.quarry.inner.config.max_batch_ops {
+            Some(limit) if self.writer.len() >= limit => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.writer.is_empty() {
+            return Ok(());
+        }
+        let writer = std::mem::take(&mut self.writer);
+        let stats = self.quarry.commit_batch(writer)?;
+        self.stats.inserts += stats.inserts;
+        self.stats.deletes += stats.deletes;
+        self.stats.pages_modified += stats.pages_modified;
+        Ok(())
+    }
+}
+
+/// Counters describing a committed batch.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchStats {
+    pub inserts: usize,
+    pub deletes: usize,
+    pub pages_modified: usize,
+}
+
+impl Quarry {
+    /// Starts an empty batch of writes.
+    pub fn open_batch(&self) -> BatchWriter {
+        BatchWriter::default()
+    }
+
+    /// Starts buffering writes that `BatchHandle::commit` applies together,
+    /// instead of paying one marble write batch per `put_keyed`.
+    pub fn begin_batch(&self) -> BatchHandle<'_> {
+        BatchHandle {
+            quarry: self,
+            writer: BatchWriter::default(),
+            stats: BatchStats::default(),
+        }
+    }
+
+    /// Applies every write of `batch` and persists all modified pages in a
+    /// single marble write batch, so either all of them land or none do.
+    pub fn commit_batch(&self, batch: BatchWriter) -> Result<BatchStats> {
+        let ops = batch.ops.into_inner().unwrap();
+        let mut stats = BatchStats::default();
+        let horizon = self.oldest_snapshot();
+
+        let mut index = self.inner.index.write().unwrap();
+        let mut pages: HashMap<ObjectId, Page> = HashMap::new();
+        let mut pending: HashMap<u64, u64> = HashMap::new();
+        let mut deltas = Vec::with_capacity(ops.len());
+        for (key, value) in ops {
+            index.check_writable(&key)?;
+            let object_id = index.page_for(&key);
+            let page = match pages.entry(object_id) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(self.read_page(object_id)?),
+            };
+            let codec = self.quota_codec(&key);
+            let added = value.as_ref().map_or(0, |v| v.len() as u64);
+            match &value {
+                Some(_) => stats.inserts += 1,
+                None => stats.deletes += 1,
+            }
+            if let Some(codec) = codec {
+                let pending = pending.entry(codec).or_default();
+                let used = self.inner.codec_usage[&codec].load(SeqCst) + *pending;
+                if page.get(&key).is_none() {
+                    self.check_codec_quota(codec, used, added)?;
+                    *pending += added;
+                }
+            }
+            let seq = self.inner.seq.fetch_add(1, SeqCst) + 1;
+            let delete = value.is_none();
+            let previous = page.insert(key.clone(), seq, value, horizon);
+            let existed = previous.is_some();
+            let removed = previous.map_or(0, |v| v.len() as u64);
+            deltas.push((key, delete, existed, codec, added, removed));
+        }
+
+        stats.pages_modified = pages.len();
+        let mut write_batch = HashMap::new();
+        for (object_id, page) in pages {
+            self.write_page(&mut index, object_id, page, &mut write_batch)?;
+        }
+        self.write_heap(write_batch)?;
+        for (_, _, _, codec, added, removed) in &deltas {
+            self.account(*codec, *added, *removed);
+        }
+        drop(index);
+
+        for (key, delete, existed, ..) in deltas {
+            self.track_key(&key, delete, existed);
+        }
+
+        Ok(stats)
+    }
+}
@@ -0,0 +1,71 @@
+//! A counting bloom filter over the stored keys, letting `has` answer for
+//! absent blocks without reading a page.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
+
+/// Counters set for each key.
+const HASHES: u64 = 4;
+
+/// Counters are bumped for every inserted key and decremented for every
+/// removed one, so keys can leave the filter. A counter that saturates stays
+/// saturated: the filter may then claim absent keys are present, but never
+/// the other way around.
+pub(crate) struct BloomFilter {
+    counters: Vec<AtomicU8>,
+}
+
+impl BloomFilter {
+    pub(crate) fn new(counters: usize) -> BloomFilter {
+        BloomFilter {
+            counters: (0..counters).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    pub(crate) fn insert(&self, key: &[u8]) {
+        for slot in self.slots(key) {
+            let _ = self.counters[slot].fetch_update(SeqCst, SeqCst, |count| {
+                (count < u8::MAX).then_some(count + 1)
+            });
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &[u8]) {
+        for slot in self.slots(key) {
+            let _ = self.counters[slot].fetch_update(SeqCst, SeqCst, |count| {
+                (count > 0 && count < u8::MAX).then(|| count - 1)
+            });
+        }
+    }
+
+    /// `false` only if `key` is definitely not stored.
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        self.counters.is_empty()
+            || self
+                .slots(key)
+                .all(|slot| self.counters[slot].load(SeqCst) > 0)
+    }
+
+    pub(crate) fn clear(&self) {
+        for counter in &self.counters {
+            counter.store(0, SeqCst);
+        }
+    }
+
+    /// Double hashing: the i-th slot is `h1 + i * h2`.
+    fn slots(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let len = self.counters.len() as u64;
+        let (h1, h2) = (hash(key, 0), hash(key, 1) | 1);
+        // an empty filter has no slots to check
+        let hashes = if len == 0 { 0 } else { HASHES };
+        (0..hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+fn hash(key: &[u8], seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
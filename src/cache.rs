@@ -0,0 +1,69 @@
+//! A least-recently-used cache of deserialized pages, sparing reads the
+//! marble lookup and deserialization of hot pages.
+
+use crate::{ObjectId, Page};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+pub(crate) struct PageCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // each page with the tick of its last use
+    pages: HashMap<ObjectId, (Arc<Page>, u64)>,
+    // pages by the tick of their last use, oldest first
+    recency: BTreeMap<u64, ObjectId>,
+    tick: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, object_id: ObjectId, last_used: u64) -> u64 {
+        self.recency.remove(&last_used);
+        self.tick += 1;
+        self.recency.insert(self.tick, object_id);
+        self.tick
+    }
+}
+
+impl PageCache {
+    /// Keeps up to `capacity` pages; zero disables caching.
+    pub(crate) fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, object_id: ObjectId) -> Option<Arc<Page>> {
+        let mut state = self.state.lock().unwrap();
+        let (page, last_used) = state.pages.get(&object_id).cloned()?;
+        let tick = state.touch(object_id, last_used);
+        state.pages.insert(object_id, (page.clone(), tick));
+        Some(page)
+    }
+
+    pub(crate) fn insert(&self, object_id: ObjectId, page: Arc<Page>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let last_used = state.pages.get(&object_id).map_or(0, |(_, tick)| *tick);
+        let tick = state.touch(object_id, last_used);
+        state.pages.insert(object_id, (page, tick));
+        while state.pages.len() > self.capacity {
+            let (_, evicted) = state.recency.pop_first().expect("every page has a tick");
+            state.pages.remove(&evicted);
+        }
+    }
+
+    /// Drops `object_id`, to be called before it is overwritten or deleted.
+    pub(crate) fn invalidate(&self, object_id: ObjectId) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, last_used)) = state.pages.remove(&object_id) {
+            state.recency.remove(&last_used);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! CARv1 archives of the DAGs held in a store.
+
+use crate::maintenance::digest_matches;
+use crate::walk::DagWalker;
+use crate::{Blockstore, Quarry};
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use std::io::{Read, Write};
+use wiresaw::codec::car::{
+    encode_car_v1_block, encode_car_v1_header, read_car_v1_block, read_car_v1_header,
+};
+
+/// Lazily encodes a CARv1 archive of every block reachable from a set of
+/// roots: the header frame first, then one block frame per call to `next`
+/// in depth-first order.
+pub struct CarEncoder<'a, S> {
+    header: Option<Vec<u8>>,
+    walker: DagWalker<'a, S>,
+}
+
+impl<'a, S: Blockstore> CarEncoder<'a, S> {
+    pub fn new(roots: &[Cid], store: &'a S) -> CarEncoder<'a, S> {
+        CarEncoder {
+            header: Some(encode_car_v1_header(roots)),
+            walker: DagWalker::new(store, roots),
+        }
+    }
+}
+
+impl<'a, S: Blockstore> Iterator for CarEncoder<'a, S> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(header) = self.header.take() {
+            return Some(Ok(header));
+        }
+        let entry = self.walker.next()?;
+        Some(entry.map(|(cid, block)| encode_car_v1_block(&cid, &block)))
+    }
+}
+
+impl Quarry {
+    /// Writes a CARv1 archive rooted at `root` holding every block reachable
+    /// from it, in depth-first order. Blocks are written as they are visited.
+    pub fn export_car<W: Write>(&self, root: &Cid, mut writer: W) -> Result<()> {
+        for frame in CarEncoder::new(&[*root], self) {
+            writer.write_all(&frame?)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Puts every block of the CARv1 archive read from `reader` into `store`
+/// and returns the roots listed in its header. Fails on the first block
+/// whose content does not match its CID; blocks before it are already
+/// stored by then.
+pub fn import_car<R: Read>(store: &dyn Blockstore, mut reader: R) -> Result<Vec<Cid>> {
+    let roots = read_car_v1_header(&mut reader)?;
+    while let Some((cid, block)) = read_car_v1_block(&mut reader)? {
+        if !digest_matches(&cid, &block) {
+            return Err(anyhow!("block {} does not match its cid", cid));
+        }
+        store.put_keyed(&cid, &block)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import_car, CarEncoder};
+    use crate::testing::{with_dir, with_instance};
+    use crate::{Blockstore, Quarry};
+    use cid::multihash::{Code, MultihashDigest};
+    use cid::Cid;
+    use std::collections::HashSet;
+    use wiresaw::codec::car::{decode_car_v1, encode_car_v1};
+    use wiresaw::{ChunkReader, DagBuilder};
+
+    #[test]
+    fn export_car() {
+        with_instance(|quarry| {
+            let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+            let mut reader = ChunkReader::with_chunk_size(1 << 10, &data[..]);
+            reader.set_content_size(data.len() as u64);
+            let root = DagBuilder::new(reader, &quarry).trickle().unwrap().root;
+
+            let stray = b"not part of the dag";
+            let stray_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(stray));
+            Blockstore::put_keyed(&quarry, &stray_cid, stray).unwrap();
+
+            let mut car = vec![];
+            quarry.export_car(&root, &mut car).unwrap();
+            let (roots, blocks) = decode_car_v1(&car).unwrap();
+            assert_eq!(roots, vec![root]);
+            assert_eq!(blocks[0].0, root);
+
+            let exported: HashSet<Cid> = blocks.iter().map(|(cid, _)| *cid).collect();
+            assert_eq!(exported.len(), blocks.len());
+            assert!(!exported.contains(&stray_cid));
+            assert_eq!(exported.len(), quarry.iter_cids().count() - 1);
+            for (cid, block) in blocks {
+                assert_eq!(Blockstore::get(&quarry, &cid).unwrap(), Some(block));
+            }
+        });
+    }
+
+    #[test]
+    fn import_car_round_trip() {
+        with_dir(|path| {
+            let source = Quarry::open(path.join("source")).unwrap();
+            let dest = Quarry::open(path.join("dest")).unwrap();
+
+            let data: Vec<u8> = (0..10_000u32).map(|i| (i % 241) as u8).collect();
+            let mut reader = ChunkReader::with_chunk_size(1 << 10, &data[..]);
+            reader.set_content_size(data.len() as u64);
+            let root = DagBuilder::new(reader, &source).trickle().unwrap().root;
+
+            let mut car = vec![];
+            source.export_car(&root, &mut car).unwrap();
+            assert_eq!(import_car(&dest, &car[..]).unwrap(), vec![root]);
+            for cid in source.iter_cids() {
+                let cid = cid.unwrap();
+                assert_eq!(
+                    Blockstore::get(&dest, &cid).unwrap(),
+                    Blockstore::get(&source, &cid).unwrap()
+                );
+            }
+
+            let forged = Cid::new_v1(0x55, Code::Sha2_256.digest(b"expected"));
+            let car = encode_car_v1(&[forged], vec![(forged, b"actual".to_vec())]);
+            assert!(import_car(&dest, &car[..]).is_err());
+            assert!(!dest.has(&forged).unwrap());
+        });
+    }
+
+    #[test]
+    fn car_encoder_frames() {
+        with_instance(|quarry| {
+            let data: Vec<u8> = (0..5_000u32).map(|i| (i % 239) as u8).collect();
+            let mut reader = ChunkReader::with_chunk_size(1 << 10, &data[..]);
+            reader.set_content_size(data.len() as u64);
+            let root = DagBuilder::new(reader, &quarry).trickle().unwrap().root;
+
+            let frames: Vec<Vec<u8>> = CarEncoder::new(&[root], &quarry)
+                .collect::<anyhow::Result<_>>()
+                .unwrap();
+            let (roots, blocks) = decode_car_v1(&frames.concat()).unwrap();
+            assert_eq!(roots, vec![root]);
+            assert_eq!(frames.len(), blocks.len() + 1);
+
+            let mut exported = vec![];
+            quarry.export_car(&root, &mut exported).unwrap();
+            assert_eq!(exported, frames.concat());
+        });
+    }
+}
@@ -0,0 +1,136 @@
+//! Blocks stored compressed by `Quarry::put_keyed_compressed` or a
+//! `CompressedBlockstore`. A compressed
+//! value starts with a header naming its codec and original size; plain
+//! blocks that happen to start like a header are stored behind a raw one,
+//! so every stored value decodes unambiguously.
+
+use anyhow::{anyhow, Result};
+
+const MAGIC: &[u8; 4] = b"\xffqzc";
+
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+const RAW: u8 = 0;
+
+const ZSTD: u8 = 1;
+
+const LZ4: u8 = 2;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// The most zstd can expand its input: a block decodes to at most 128 KiB
+/// and takes a 3 byte header and a byte of payload at the least.
+const ZSTD_MAX_RATIO: usize = (128 << 10) / 4;
+
+/// The most LZ4 can expand its input, a byte of match length at a time.
+const LZ4_MAX_RATIO: usize = 255;
+
+/// Compression applied to a block by `Quarry::put_keyed_compressed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionCodec {
+    Zstd,
+}
+
+/// Stored form of an uncompressed block.
+pub(crate) fn encode_plain(block: &[u8]) -> Vec<u8> {
+    if !block.starts_with(MAGIC) {
+        return block.to_vec();
+    }
+    let mut stored = header(RAW, block.len());
+    stored.extend_from_slice(block);
+    stored
+}
+
+/// Stored form of a block compressed with `codec`.
+pub(crate) fn encode(block: &[u8], codec: CompressionCodec) -> Result<Vec<u8>> {
+    match codec {
+        CompressionCodec::Zstd => encode_zstd(block, ZSTD_LEVEL),
+    }
+}
+
+/// Stored form of a block compressed with zstd at `level`.
+pub(crate) fn encode_zstd(block: &[u8], level: i32) -> Result<Vec<u8>> {
+    let mut compressed = Vec::with_capacity(zstd_safe::compress_bound(block.len()));
+    zstd_safe::compress(&mut compressed, block, level)
+        .map_err(|code| anyhow!("zstd: {}", zstd_safe::get_error_name(code)))?;
+    let mut stored = header(ZSTD, block.len());
+    stored.extend_from_slice(&compressed);
+    Ok(stored)
+}
+
+/// Stored form of a block compressed with LZ4's block format.
+pub(crate) fn encode_lz4(block: &[u8]) -> Vec<u8> {
+    let mut stored = header(LZ4, block.len());
+    stored.extend_from_slice(&lz4_flex::block::compress(block));
+    stored
+}
+
+/// Original block of a stored value.
+pub(crate) fn decode(stored: &[u8]) -> Result<Vec<u8>> {
+    let (codec, size, payload) = match split(stored)? {
+        Some(parts) => parts,
+        None => return Ok(stored.to_vec()),
+    };
+    match codec {
+        RAW => Ok(payload.to_vec()),
+        ZSTD => {
+            check_ratio(size, payload, ZSTD_MAX_RATIO)?;
+            let mut block = Vec::new();
+            block.try_reserve_exact(size)?;
+            zstd_safe::decompress(&mut block, payload)
+                .map_err(|code| anyhow!("zstd: {}", zstd_safe::get_error_name(code)))?;
+            Ok(block)
+        }
+        LZ4 => {
+            check_ratio(size, payload, LZ4_MAX_RATIO)?;
+            lz4_flex::block::decompress(payload, size).map_err(|e| anyhow!("lz4: {}", e))
+        }
+        _ => Err(anyhow!("unknown compression codec {}", codec)),
+    }
+}
+
+/// Fails unless `payload` can decode to the `size` bytes its header
+/// claims, so that a corrupt header can't make decoding allocate more.
+fn check_ratio(size: usize, payload: &[u8], max_ratio: usize) -> Result<()> {
+    if size > payload.len().saturating_mul(max_ratio) {
+        return Err(anyhow!(
+            "compression header claims {} bytes out of {}",
+            size,
+            payload.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Size of the original block of a stored value.
+pub(crate) fn original_size(stored: &[u8]) -> usize {
+    match split(stored) {
+        Ok(Some((_, size, _))) => size,
+        _ => stored.len(),
+    }
+}
+
+fn header(codec: u8, size: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(codec);
+    header.extend_from_slice(&(size as u64).to_le_bytes());
+    header
+}
+
+/// Splits a stored value into codec, original size and payload, or `None`
+/// for a plain block.
+fn split(stored: &[u8]) -> Result<Option<(u8, usize, &[u8])>> {
+    if !stored.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    if stored.len() < HEADER_LEN {
+        return Err(anyhow!("truncated compression header"));
+    }
+    let size = u64::from_le_bytes(stored[MAGIC.len() + 1..HEADER_LEN].try_into()?);
+    Ok(Some((
+        stored[MAGIC.len()],
+        size as usize,
+        &stored[HEADER_LEN..],
+    )))
+}
@@ -0,0 +1,27 @@
+use cid::Cid;
+use std::fmt;
+
+/// Errors specific to quarry, returned wrapped in an `anyhow::Error` so they
+/// can be told apart with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Storing the block would take its codec past the configured quota.
+    CodecQuotaExceeded { codec: u64, used: u64, limit: u64 },
+    /// The CID lies within a range marked read-only.
+    ImmutableRange { cid: Cid },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::CodecQuotaExceeded { codec, used, limit } => write!(
+                f,
+                "quota for codec {:#x} exceeded: {} of {} bytes used",
+                codec, used, limit
+            ),
+            Error::ImmutableRange { cid } => write!(f, "{} lies within a read-only range", cid),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
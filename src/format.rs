@@ -0,0 +1,268 @@
+//! On-disk encoding of the marble objects holding the index, the pins and
+//! the pages.
+//!
+//! Format version 2, the current one, starts every object with the three
+//! bytes `qry` and a version byte, followed by the value in CBOR with
+//! structs encoded as maps keyed by field name. Fields can then be added
+//! to `Index` or `Page` behind `#[serde(default)]` without breaking stores
+//! written before, and the version byte is bumped for anything else.
+//!
+//! Version 1 is the layout stores had before objects were versioned: a
+//! bincode `IndexV1` and `PageV1`s holding a single value per key, with no
+//! header, no pins and CIDv0 blocks keyed by their v0 bytes. Such stores
+//! fail to open until converted with [`migrate_v1_to_v2`].
+
+use crate::{
+    compression, storage_key, Index, ObjectId, Page, QuarryConfig, Versions, INDEX_OBJECT_ID,
+};
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::{ByteBuf, Bytes};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+
+/// Written after the magic bytes of every object.
+pub const FORMAT_VERSION: u8 = 2;
+
+const MAGIC: &[u8; 3] = b"qry";
+
+pub(crate) fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut data = MAGIC.to_vec();
+    data.push(FORMAT_VERSION);
+    serde_cbor::to_writer(&mut data, value)?;
+    Ok(data)
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    match data.strip_prefix(&MAGIC[..]) {
+        Some([FORMAT_VERSION, rest @ ..]) => Ok(serde_cbor::from_slice(rest)?),
+        Some([version, ..]) => Err(anyhow!("unsupported store format version {}", version)),
+        _ => Err(anyhow!(
+            "object is not in store format {}; stores written by older versions \
+             must be converted with migrate_v1_to_v2",
+            FORMAT_VERSION
+        )),
+    }
+}
+
+/// Length of `encode(value)`, without keeping the encoding around.
+pub(crate) fn encoded_len<T: Serialize>(value: &T) -> Result<u64> {
+    struct Counter(u64);
+    impl io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let mut counter = Counter((MAGIC.len() + 1) as u64);
+    serde_cbor::to_writer(&mut counter, value)?;
+    Ok(counter.0)
+}
+
+/// The index of a version 1 store.
+#[derive(Serialize, Deserialize)]
+struct IndexV1 {
+    pages: BTreeMap<Vec<u8>, ObjectId>,
+    last_pid: u64,
+}
+
+/// A page of a version 1 store, mapping keys to raw blocks.
+#[derive(Serialize, Deserialize)]
+struct PageV1 {
+    hi: Option<Vec<u8>>,
+    lo: Vec<u8>,
+    kvs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Rewrites the index and every page of the version 1 store at `path` in
+/// the current format, in a single marble write batch. Every block becomes
+/// the single version of its key and blocks stored under a CIDv0 are
+/// rekeyed under the equivalent CIDv1. Stores already converted are left
+/// untouched.
+pub fn migrate_v1_to_v2(path: &Path) -> Result<()> {
+    let heap = QuarryConfig::default().heap_config(path).open()?;
+    let data = match heap.read(INDEX_OBJECT_ID)? {
+        Some(data) => data,
+        None => return Err(anyhow!("no store at {}", path.display())),
+    };
+    if data.starts_with(MAGIC) {
+        return Ok(());
+    }
+    let old: IndexV1 = bincode::deserialize(&data)?;
+    let index = Index {
+        pages: old.pages,
+        last_pid: old.last_pid,
+        immutable_ranges: vec![],
+    };
+
+    let mut pages = BTreeMap::new();
+    let mut rekeyed = vec![];
+    for object_id in index.pages.values() {
+        let data = heap
+            .read(*object_id)?
+            .ok_or_else(|| anyhow!("page {} is missing", object_id))?;
+        let old: PageV1 = bincode::deserialize(&data)?;
+        let mut kvs = BTreeMap::new();
+        for (key, block) in old.kvs {
+            let versions = vec![(1, Some(compression::encode_plain(&block)))];
+            match Cid::try_from(&key[..]) {
+                Ok(cid) if cid.version() == cid::Version::V0 => {
+                    rekeyed.push((storage_key(&cid), versions))
+                }
+                _ => {
+                    kvs.insert(key, versions);
+                }
+            }
+        }
+        let page = Page {
+            hi: old.hi,
+            lo: old.lo,
+            kvs,
+        };
+        pages.insert(*object_id, page);
+    }
+    // the v1 key can sort into another page than the v0 key did
+    for (key, versions) in rekeyed {
+        let (_, object_id) = index.pages.range(..=key.clone()).next_back().unwrap();
+        let page = pages.get_mut(object_id).unwrap();
+        page.kvs.entry(key).or_insert(versions);
+    }
+
+    let mut batch = HashMap::new();
+    for (object_id, page) in &pages {
+        batch.insert(*object_id, Some(encode(page)?));
+    }
+    batch.insert(INDEX_OBJECT_ID, Some(encode(&index)?));
+    heap.write_batch(batch)?;
+    Ok(())
+}
+
+/// `#[serde(with)]` helpers for maps keyed by byte strings.
+pub(crate) mod byte_keys {
+    use super::*;
+
+    pub fn serialize<V: Serialize, S: Serializer>(
+        map: &BTreeMap<Vec<u8>, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(k, v)| (Bytes::new(k), v)))
+    }
+
+    pub fn deserialize<'de, V: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Vec<u8>, V>, D::Error> {
+        let map = BTreeMap::<ByteBuf, V>::deserialize(deserializer)?;
+        Ok(map.into_iter().map(|(k, v)| (k.into_vec(), v)).collect())
+    }
+}
+
+/// `#[serde(with)]` helpers for the versioned keys of a page, encoding
+/// values as byte strings too.
+pub(crate) mod page_kvs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        kvs: &BTreeMap<Vec<u8>, Versions>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(kvs.iter().map(|(k, versions)| {
+            let versions: Vec<(u64, Option<&Bytes>)> = versions
+                .iter()
+                .map(|(seq, value)| (*seq, value.as_deref().map(Bytes::new)))
+                .collect();
+            (Bytes::new(k), versions)
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Vec<u8>, Versions>, D::Error> {
+        let kvs = BTreeMap::<ByteBuf, Vec<(u64, Option<ByteBuf>)>>::deserialize(deserializer)?;
+        Ok(kvs
+            .into_iter()
+            .map(|(k, versions)| {
+                let versions = versions
+                    .into_iter()
+                    .map(|(seq, value)| (seq, value.map(ByteBuf::into_vec)))
+                    .collect();
+                (k.into_vec(), versions)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::with_dir;
+    use crate::{Blockstore, Quarry};
+    use cid::multihash::{Code, MultihashDigest};
+
+    #[test]
+    fn migrate_v1_store() {
+        with_dir(|path| {
+            let block = b"written by bincode".to_vec();
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&block));
+            let pb_block = b"a dag-pb node".to_vec();
+            let v0 = Cid::new_v0(Code::Sha2_256.digest(&pb_block)).unwrap();
+            let page = PageV1 {
+                hi: None,
+                lo: vec![],
+                kvs: BTreeMap::from([
+                    (cid.to_bytes(), block.clone()),
+                    (v0.to_bytes(), pb_block.clone()),
+                ]),
+            };
+            let index = IndexV1 {
+                pages: BTreeMap::from([(vec![], 3)]),
+                last_pid: 3,
+            };
+            QuarryConfig::default()
+                .heap_config(path)
+                .open()
+                .unwrap()
+                .write_batch([
+                    (INDEX_OBJECT_ID, Some(bincode::serialize(&index).unwrap())),
+                    (3, Some(bincode::serialize(&page).unwrap())),
+                ])
+                .unwrap();
+
+            let err = Quarry::open(path).err().unwrap();
+            assert!(err.to_string().contains("migrate_v1_to_v2"));
+
+            migrate_v1_to_v2(path).unwrap();
+            // converting again is a no-op
+            migrate_v1_to_v2(path).unwrap();
+
+            let quarry = Quarry::open(path).unwrap();
+            assert_eq!(quarry.get(&cid).unwrap(), Some(block));
+            assert_eq!(quarry.get(&v0).unwrap(), Some(pb_block.clone()));
+            let v1 = Cid::new_v1(0x70, *v0.hash());
+            assert_eq!(quarry.get(&v1).unwrap(), Some(pb_block));
+            assert!(quarry.pins().is_empty());
+        });
+    }
+
+    #[test]
+    fn encoded_len_matches() {
+        let page = Page {
+            hi: Some(vec![0xff; 40]),
+            lo: vec![1, 2, 3],
+            kvs: BTreeMap::from([(vec![9; 36], vec![(7, None), (8, Some(vec![0xab; 300]))])]),
+        };
+        let data = encode(&page).unwrap();
+        assert_eq!(encoded_len(&page).unwrap(), data.len() as u64);
+        // byte strings are not spelled out one integer per byte
+        assert!(data.len() < 500);
+        let decoded: Page = decode(&data).unwrap();
+        assert_eq!(decoded.kvs, page.kvs);
+        assert_eq!(decoded.hi, page.hi);
+        assert!(decode::<Page>(&data[1..]).is_err());
+    }
+}
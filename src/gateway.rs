@@ -0,0 +1,334 @@
+//! A read-only blockstore fetching raw blocks from an IPFS HTTP gateway.
+//!
+//! Only plain `http://` gateways are supported, which covers the local
+//! gateway a kubo node serves on `127.0.0.1:8080`; requests are made over
+//! a fresh connection each time with `Connection: close`.
+
+use crate::stores::{DelegatingBlockstore, VerifyingBlockstore};
+use crate::{Blockstore, MemoryBlockstore};
+use anyhow::{anyhow, Context, Result};
+use cid::Cid;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The largest block a gateway may return, the 2 MiB IPFS peers exchange
+/// at most.
+const MAX_BLOCK_SIZE: u64 = 2 << 20;
+
+/// Room left for the status line, the headers and chunk framing on top of
+/// a block of `MAX_BLOCK_SIZE`.
+const MAX_FRAMING_SIZE: u64 = 64 << 10;
+
+/// Gateways are not trusted: wrap this in a [`VerifyingBlockstore`], or
+/// use [`GatewayBlockstore::cached`] which does so.
+#[derive(Clone, Debug)]
+pub struct GatewayBlockstore {
+    base_url: String,
+}
+
+impl GatewayBlockstore {
+    /// `base_url` is the gateway root, e.g. `http://127.0.0.1:8080`.
+    pub fn new(base_url: impl Into<String>) -> Result<Self> {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        split_url(&base_url)?;
+        Ok(GatewayBlockstore { base_url })
+    }
+
+    /// A gateway store behind an in-memory cache, so every block is
+    /// fetched and verified at most once.
+    pub fn cached(
+        base_url: impl Into<String>,
+    ) -> Result<DelegatingBlockstore<MemoryBlockstore, VerifyingBlockstore<GatewayBlockstore>>>
+    {
+        let gateway = VerifyingBlockstore(GatewayBlockstore::new(base_url)?);
+        Ok(DelegatingBlockstore::new(MemoryBlockstore::new(), gateway).promote_on_read(true))
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn request(&self, method: &str, k: &Cid) -> Result<(u16, Vec<u8>)> {
+        let (host, prefix) = split_url(&self.base_url)?;
+        let mut stream =
+            TcpStream::connect(host).with_context(|| format!("connecting to {}", host))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        write!(
+            stream,
+            "{} {}/ipfs/{}?format=raw HTTP/1.1\r\nHost: {}\r\nAccept: application/vnd.ipld.raw\r\nConnection: close\r\n\r\n",
+            method, prefix, k, host
+        )?;
+        let limit = MAX_BLOCK_SIZE + MAX_FRAMING_SIZE;
+        let mut response = Vec::new();
+        stream.take(limit + 1).read_to_end(&mut response)?;
+        if response.len() as u64 > limit {
+            return Err(anyhow!(
+                "gateway response for {} exceeds {} bytes",
+                k,
+                limit
+            ));
+        }
+        let (status, body) = parse_response(&response, method == "HEAD")?;
+        if body.len() as u64 > MAX_BLOCK_SIZE {
+            return Err(anyhow!(
+                "gateway returned a block of {} bytes for {}, more than {}",
+                body.len(),
+                k,
+                MAX_BLOCK_SIZE
+            ));
+        }
+        Ok((status, body))
+    }
+}
+
+impl Blockstore for GatewayBlockstore {
+    fn delete_block(&self, _k: &Cid) -> Result<()> {
+        Err(anyhow!("gateway blockstore is read-only"))
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        match self.request("GET", k)? {
+            (200, body) => Ok(Some(body)),
+            (404, _) => Ok(None),
+            (status, _) => Err(anyhow!("gateway returned {} for {}", status, k)),
+        }
+    }
+    fn put_keyed(&self, _k: &Cid, _block: &[u8]) -> Result<()> {
+        Err(anyhow!("gateway blockstore is read-only"))
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        match self.request("HEAD", k)?.0 {
+            200 => Ok(true),
+            404 => Ok(false),
+            status => Err(anyhow!("gateway returned {} for {}", status, k)),
+        }
+    }
+}
+
+/// Splits `http://host[:port][/prefix]` into the authority and the path
+/// prefix requests are made under.
+fn split_url(url: &str) -> Result<(&str, &str)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("unsupported gateway url {}: only http:// is supported", url))?;
+    let (host, prefix) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    if host.is_empty() {
+        return Err(anyhow!("gateway url {} has no host", url));
+    }
+    Ok((host, prefix))
+}
+
+fn parse_response(response: &[u8], head: bool) -> Result<(u16, Vec<u8>)> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| anyhow!("truncated gateway response"))?;
+    let header = std::str::from_utf8(&response[..end])?;
+    let body = &response[end + 4..];
+    let mut lines = header.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("malformed gateway status line"))?;
+    // requests never ask for an informational response, and redirects
+    // are not followed
+    if matches!(status, 100..=199 | 300..=399) {
+        return Err(anyhow!("unexpected gateway status {}", status));
+    }
+    if head {
+        return Ok((status, Vec::new()));
+    }
+    let mut chunked = false;
+    let mut length = None;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.parse::<usize>()?);
+        }
+    }
+    match (chunked, length) {
+        (true, _) => Ok((status, decode_chunked(body)?)),
+        (false, Some(len)) if body.len() < len => Err(anyhow!("truncated gateway response")),
+        (false, Some(len)) => Ok((status, body[..len].to_vec())),
+        (false, None) => Ok((status, body.to_vec())),
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let eol = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .ok_or_else(|| anyhow!("truncated chunked response"))?;
+        let size = std::str::from_utf8(&body[..eol])?;
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)?;
+        body = &body[eol + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        let framed = size
+            .checked_add(2)
+            .ok_or_else(|| anyhow!("truncated chunked response"))?;
+        if body.len() < framed {
+            return Err(anyhow!("truncated chunked response"));
+        }
+        out.extend_from_slice(&body[..size]);
+        body = &body[framed..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::{Code, MultihashDigest};
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Reads a request's head off `stream`, returning its request line.
+    fn read_head(stream: &TcpStream) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        reader.read_line(&mut request).unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        request
+    }
+
+    /// Serves `/ipfs/{cid}?format=raw` out of `blocks`, alternating between
+    /// sized and chunked bodies, and counts requests.
+    fn serve(blocks: HashMap<String, Vec<u8>>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, SeqCst);
+                let request = read_head(&stream);
+                let mut parts = request.split(' ');
+                let method = parts.next().unwrap().to_string();
+                let path = parts.next().unwrap();
+                let cid = path
+                    .trim_start_matches("/ipfs/")
+                    .trim_end_matches("?format=raw");
+                let response = match blocks.get(cid) {
+                    Some(body) if method == "HEAD" => {
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+                            .into_bytes()
+                    }
+                    Some(body) if i % 2 == 0 => {
+                        let mut r =
+                            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+                                .into_bytes();
+                        r.extend_from_slice(body);
+                        r
+                    }
+                    Some(body) => {
+                        let mut r =
+                            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+                        for chunk in body.chunks(5) {
+                            r.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                            r.extend_from_slice(chunk);
+                            r.extend_from_slice(b"\r\n");
+                        }
+                        r.extend_from_slice(b"0\r\n\r\n");
+                        r
+                    }
+                    None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, hits)
+    }
+
+    fn raw_cid(data: &[u8]) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(data))
+    }
+
+    #[test]
+    fn gateway_fetches_blocks() {
+        let hello = b"hello gateway".to_vec();
+        let good = raw_cid(&hello);
+        let bad = raw_cid(b"something else");
+        let missing = raw_cid(b"missing");
+        let blocks = HashMap::from([
+            (good.to_string(), hello.clone()),
+            (bad.to_string(), b"tampered".to_vec()),
+        ]);
+        let (url, hits) = serve(blocks);
+
+        let gateway = GatewayBlockstore::new(format!("{}/", url)).unwrap();
+        assert_eq!(gateway.get(&good).unwrap(), Some(hello.clone()));
+        assert_eq!(gateway.get(&good).unwrap(), Some(hello.clone()));
+        assert_eq!(gateway.get(&missing).unwrap(), None);
+        assert!(gateway.has(&good).unwrap());
+        assert!(!gateway.has(&missing).unwrap());
+        assert!(gateway.put_keyed(&good, &hello).is_err());
+        assert!(GatewayBlockstore::new("https://ipfs.io").is_err());
+
+        let cached = GatewayBlockstore::cached(url).unwrap();
+        let before = hits.load(SeqCst);
+        assert_eq!(cached.get(&good).unwrap(), Some(hello.clone()));
+        assert_eq!(cached.get(&good).unwrap(), Some(hello));
+        assert_eq!(hits.load(SeqCst), before + 1);
+        assert!(cached.get(&bad).is_err());
+        assert!(!cached.hot().has(&bad).unwrap());
+    }
+
+    #[test]
+    fn gateway_rejects_oversized_and_redirected_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let len = MAX_BLOCK_SIZE + MAX_FRAMING_SIZE;
+            let mut response =
+                format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", len).into_bytes();
+            response.resize(response.len() + len as usize, 0);
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                // answering before the request is read could reset the
+                // connection under the client's write
+                read_head(&stream);
+                // the client hangs up once past the limit
+                let _ = stream.write_all(&response);
+            }
+        });
+        let gateway = GatewayBlockstore::new(url).unwrap();
+        let err = gateway.get(&raw_cid(b"big")).unwrap_err();
+        assert!(err.to_string().contains("exceeds"));
+
+        let redirect = b"HTTP/1.1 301 Moved Permanently\r\nLocation: /elsewhere\r\n\r\n";
+        assert!(parse_response(redirect, false).is_err());
+        assert!(parse_response(b"HTTP/1.1 100 Continue\r\n\r\n", true).is_err());
+        // a chunk size near usize::MAX must not wrap past the bounds check
+        assert!(decode_chunked(b"ffffffffffffffff\r\n").is_err());
+        assert_eq!(
+            parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n", true)
+                .unwrap()
+                .0,
+            404
+        );
+    }
+}
@@ -1,58 +1,458 @@
-use anyhow::Result;
-use bincode::{deserialize, serialize};
-use cid::{
-    multihash::{Code, MultihashDigest},
-    Cid,
-};
+use access_log::AccessLog;
+use anyhow::{anyhow, Result};
+use bloom::BloomFilter;
+use bytes::Bytes;
+use cache::PageCache;
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
 use marble::Marble;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::sync::{Arc, Mutex, RwLock};
+
+mod access_log;
+pub mod async_store;
+mod backup;
+mod batch;
+mod bloom;
+mod cache;
+pub mod car;
+mod compression;
+mod error;
+mod format;
+pub mod gateway;
+mod maintenance;
+mod memory;
+mod pins;
+pub mod proof;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod stores;
+pub mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod walk;
+
+pub use batch::{BatchHandle, BatchStats, BatchWriter};
+pub use compression::CompressionCodec;
+pub use error::Error;
+pub use format::{migrate_v1_to_v2, FORMAT_VERSION};
+pub use maintenance::{CompactionReport, QuarryStats, RepairSummary, VacuumStats, VerifyReport};
+pub use memory::MemoryBlockstore;
 
 type ObjectId = u64;
 
+/// Ordered `(seq, value)` versions of a key, `None` marking a delete.
+type Versions = Vec<(u64, Option<Vec<u8>>)>;
+
 const INDEX_OBJECT_ID: ObjectId = 1;
 
+/// Holds the pinned roots; pages are numbered after it.
+const PINS_OBJECT_ID: ObjectId = 2;
+
+const HISTOGRAM_BUCKET_SIZE: usize = 10;
+
+const DEFAULT_MAX_PAGE_BYTES: usize = 512 << 10;
+
+const DEFAULT_MIN_PAGE_ENTRIES: usize = 4;
+
+const DEFAULT_BLOOM_FILTER_COUNTERS: usize = 1 << 20;
+
+const DEFAULT_CACHE_CAPACITY: usize = 64;
+
+const DEFAULT_INLINE_THRESHOLD: usize = 32;
+
+/// Multihash code of the identity "hash", whose digest is the data itself.
+const IDENTITY: u64 = 0x00;
+
+const RAW: u64 = 0x55;
+
+/// The codec CIDv0s imply.
+const DAG_PB: u64 = 0x70;
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Index {
+    #[serde(with = "format::byte_keys")]
     pages: BTreeMap<Vec<u8>, ObjectId>,
     last_pid: u64,
+    // `[start, end)` key ranges rejecting every write
+    immutable_ranges: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
 impl Default for Index {
     fn default() -> Self {
         Index {
             pages: Default::default(),
-            last_pid: INDEX_OBJECT_ID + 1,
+            last_pid: PINS_OBJECT_ID,
+            immutable_ranges: vec![],
+        }
+    }
+}
+
+impl Index {
+    /// The page whose key range holds `key`.
+    fn page_for(&self, key: &[u8]) -> ObjectId {
+        *self.pages.range(..=key.to_vec()).next_back().unwrap().1
+    }
+
+    /// Whether `key` lies within a range marked with `mark_readonly_range`.
+    fn is_immutable(&self, key: &[u8]) -> bool {
+        self.immutable_ranges
+            .iter()
+            .any(|(start, end)| &start[..] <= key && key < &end[..])
+    }
+
+    fn check_writable(&self, key: &[u8]) -> Result<()> {
+        if self.is_immutable(key) {
+            return Err(Error::ImmutableRange {
+                cid: Cid::read_bytes(key)?,
+            }
+            .into());
         }
+        Ok(())
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Page {
+    #[serde(with = "serde_bytes")]
     hi: Option<Vec<u8>>,
+    #[serde(with = "serde_bytes")]
     lo: Vec<u8>,
-    kvs: BTreeMap<Vec<u8>, Vec<u8>>,
+    #[serde(with = "format::page_kvs")]
+    kvs: BTreeMap<Vec<u8>, Versions>,
+}
+
+impl Page {
+    /// Latest value of a key.
+    fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.get_at(key, u64::MAX)
+    }
+
+    /// Value of a key as of `seq`, ignoring any later version.
+    fn get_at(&self, key: &[u8], seq: u64) -> Option<&Vec<u8>> {
+        let versions = self.kvs.get(key)?;
+        let (_, value) = versions.iter().rev().find(|(vseq, _)| *vseq <= seq)?;
+        value.as_ref()
+    }
+
+    /// Records a new version of a key and returns the previous latest
+    /// value. Versions that no reader at or after `horizon` can observe
+    /// are pruned; with no horizon only the latest version is kept.
+    fn insert(
+        &mut self,
+        key: Vec<u8>,
+        seq: u64,
+        value: Option<Vec<u8>>,
+        horizon: Option<u64>,
+    ) -> Option<Vec<u8>> {
+        let versions = self.kvs.entry(key.clone()).or_default();
+        let previous = versions.last().and_then(|(_, v)| v.clone());
+        versions.push((seq, value));
+
+        let horizon = horizon.unwrap_or(u64::MAX);
+        let visible = versions
+            .iter()
+            .rposition(|(vseq, _)| *vseq <= horizon)
+            .unwrap_or(0);
+        versions.drain(..visible);
+
+        if let [(_, None)] = versions.as_slice() {
+            if versions[0].0 <= horizon {
+                self.kvs.remove(&key);
+            }
+        }
+
+        previous
+    }
+
+    /// Iterates over the latest live value of every key.
+    fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)> {
+        self.range::<Vec<u8>, _>(..)
+    }
+
+    /// Iterates over the latest live value of every key within `range`.
+    fn range<K, R>(&self, range: R) -> impl Iterator<Item = (&Vec<u8>, &Vec<u8>)>
+    where
+        K: Ord + ?Sized,
+        Vec<u8>: std::borrow::Borrow<K>,
+        R: RangeBounds<K>,
+    {
+        self.kvs
+            .range(range)
+            .filter_map(move |(key, _)| Some((key, self.get(key)?)))
+    }
+
+    /// Keys whose latest version is a delete or a zero-length value.
+    fn tombstones(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.kvs
+            .iter()
+            .filter_map(|(key, versions)| match versions.last() {
+                Some((_, None)) => Some(key),
+                Some((_, Some(value))) if value.is_empty() => Some(key),
+                _ => None,
+            })
+    }
+
+    /// Total size of the latest live value of every key.
+    fn data_bytes(&self) -> u64 {
+        self.iter().map(|(_, value)| value.len() as u64).sum()
+    }
+
+    fn max_seq(&self) -> u64 {
+        self.kvs
+            .values()
+            .filter_map(|versions| versions.last())
+            .map(|(seq, _)| *seq)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Tuning parameters applied when opening a `Quarry`.
+#[derive(Debug, Clone)]
+pub struct QuarryConfig {
+    max_total_bytes: Option<u64>,
+    codec_quotas: HashMap<u64, u64>,
+    dead_object_warning_threshold: u64,
+    access_log: Option<PathBuf>,
+    max_page_bytes: usize,
+    min_page_entries: usize,
+    fsync_each_batch: bool,
+    target_file_size: Option<usize>,
+    bloom_filter_counters: usize,
+    cache_capacity: usize,
+    max_batch_ops: Option<usize>,
+    inline_threshold: usize,
+}
+
+impl Default for QuarryConfig {
+    fn default() -> Self {
+        QuarryConfig {
+            max_total_bytes: None,
+            codec_quotas: HashMap::new(),
+            dead_object_warning_threshold: 0,
+            access_log: None,
+            max_page_bytes: DEFAULT_MAX_PAGE_BYTES,
+            min_page_entries: DEFAULT_MIN_PAGE_ENTRIES,
+            fsync_each_batch: false,
+            target_file_size: None,
+            bloom_filter_counters: DEFAULT_BLOOM_FILTER_COUNTERS,
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            max_batch_ops: None,
+            inline_threshold: DEFAULT_INLINE_THRESHOLD,
+        }
+    }
+}
+
+impl QuarryConfig {
+    /// Splits pages whose serialized size grows past `limit` bytes.
+    pub fn with_max_page_bytes(mut self, limit: usize) -> Self {
+        self.max_page_bytes = limit;
+        self
+    }
+
+    /// Merges pages left with fewer than `entries` keys by a delete into a
+    /// neighbour.
+    pub fn with_min_page_entries(mut self, entries: usize) -> Self {
+        self.min_page_entries = entries;
+        self
+    }
+
+    /// Caps the bytes `put_if_not_full` will let the store hold.
+    pub fn with_max_total_bytes(mut self, limit: u64) -> Self {
+        self.max_total_bytes = Some(limit);
+        self
+    }
+
+    /// Caps the bytes stored under CIDs with the given codec. Puts that
+    /// would exceed it fail with `Error::CodecQuotaExceeded`.
+    pub fn with_codec_quota(mut self, codec: u64, limit: u64) -> Self {
+        self.codec_quotas.insert(codec, limit);
+        self
+    }
+
+    /// Appends a JSON line to the file at `path` for every `get`,
+    /// `put_keyed` and `delete_block`.
+    pub fn with_access_log<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.access_log = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Dead marble objects tolerated before `tombstone_check` bothers
+    /// scanning pages for tombstones.
+    pub fn with_dead_object_warning_threshold(mut self, threshold: u64) -> Self {
+        self.dead_object_warning_threshold = threshold;
+        self
+    }
+
+    /// Makes marble fsync each storage file it creates, so every write is
+    /// durable once it returns.
+    pub fn with_fsync_each_batch(mut self, fsync: bool) -> Self {
+        self.fsync_each_batch = fsync;
+        self
+    }
+
+    /// Size in bytes marble's garbage collection aims to keep storage
+    /// files under.
+    pub fn with_target_file_size(mut self, size: usize) -> Self {
+        self.target_file_size = Some(size);
+        self
+    }
+
+    /// Sizes the in-memory filter `has` consults before reading a page, at
+    /// one byte per counter. Zero disables it.
+    pub fn with_bloom_filter_counters(mut self, counters: usize) -> Self {
+        self.bloom_filter_counters = counters;
+        self
+    }
+
+    /// Keeps up to `n_pages` recently read pages deserialized in memory.
+    /// Zero disables the cache.
+    pub fn with_cache_capacity(mut self, n_pages: usize) -> Self {
+        self.cache_capacity = n_pages;
+        self
+    }
+
+    /// Makes a `BatchHandle` flush on its own once `ops` writes are queued,
+    /// bounding the memory a long batch holds at the cost of atomicity.
+    pub fn with_max_batch_ops(mut self, ops: usize) -> Self {
+        self.max_batch_ops = Some(ops.max(1));
+        self
+    }
+
+    /// Largest block `put_keyed_or_inline` embeds in its CID rather than
+    /// storing, capped at the 64 bytes an identity multihash can hold.
+    pub fn with_inline_threshold(mut self, bytes: usize) -> Self {
+        self.inline_threshold = bytes.min(64);
+        self
+    }
+
+    fn heap_config(&self, path: &Path) -> marble::Config {
+        let default = marble::Config::default();
+        marble::Config {
+            path: path.to_path_buf(),
+            fsync_each_batch: self.fsync_each_batch,
+            target_file_size: self.target_file_size.unwrap_or(default.target_file_size),
+            ..default
+        }
+    }
 }
 
+/// Outcome of a conditional put.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PutResult {
+    Inserted,
+    AlreadyPresent,
+    QuotaExceeded { used: u64, limit: u64 },
+}
+
+/// A handle to an open store. Clones are cheap and share the same store,
+/// so a `Quarry` can be handed to as many threads as needed.
+#[derive(Clone)]
 pub struct Quarry {
-    heap: Marble,
-    index: Index,
+    inner: Arc<QuarryInner>,
+}
+
+struct QuarryInner {
+    path: PathBuf,
+    heap: Arc<Marble>,
+    // taken for writing for the whole of every page update
+    index: RwLock<Index>,
+    config: QuarryConfig,
+    // bytes held by the latest version of every stored block
+    used_bytes: AtomicU64,
+    // the same, restricted to each codec that has a quota
+    codec_usage: HashMap<u64, AtomicU64>,
+    seq: AtomicU64,
+    // reference counts of the sequence numbers held by open snapshots
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+    access_log: Option<AccessLog>,
+    bloom: BloomFilter,
+    cache: PageCache,
+    pins: Mutex<BTreeSet<Cid>>,
+    gc_thread: Mutex<Option<maintenance::GcThread>>,
+}
+
+impl Drop for QuarryInner {
+    fn drop(&mut self) {
+        if let Some(gc) = self.gc_thread.get_mut().unwrap().take() {
+            gc.stop();
+        }
+    }
 }
 
 impl Quarry {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Quarry> {
-        let heap = marble::open(path)?;
+        Quarry::open_with_config(path, QuarryConfig::default())
+    }
+
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: QuarryConfig) -> Result<Quarry> {
+        Quarry::load(path.as_ref(), config, true)
+    }
+
+    /// Opens an existing store for reading only. Nothing is written to it,
+    /// not even the first page of a store that was never written to.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<ReadOnlyQuarry> {
+        let quarry = Quarry::load(path.as_ref(), QuarryConfig::default(), false)?;
+        Ok(ReadOnlyQuarry { quarry })
+    }
+
+    fn load(path: &Path, config: QuarryConfig, create: bool) -> Result<Quarry> {
+        if !create && !path.exists() {
+            return Err(anyhow!("no store at {}", path.display()));
+        }
+        let heap = Arc::new(config.heap_config(path).open()?);
 
         let index: Index = if let Some(data) = heap.read(INDEX_OBJECT_ID)? {
-            deserialize(&data)?
-        } else {
+            format::decode(&data)?
+        } else if create {
             Index::default()
+        } else {
+            return Err(anyhow!("no store at {}", path.display()));
+        };
+
+        let codec_usage = config
+            .codec_quotas
+            .keys()
+            .map(|codec| (*codec, AtomicU64::new(0)))
+            .collect();
+
+        let access_log = match &config.access_log {
+            Some(path) => Some(AccessLog::open(path)?),
+            None => None,
         };
 
-        let mut qry = Quarry { index, heap };
+        let bloom = BloomFilter::new(config.bloom_filter_counters);
+        let cache = PageCache::new(config.cache_capacity);
+        let pins = Mutex::new(pins::read_pins(&heap)?);
 
-        if qry.index.pages.is_empty() {
+        let qry = Quarry {
+            inner: Arc::new(QuarryInner {
+                path: path.to_path_buf(),
+                index: RwLock::new(index),
+                heap,
+                config,
+                used_bytes: AtomicU64::new(0),
+                codec_usage,
+                seq: AtomicU64::new(0),
+                snapshots: Mutex::new(BTreeMap::new()),
+                access_log,
+                bloom,
+                cache,
+                pins,
+                gc_thread: Mutex::new(None),
+            }),
+        };
+
+        qry.recount()?;
+
+        if qry.inner.index.read().unwrap().pages.is_empty() {
             let init_page = Page {
                 hi: None,
                 lo: vec![],
@@ -65,144 +465,2162 @@ impl Quarry {
         Ok(qry)
     }
 
-    fn allocate_page(&mut self, page: Page) -> Result<()> {
-        self.index.last_pid += 1;
-        let object_id = self.index.last_pid;
+    fn allocate_page(&self, page: Page) -> Result<()> {
+        let mut index = self.inner.index.write().unwrap();
+        index.last_pid += 1;
+        let object_id = index.last_pid;
 
-        let previous = self.index.pages.insert(page.lo.clone(), object_id);
+        let previous = index.pages.insert(page.lo.clone(), object_id);
         assert!(previous.is_none());
 
         let batch: HashMap<ObjectId, Option<Vec<u8>>> = [
-            (object_id, Some(serialize(&page)?)),
-            (INDEX_OBJECT_ID, Some(serialize(&self.index)?)),
+            (object_id, Some(format::encode(&page)?)),
+            (INDEX_OBJECT_ID, Some(format::encode(&*index)?)),
         ]
         .into_iter()
         .collect();
 
-        self.heap.write_batch(batch)?;
+        self.write_heap(batch)?;
 
         Ok(())
     }
 
-    fn pid_for_key(&self, key: Vec<u8>) -> ObjectId {
-        *self.index.pages.range(..=key).next_back().unwrap().1
-    }
+    /// Reassigns contiguous object IDs to every page in key order so that
+    /// scans read marble objects sequentially. Returns how many pages moved.
+    pub fn reorder_pages(&self) -> Result<usize> {
+        let mut index = self.inner.index.write().unwrap();
+        let mut batch: HashMap<ObjectId, Option<Vec<u8>>> = index
+            .pages
+            .values()
+            .map(|object_id| (*object_id, None))
+            .collect();
 
-    fn mutate(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
-        let object_id = self.pid_for_key(key.clone());
-        let leaf_data = self.heap.read(object_id)?.unwrap();
-        let mut leaf: Page = deserialize(&leaf_data)?;
-        let ret = if let Some(v) = value {
-            // TODO Page split logic when it becomes large
-            leaf.kvs.insert(key, v)
-        } else {
-            // TODO Page merge logic when it becomes small
-            leaf.kvs.remove(&key)
-        };
+        let mut object_id = PINS_OBJECT_ID;
+        let mut moved = 0;
+        for old_id in index.pages.values_mut() {
+            object_id += 1;
+            let page_data = self.inner.heap.read(*old_id)?.unwrap();
+            batch.insert(object_id, Some(page_data.into_vec()));
+            if *old_id != object_id {
+                moved += 1;
+            }
+            *old_id = object_id;
+        }
+        index.last_pid = object_id;
 
-        let write_batch = [(object_id, Some(serialize(&leaf).unwrap()))];
+        batch.insert(INDEX_OBJECT_ID, Some(format::encode(&*index)?));
+        self.write_heap(batch)?;
 
-        self.heap.write_batch(write_batch)?;
+        Ok(moved)
+    }
 
-        let stats = self.heap.stats();
+    fn read_page(&self, object_id: ObjectId) -> Result<Page> {
+        read_page(&self.inner.heap, object_id)
+    }
 
-        if stats.dead_objects > stats.live_objects {
-            self.heap.maintenance()?;
+    /// Reads the page holding `key` through the page cache, keeping the
+    /// index locked so that a concurrent split can't move the key away in
+    /// between, nor a writer replace the page while it is being cached.
+    fn page_for_key(&self, key: &[u8]) -> Result<Arc<Page>> {
+        let index = self.inner.index.read().unwrap();
+        let object_id = index.page_for(key);
+        if let Some(page) = self.inner.cache.get(object_id) {
+            return Ok(page);
         }
+        let page = Arc::new(self.read_page(object_id)?);
+        self.inner.cache.insert(object_id, page.clone());
+        Ok(page)
+    }
 
-        Ok(ret)
+    /// Writes `batch` to marble, first evicting the pages it replaces from
+    /// the page cache.
+    fn write_heap(&self, batch: HashMap<ObjectId, Option<Vec<u8>>>) -> Result<()> {
+        for object_id in batch.keys() {
+            self.inner.cache.invalidate(*object_id);
+        }
+        self.inner.heap.write_batch(batch)?;
+        Ok(())
     }
-}
 
-impl Blockstore for Quarry {
-    fn delete_block(&self, k: &Cid) -> Result<()> {
-        let kd = k.to_bytes();
-        self.mutate(kd, None)?;
+    /// Iterates over every page in key order, reading each one lazily.
+    /// Each page is resolved and read under the index lock, by the first
+    /// key past the page before it, so concurrent splits and merges can't
+    /// remove it in between. Keys a merge moved into a page from one
+    /// already read are trimmed off.
+    fn iter_pages(&self) -> impl Iterator<Item = Result<Page>> + '_ {
+        let mut next = Some(vec![]);
+        std::iter::from_fn(move || {
+            let key = next.take()?;
+            let index = self.inner.index.read().unwrap();
+            // only a store still being opened has no pages
+            let (lo, &object_id) = index.pages.range(..=key.clone()).next_back()?;
+            let merged = *lo < key;
+            next = index
+                .pages
+                .range::<Vec<u8>, _>((Bound::Excluded(lo), Bound::Unbounded))
+                .next()
+                .map(|(upper, _)| upper.clone());
+            let page = self.read_page(object_id);
+            drop(index);
+            Some(page.map(|mut page| {
+                if merged {
+                    page.kvs = page.kvs.split_off(&key);
+                }
+                page
+            }))
+        })
+    }
+
+    /// Serializes `page` into `batch` under `object_id`, first splitting it
+    /// in halves as many times as needed to keep every page within
+    /// `max_page_bytes`. Split-off pages are registered in `index`, which
+    /// is then added to the batch as well.
+    fn write_page(
+        &self,
+        index: &mut Index,
+        object_id: ObjectId,
+        page: Page,
+        batch: &mut HashMap<ObjectId, Option<Vec<u8>>>,
+    ) -> Result<()> {
+        let mut pending = vec![(object_id, page)];
+        let mut split = false;
+        while let Some((object_id, mut page)) = pending.pop() {
+            let data = format::encode(&page)?;
+            if data.len() <= self.inner.config.max_page_bytes || page.kvs.len() < 2 {
+                batch.insert(object_id, Some(data));
+                continue;
+            }
+            let median = page.kvs.keys().nth(page.kvs.len() / 2).unwrap().clone();
+            let upper = Page {
+                hi: page.hi.replace(median.clone()),
+                lo: median.clone(),
+                kvs: page.kvs.split_off(&median),
+            };
+            index.last_pid += 1;
+            index.pages.insert(median, index.last_pid);
+            pending.push((object_id, page));
+            pending.push((index.last_pid, upper));
+            split = true;
+        }
+        if split {
+            batch.insert(INDEX_OBJECT_ID, Some(format::encode(&*index)?));
+        }
         Ok(())
     }
-    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
-        let kd = k.to_bytes();
-        let object_id = self.pid_for_key(kd.clone());
-        let page_data = self.heap.read(object_id)?.unwrap();
-        let page: Page = deserialize(&page_data)?;
-        Ok(page.kvs.get(&kd).cloned())
+
+    /// Iterates over the CID of every stored block in key order, reading one
+    /// page at a time. Blocks put under a CIDv0 are listed under its CIDv1.
+    pub fn iter_cids(&self) -> impl Iterator<Item = Result<Cid>> + '_ {
+        self.iter_cids_with_size()
+            .map(|entry| entry.map(|(cid, _)| cid))
     }
-    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
-        let kd = k.to_bytes();
-        self.mutate(kd, Some(block.to_vec()))?;
+
+    /// Same as `iter_cids`, for callers that expect listing to be
+    /// fallible up front.
+    pub fn list_cids(&self) -> Result<impl Iterator<Item = Result<Cid>> + '_> {
+        Ok(self.iter_cids())
+    }
+
+    /// Same as `iter_cids`, pairing each CID with the byte length of its
+    /// block. Each page is decoded once, values included, but only the
+    /// sizes are handed out.
+    pub fn iter_cids_with_size(&self) -> impl Iterator<Item = Result<(Cid, usize)>> + '_ {
+        self.iter_pages().flat_map(|page| match page {
+            Ok(page) => page
+                .iter()
+                .map(|(key, value)| {
+                    let size = compression::original_size(value);
+                    Ok((Cid::read_bytes(&key[..])?, size))
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => vec![Err(e)],
+        })
+    }
+
+    /// Iterates over every stored block and its CID in key order, reading
+    /// one page at a time.
+    pub fn scan(&self) -> Result<impl Iterator<Item = Result<(Cid, Vec<u8>)>> + '_> {
+        Ok(self.iter_pages().flat_map(|page| match page {
+            Ok(page) => page
+                .iter()
+                .map(|(key, value)| Ok((Cid::read_bytes(&key[..])?, compression::decode(value)?)))
+                .collect::<Vec<_>>(),
+            Err(e) => vec![Err(e)],
+        }))
+    }
+
+    /// Byte length of the block stored under `k`, read off its page without
+    /// decoding the block.
+    pub fn get_block_size(&self, k: &Cid) -> Result<Option<usize>> {
+        let kd = storage_key(k);
+        let page = self.page_for_key(&kd)?;
+        Ok(page
+            .get(&kd)
+            .map(|stored| compression::original_size(stored)))
+    }
+
+    /// Sums the bytes every block takes on its page, after compression.
+    pub fn total_stored_size(&self) -> Result<u64> {
+        let mut total = 0;
+        for page in self.iter_pages() {
+            total += page?.iter().map(|(_, v)| v.len() as u64).sum::<u64>();
+        }
+        Ok(total)
+    }
+
+    /// Same as `scan`, limited to the blocks whose CID bytes start with
+    /// `prefix`. Only the pages covering the prefix are read.
+    pub fn scan_prefix(
+        &self,
+        prefix: &[u8],
+    ) -> Result<impl Iterator<Item = Result<(Cid, Vec<u8>)>> + '_> {
+        let prefix = prefix.to_vec();
+        let index = self.inner.index.read().unwrap();
+        let first = index.page_for(&prefix);
+        let pages: Vec<ObjectId> = std::iter::once(first)
+            .chain(
+                index
+                    .pages
+                    .range::<Vec<u8>, _>((Bound::Excluded(&prefix), Bound::Unbounded))
+                    .take_while(|(lo, _)| lo.starts_with(&prefix))
+                    .map(|(_, object_id)| *object_id),
+            )
+            .collect();
+        drop(index);
+
+        Ok(pages
+            .into_iter()
+            .flat_map(move |object_id| match self.read_page(object_id) {
+                Ok(page) => page
+                    .range::<Vec<u8>, _>(&prefix..)
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .map(|(key, value)| {
+                        Ok((Cid::read_bytes(&key[..])?, compression::decode(value)?))
+                    })
+                    .collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            }))
+    }
+
+    /// Sums the size of every stored block by scanning all pages.
+    pub fn total_data_bytes(&self) -> Result<u64> {
+        self.iter_cids_with_size()
+            .map(|entry| entry.map(|(_, size)| size as u64))
+            .sum()
+    }
+
+    /// Folds `page` into a neighbour, preferring the one on its left, and
+    /// queues the merged page and the index in `batch`. The page is
+    /// written as is when no neighbour can take it without outgrowing
+    /// `max_page_bytes`.
+    fn merge_page(
+        &self,
+        index: &mut Index,
+        object_id: ObjectId,
+        page: Page,
+        batch: &mut HashMap<ObjectId, Option<Vec<u8>>>,
+    ) -> Result<()> {
+        let left = index.pages.range::<Vec<u8>, _>(..&page.lo).next_back();
+        let right = index
+            .pages
+            .range::<Vec<u8>, _>((Bound::Excluded(&page.lo), Bound::Unbounded))
+            .next();
+        let neighbours: Vec<(ObjectId, bool)> = left
+            .map(|(_, id)| (*id, true))
+            .into_iter()
+            .chain(right.map(|(_, id)| (*id, false)))
+            .collect();
+
+        let size = format::encoded_len(&page)?;
+        let mut chosen = None;
+        for (neighbour_id, on_left) in neighbours {
+            let neighbour = self.read_page(neighbour_id)?;
+            if size + format::encoded_len(&neighbour)? <= self.inner.config.max_page_bytes as u64 {
+                chosen = Some((neighbour_id, neighbour, on_left));
+                break;
+            }
+        }
+        let (neighbour_id, neighbour, on_left) = match chosen {
+            Some(chosen) => chosen,
+            None => return self.write_page(index, object_id, page, batch),
+        };
+
+        let ((lower_id, mut lower), (upper_id, upper)) = if on_left {
+            ((neighbour_id, neighbour), (object_id, page))
+        } else {
+            ((object_id, page), (neighbour_id, neighbour))
+        };
+        lower.kvs.extend(upper.kvs);
+        lower.hi = upper.hi;
+        index.pages.remove(&upper.lo);
+
+        batch.insert(upper_id, None);
+        batch.insert(lower_id, Some(format::encode(&lower)?));
+        batch.insert(INDEX_OBJECT_ID, Some(format::encode(&*index)?));
         Ok(())
     }
-}
 
-/// Layer of abstraction for block-centered methods over a datastore.
-pub trait Blockstore {
-    /// Delete a block from the blockstore.
-    fn delete_block(&self, k: &Cid) -> Result<()>;
+    /// Rejects every later put or delete of a CID within `[start, end)`
+    /// with `Error::ImmutableRange`. Returns how many stored blocks the
+    /// range covers.
+    pub fn mark_readonly_range(&self, start: &Cid, end: &Cid) -> Result<usize> {
+        let range = (storage_key(start), storage_key(end));
+        let mut covered = 0;
+        for cid in self.iter_cids() {
+            let key = cid?.to_bytes();
+            if range.0 <= key && key < range.1 {
+                covered += 1;
+            }
+        }
+        let mut index = self.inner.index.write().unwrap();
+        if !index.immutable_ranges.contains(&range) {
+            index.immutable_ranges.push(range);
+            self.inner
+                .heap
+                .write_batch([(INDEX_OBJECT_ID, Some(format::encode(&*index)?))])?;
+        }
+        Ok(covered)
+    }
 
-    /// Gets the block from the blockstore.
-    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>>;
+    /// Lifts a range set with `mark_readonly_range`, returning whether it
+    /// was marked.
+    pub fn unmark_readonly_range(&self, start: &Cid, end: &Cid) -> Result<bool> {
+        let range = (storage_key(start), storage_key(end));
+        let mut index = self.inner.index.write().unwrap();
+        let before = index.immutable_ranges.len();
+        index.immutable_ranges.retain(|marked| *marked != range);
+        if index.immutable_ranges.len() == before {
+            return Ok(false);
+        }
+        self.inner
+            .heap
+            .write_batch([(INDEX_OBJECT_ID, Some(format::encode(&*index)?))])?;
+        Ok(true)
+    }
 
-    /// Put a block with a pre-computed cid.
-    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()>;
+    /// Returns up to `limit` blocks whose CID bytes fall within
+    /// `[start, end)`, in key order.
+    pub fn get_range(&self, start: &Cid, end: &Cid, limit: usize) -> Result<Vec<(Cid, Vec<u8>)>> {
+        let (start, end) = (storage_key(start), storage_key(end));
+        if start >= end {
+            return Ok(vec![]);
+        }
 
-    /// Checks if the blockstore has the specified block.
-    fn has(&self, k: &Cid) -> Result<bool> {
-        Ok(self.get(k)?.is_some())
+        // the page holding `start` followed by every page starting before `end`
+        let index = self.inner.index.read().unwrap();
+        let first = index.page_for(&start);
+        let rest = index
+            .pages
+            .range::<Vec<u8>, _>((Bound::Excluded(&start), Bound::Excluded(&end)))
+            .map(|(_, object_id)| *object_id);
+
+        let mut blocks = vec![];
+        for object_id in std::iter::once(first).chain(rest) {
+            let page = self.read_page(object_id)?;
+            for (key, value) in page.range::<Vec<u8>, _>(&start..&end) {
+                if blocks.len() == limit {
+                    return Ok(blocks);
+                }
+                blocks.push((Cid::read_bytes(&key[..])?, compression::decode(value)?));
+            }
+        }
+        Ok(blocks)
     }
 
-    /// Bulk-put pre-keyed blocks into the blockstore.
+    /// Deletes every stored block whose CID bytes fall within `[lo, hi)`
+    /// and returns how many were removed. Pages left with fewer than
+    /// `min_page_entries` keys are folded into their neighbours, and every
+    /// change lands in a single marble write batch.
+    pub fn delete_range(&self, lo: &[u8], hi: &[u8]) -> Result<usize> {
+        let (lo, hi) = (lo.to_vec(), hi.to_vec());
+        if lo >= hi {
+            return Ok(0);
+        }
+        let horizon = self.oldest_snapshot();
+
+        // the page holding `lo` followed by every page starting before `hi`
+        let mut index = self.inner.index.write().unwrap();
+        let first = index.page_for(&lo);
+        let object_ids: Vec<ObjectId> = std::iter::once(first)
+            .chain(
+                index
+                    .pages
+                    .range::<Vec<u8>, _>((Bound::Excluded(&lo), Bound::Excluded(&hi)))
+                    .map(|(_, object_id)| *object_id),
+            )
+            .collect();
+
+        let mut pages = vec![];
+        let mut deleted = vec![];
+        for object_id in object_ids {
+            let mut page = self.read_page(object_id)?;
+            let keys: Vec<Vec<u8>> = page
+                .range::<Vec<u8>, _>(&lo..&hi)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &keys {
+                index.check_writable(key)?;
+            }
+            let dirty = !keys.is_empty();
+            for key in keys {
+                let seq = self.inner.seq.fetch_add(1, SeqCst) + 1;
+                let previous = page.insert(key.clone(), seq, None, horizon);
+                let removed = previous.map_or(0, |v| v.len() as u64);
+                deleted.push((self.quota_codec(&key), key, removed));
+            }
+            pages.push((object_id, page, dirty));
+        }
+
+        // fold each under-occupied page into the one before it
+        let mut write_batch = HashMap::new();
+        let mut kept: Vec<(ObjectId, Page, bool)> = vec![];
+        let min = self.inner.config.min_page_entries;
+        for (object_id, page, dirty) in pages {
+            if let Some((_, lower, lower_dirty)) = kept.last_mut() {
+                let small = lower.kvs.len() < min || page.kvs.len() < min;
+                let fits = format::encoded_len(lower)? + format::encoded_len(&page)?
+                    <= self.inner.config.max_page_bytes as u64;
+                if (dirty || *lower_dirty) && small && fits {
+                    lower.kvs.extend(page.kvs);
+                    lower.hi = page.hi;
+                    *lower_dirty = true;
+                    index.pages.remove(&page.lo);
+                    write_batch.insert(object_id, None);
+                    continue;
+                }
+            }
+            kept.push((object_id, page, dirty));
+        }
+        if !write_batch.is_empty() {
+            write_batch.insert(INDEX_OBJECT_ID, Some(format::encode(&*index)?));
+        }
+        for (object_id, page, dirty) in kept {
+            if dirty {
+                self.write_page(&mut index, object_id, page, &mut write_batch)?;
+            }
+        }
+        if !write_batch.is_empty() {
+            self.write_heap(write_batch)?;
+        }
+        drop(index);
+
+        for (codec, key, removed) in &deleted {
+            self.account(*codec, 0, *removed);
+            self.track_key(key, true, true);
+        }
+        Ok(deleted.len())
+    }
+
+    /// Picks up to `n` stored CIDs uniformly at random, using reservoir
+    /// sampling so only the sample is held in memory.
+    pub fn sample(&self, n: usize) -> Result<Vec<Cid>> {
+        let mut rng = rand::thread_rng();
+        let mut reservoir = Vec::with_capacity(n);
+        for (seen, cid) in self.iter_cids().enumerate() {
+            let cid = cid?;
+            if seen < n {
+                reservoir.push(cid);
+            } else {
+                let slot = rng.gen_range(0..=seen);
+                if slot < n {
+                    reservoir[slot] = cid;
+                }
+            }
+        }
+        Ok(reservoir)
+    }
+
+    /// Like `sample`, returning each sampled block alongside its CID.
+    pub fn sample_blocks(&self, n: usize) -> Result<Vec<(Cid, Vec<u8>)>> {
+        let mut blocks = Vec::with_capacity(n);
+        for cid in self.sample(n)? {
+            if let Some(block) = self.get(&cid)? {
+                blocks.push((cid, block));
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Returns the distribution of entry counts across pages.
     ///
-    /// By default, this defers to put_keyed.
-    fn put_many_keyed<D, I>(&self, blocks: I) -> Result<()>
-    where
-        Self: Sized,
-        D: AsRef<[u8]>,
-        I: IntoIterator<Item = (Cid, D)>,
-    {
-        for (c, b) in blocks {
-            self.put_keyed(&c, b.as_ref())?
+    /// Each element is `(bucket_start, count)` where `count` is the number of
+    /// pages holding between `bucket_start` and `bucket_start + 9` entries.
+    /// Buckets are contiguous from 0 up to the bucket of the fullest page.
+    pub fn page_histogram(&self) -> Result<Vec<(usize, usize)>> {
+        let mut counts: Vec<usize> = vec![];
+        for page in self.iter_pages() {
+            let bucket = page?.kvs.len() / HISTOGRAM_BUCKET_SIZE;
+            if bucket >= counts.len() {
+                counts.resize(bucket + 1, 0);
+            }
+            counts[bucket] += 1;
+        }
+        Ok(counts
+            .into_iter()
+            .enumerate()
+            .map(|(bucket, count)| (bucket * HISTOGRAM_BUCKET_SIZE, count))
+            .collect())
+    }
+
+    /// Writes the page histogram as an ASCII bar chart, one bucket per line.
+    pub fn print_histogram<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let histogram = self.page_histogram()?;
+        let width = histogram
+            .last()
+            .map(|(start, _)| (start + HISTOGRAM_BUCKET_SIZE - 1).to_string().len())
+            .unwrap_or(1);
+        for (start, count) in histogram {
+            let range = format!("{}-{}", start, start + HISTOGRAM_BUCKET_SIZE - 1);
+            writeln!(
+                writer,
+                "{:>w$} | {} {}",
+                range,
+                "#".repeat(count),
+                count,
+                w = width * 2 + 1
+            )?;
         }
         Ok(())
     }
-}
 
-pub trait Buffered: Blockstore {
-    fn flush(&self, root: &Cid) -> Result<()>;
-}
+    /// Returns a raw CID for `block`. Blocks of up to
+    /// `QuarryConfig::with_inline_threshold` bytes are embedded in it with
+    /// the identity multihash and not stored at all; larger ones are hashed
+    /// with SHA2-256 and put as usual.
+    pub fn put_keyed_or_inline(&self, block: &[u8]) -> Result<Cid> {
+        if block.len() <= self.inner.config.inline_threshold {
+            return Ok(Cid::new_v1(RAW, Code::Identity.digest(block)));
+        }
+        let cid = Cid::new_v1(RAW, Code::Sha2_256.digest(block));
+        self.put_keyed(&cid, block)?;
+        Ok(cid)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+    /// Puts a block unless it is already stored or doing so would take the
+    /// store past `QuarryConfig::with_max_total_bytes`.
+    pub fn put_if_not_full(&self, k: &Cid, block: &[u8]) -> Result<PutResult> {
+        if self.has(k)? {
+            return Ok(PutResult::AlreadyPresent);
+        }
+        let len = block.len() as u64;
+        let limit = match self.inner.config.max_total_bytes {
+            Some(limit) => limit,
+            None => {
+                self.put_stored(k, compression::encode_plain(block))?;
+                return Ok(PutResult::Inserted);
+            }
+        };
+        // the block's bytes are reserved up front so that racing puts can't
+        // all pass the check, then released once the put has accounted for
+        // them itself
+        let reserved = self.inner.used_bytes.fetch_update(SeqCst, SeqCst, |used| {
+            (used + len <= limit).then_some(used + len)
+        });
+        if let Err(used) = reserved {
+            return Ok(PutResult::QuotaExceeded { used, limit });
+        }
+        let put = self.put_stored(k, compression::encode_plain(block));
+        self.inner.used_bytes.fetch_sub(len, SeqCst);
+        put?;
+        Ok(PutResult::Inserted)
+    }
+
+    /// Recomputes the sequence number, byte counters and bloom filter from
+    /// the pages.
+    fn recount(&self) -> Result<()> {
+        let (mut seq, mut used) = (0, 0);
+        let mut codec_used: HashMap<u64, u64> = HashMap::new();
+        self.inner.bloom.clear();
+        for page in self.iter_pages() {
+            let page = page?;
+            seq = seq.max(page.max_seq());
+            used += page.data_bytes();
+            for (key, value) in page.iter() {
+                self.inner.bloom.insert(key);
+                if let Some(codec) = self.quota_codec(key) {
+                    *codec_used.entry(codec).or_default() += value.len() as u64;
+                }
+            }
+        }
+        self.inner.seq.fetch_max(seq, SeqCst);
+        self.inner.used_bytes.store(used, SeqCst);
+        for (codec, usage) in &self.inner.codec_usage {
+            usage.store(codec_used.get(codec).copied().unwrap_or(0), SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Codec of a stored key, if that codec has a quota.
+    fn quota_codec(&self, key: &[u8]) -> Option<u64> {
+        if self.inner.codec_usage.is_empty() {
+            return None;
+        }
+        let codec = Cid::read_bytes(key).ok()?.codec();
+        self.inner.codec_usage.contains_key(&codec).then_some(codec)
+    }
 
-    const TEST_DIR: &str = "test_dir";
+    fn check_codec_quota(&self, codec: u64, used: u64, len: u64) -> Result<()> {
+        match self.inner.config.codec_quotas.get(&codec) {
+            Some(&limit) if used + len > limit => {
+                Err(Error::CodecQuotaExceeded { codec, used, limit }.into())
+            }
+            _ => Ok(()),
+        }
+    }
 
-    static TEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+    /// Updates the byte counters once a write has landed.
+    fn account(&self, codec: Option<u64>, added: u64, removed: u64) {
+        self.inner.used_bytes.fetch_add(added, SeqCst);
+        self.inner.used_bytes.fetch_sub(removed, SeqCst);
+        if let Some(usage) = codec.and_then(|codec| self.inner.codec_usage.get(&codec)) {
+            usage.fetch_add(added, SeqCst);
+            usage.fetch_sub(removed, SeqCst);
+        }
+    }
 
-    fn with_instance<F: FnOnce(Quarry)>(f: F) {
-        let subdir = format!("test_{}", TEST_COUNTER.fetch_add(1, SeqCst));
-        let path = std::path::Path::new(TEST_DIR).join(subdir);
+    /// The sequence number of the most recent write.
+    pub fn current_seq(&self) -> u64 {
+        self.inner.seq.load(SeqCst)
+    }
 
-        let _ = fs::remove_dir_all(&path);
+    /// Opens a read-only view of the store as of `seq`, usually obtained
+    /// from `current_seq`. Versions visible to the snapshot are retained
+    /// until it is dropped.
+    pub fn open_snapshot(&self, seq: u64) -> QuarrySnapshot<'_> {
+        *self.inner.snapshots.lock().unwrap().entry(seq).or_insert(0) += 1;
+        QuarrySnapshot { quarry: self, seq }
+    }
 
-        let quarry = Quarry::open(&path).unwrap();
+    /// Same as `put_keyed`, compressing the block with `codec`. `get`
+    /// decompresses it transparently.
+    pub fn put_keyed_compressed(
+        &self,
+        k: &Cid,
+        block: &[u8],
+        codec: CompressionCodec,
+    ) -> Result<()> {
+        self.put_stored(k, compression::encode(block, codec)?)
+    }
 
-        f(quarry);
+    fn put_stored(&self, k: &Cid, stored: Vec<u8>) -> Result<()> {
+        let kd = storage_key(k);
+        let previous = self.mutate(kd, Some(stored))?;
+        self.log_access("put", k, previous.is_some());
+        Ok(())
+    }
 
-        fs::remove_dir_all(path).unwrap();
+    /// Keeps the bloom filter in step with a put or delete of `key` that
+    /// found it `existed`.
+    fn track_key(&self, key: &[u8], delete: bool, existed: bool) {
+        match (delete, existed) {
+            (false, false) => self.inner.bloom.insert(key),
+            (true, true) => self.inner.bloom.remove(key),
+            _ => {}
+        }
     }
 
-    #[test]
-    fn put_get() {
-        with_instance(|quarry| {
-            let content: &[u8; 17] = b"morrocan mint tea";
-            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(content));
+    fn log_access(&self, op: &str, k: &Cid, hit: bool) {
+        if let Some(log) = &self.inner.access_log {
+            log.record(op, k, hit);
+        }
+    }
 
-            quarry.put_keyed(&cid, content).unwrap();
+    fn oldest_snapshot(&self) -> Option<u64> {
+        self.inner.snapshots.lock().unwrap().keys().next().copied()
+    }
+
+    fn get_at(&self, k: &Cid, seq: u64) -> Result<Option<Vec<u8>>> {
+        let kd = storage_key(k);
+        let page = self.page_for_key(&kd)?;
+        page.get_at(&kd, seq)
+            .map(|stored| compression::decode(stored))
+            .transpose()
+    }
+
+    fn mutate(&self, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let mut index = self.inner.index.write().unwrap();
+        index.check_writable(&key)?;
+        let object_id = index.page_for(&key);
+        let mut leaf = self.read_page(object_id)?;
+        let codec = self.quota_codec(&key);
+        let delete = value.is_none();
+        let added = value.as_ref().map_or(0, |v| v.len() as u64);
+        // re-putting a stored block never grows the store
+        if let (Some(codec), false) = (codec, delete || leaf.get(&key).is_some()) {
+            let used = self.inner.codec_usage[&codec].load(SeqCst);
+            self.check_codec_quota(codec, used, added)?;
+        }
+        let seq = self.inner.seq.fetch_add(1, SeqCst) + 1;
+        let ret = leaf.insert(key.clone(), seq, value, self.oldest_snapshot());
+        let removed = ret.as_ref().map_or(0, |v| v.len() as u64);
+
+        let mut write_batch = HashMap::new();
+        if delete && leaf.kvs.len() < self.inner.config.min_page_entries {
+            self.merge_page(&mut index, object_id, leaf, &mut write_batch)?;
+        } else {
+            self.write_page(&mut index, object_id, leaf, &mut write_batch)?;
+        }
+
+        self.write_heap(write_batch)?;
+
+        // marble's stats can't be read consistently while another batch is
+        // being written, so this stays under the index lock too
+        #[cfg(feature = "auto_compact")]
+        {
+            let stats = self.inner.heap.stats();
+            if stats.dead_objects > stats.live_objects {
+                self.inner.heap.maintenance()?;
+            }
+        }
+        // accounted before the lock is released, so the next writer checks
+        // its quota against this write too
+        self.account(codec, added, removed);
+        drop(index);
+
+        self.track_key(&key, delete, ret.is_some());
+
+        Ok(ret)
+    }
+}
+
+/// The CID a block is stored under: CIDv0s are stored as the equivalent
+/// dag-pb CIDv1 so that either version finds the block.
+pub(crate) fn storage_cid(k: &Cid) -> Cid {
+    match k.version() {
+        cid::Version::V0 => Cid::new_v1(DAG_PB, *k.hash()),
+        cid::Version::V1 => *k,
+    }
+}
+
+/// The key a block is stored under, the bytes of its `storage_cid`.
+fn storage_key(k: &Cid) -> Vec<u8> {
+    storage_cid(k).to_bytes()
+}
+
+fn read_page(heap: &Marble, object_id: ObjectId) -> Result<Page> {
+    // readers outside the index lock can race with a page being rewritten
+    let page_data = heap
+        .read(object_id)?
+        .ok_or_else(|| anyhow!("page {} is missing", object_id))?;
+    format::decode(&page_data)
+}
+
+impl Blockstore for Quarry {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        let kd = storage_key(k);
+        let previous = self.mutate(kd, None)?;
+        self.log_access("delete", k, previous.is_some());
+        Ok(())
+    }
+    /// Identity CIDs carry their block, which is returned without looking
+    /// at the store.
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        if k.hash().code() == IDENTITY {
+            return Ok(Some(k.hash().digest().to_vec()));
+        }
+        let kd = storage_key(k);
+        let page = self.page_for_key(&kd)?;
+        let block = page
+            .get(&kd)
+            .map(|stored| compression::decode(stored))
+            .transpose()?;
+        self.log_access("get", k, block.is_some());
+        Ok(block)
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        if k.hash().code() == IDENTITY {
+            return Ok(true);
+        }
+        let kd = storage_key(k);
+        if !self.inner.bloom.may_contain(&kd) {
+            return Ok(false);
+        }
+        Ok(self.page_for_key(&kd)?.get(&kd).is_some())
+    }
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.put_stored(k, compression::encode_plain(block))
+    }
+
+    /// Writes every block, and the pages they split into, in a single
+    /// marble write batch.
+    fn put_many_keyed<D, I>(&self, blocks: I) -> Result<()>
+    where
+        Self: Sized,
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = (Cid, D)>,
+    {
+        let batch = self.open_batch();
+        for (c, b) in blocks {
+            batch.put_keyed(&c, b.as_ref());
+        }
+        self.commit_batch(batch)?;
+        Ok(())
+    }
+}
+
+impl wiresaw::Getter for Quarry {
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        Blockstore::get(self, k)
+    }
+}
+
+impl wiresaw::Storer for Quarry {
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        Blockstore::put_keyed(self, k, block)
+    }
+}
+
+/// A read-only view of a `Quarry` as it was at a given sequence number.
+pub struct QuarrySnapshot<'a> {
+    quarry: &'a Quarry,
+    seq: u64,
+}
+
+impl<'a> QuarrySnapshot<'a> {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl<'a> Drop for QuarrySnapshot<'a> {
+    fn drop(&mut self) {
+        let mut snapshots = self.quarry.inner.snapshots.lock().unwrap();
+        if let Some(count) = snapshots.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&self.seq);
+            }
+        }
+    }
+}
+
+impl<'a> Blockstore for QuarrySnapshot<'a> {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        Err(anyhow!("cannot delete {} from a read-only snapshot", k))
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        self.quarry.get_at(k, self.seq)
+    }
+    fn put_keyed(&self, k: &Cid, _block: &[u8]) -> Result<()> {
+        Err(anyhow!("cannot put {} into a read-only snapshot", k))
+    }
+}
+
+/// A store opened with `Quarry::open_read_only`, rejecting every write.
+pub struct ReadOnlyQuarry {
+    quarry: Quarry,
+}
+
+impl ReadOnlyQuarry {
+    pub fn list_cids(&self) -> Result<impl Iterator<Item = Result<Cid>> + '_> {
+        self.quarry.list_cids()
+    }
+}
+
+impl Blockstore for ReadOnlyQuarry {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        Err(anyhow!("cannot delete {} from a store opened read-only", k))
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        self.quarry.get(k)
+    }
+    fn put_keyed(&self, k: &Cid, _block: &[u8]) -> Result<()> {
+        Err(anyhow!("cannot put {} into a store opened read-only", k))
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        self.quarry.has(k)
+    }
+}
+
+/// Layer of abstraction for block-centered methods over a datastore.
+pub trait Blockstore {
+    /// Delete a block from the blockstore.
+    fn delete_block(&self, k: &Cid) -> Result<()>;
+
+    /// Gets the block from the blockstore.
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>>;
+
+    /// Put a block with a pre-computed cid.
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()>;
+
+    /// Checks if the blockstore has the specified block.
+    fn has(&self, k: &Cid) -> Result<bool> {
+        Ok(self.get(k)?.is_some())
+    }
+
+    /// Hashes the block with `hash_code`, puts it under the CIDv1 made of
+    /// `codec` and that hash, and returns the CID.
+    fn put_keyed_with_codec(&self, block: &[u8], codec: u64, hash_code: Code) -> Result<Cid> {
+        let cid = Cid::new_v1(codec, hash_code.digest(block));
+        self.put_keyed(&cid, block)?;
+        Ok(cid)
+    }
+
+    /// Gets the block as a shared buffer.
+    ///
+    /// By default, this wraps the result of get. Stores that keep blocks
+    /// in shared buffers return them without copying.
+    fn get_bytes(&self, k: &Cid) -> Result<Option<Bytes>> {
+        Ok(self.get(k)?.map(Bytes::from))
+    }
+
+    /// Bulk-put pre-keyed blocks into the blockstore.
+    ///
+    /// By default, this defers to put_keyed.
+    fn put_many_keyed<D, I>(&self, blocks: I) -> Result<()>
+    where
+        Self: Sized,
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = (Cid, D)>,
+    {
+        for (c, b) in blocks {
+            self.put_keyed(&c, b.as_ref())?
+        }
+        Ok(())
+    }
+}
+
+pub trait Buffered: Blockstore {
+    fn flush(&self, root: &Cid) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{raw_blocks, with_config, with_dir, with_instance};
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn put_get() {
+        with_instance(|quarry| {
+            let content: &[u8; 17] = b"morrocan mint tea";
+            // the store is agnostic of the hash function
+            for code in [Code::Sha2_256, Code::Sha3_256, Code::Blake3_256] {
+                let cid = Cid::new_v1(0x55, code.digest(content));
+
+                quarry.put_keyed(&cid, content).unwrap();
+
+                let result = quarry.get(&cid).unwrap();
+                assert_eq!(result, Some(content.to_vec()));
+            }
+        });
+    }
+
+    #[test]
+    fn page_histogram() {
+        with_instance(|quarry| {
+            for (lo, entries) in [(1u8, 3u8), (2, 12), (3, 15), (4, 25)] {
+                let kvs = (0..entries)
+                    .map(|i| (vec![lo, i], vec![(1, Some(vec![i]))]))
+                    .collect();
+                let page = Page {
+                    hi: Some(vec![lo + 1]),
+                    lo: vec![lo],
+                    kvs,
+                };
+                quarry.allocate_page(page).unwrap();
+            }
+
+            // the initial empty page falls in the first bucket with the 3 entry page
+            let histogram = quarry.page_histogram().unwrap();
+            assert_eq!(histogram, vec![(0, 2), (10, 2), (20, 1)]);
+
+            let mut out = vec![];
+            quarry.print_histogram(&mut out).unwrap();
+            let chart = String::from_utf8(out).unwrap();
+            assert_eq!(chart, "  0-9 | ## 2\n10-19 | ## 2\n20-29 | # 1\n");
+        });
+    }
+
+    #[test]
+    fn snapshot_isolation() {
+        with_instance(|quarry| {
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"v1"));
+
+            quarry.put_keyed(&cid, b"v1").unwrap();
+            let snapshot = quarry.open_snapshot(quarry.current_seq());
+            quarry.put_keyed(&cid, b"v2").unwrap();
+            quarry.delete_block(&cid).unwrap();
+
+            assert_eq!(snapshot.get(&cid).unwrap(), Some(b"v1".to_vec()));
+            assert_eq!(quarry.get(&cid).unwrap(), None);
+            assert!(snapshot.put_keyed(&cid, b"v3").is_err());
+
+            // once the snapshot is gone the next write prunes old versions
+            drop(snapshot);
+            quarry.put_keyed(&cid, b"v3").unwrap();
+            let page = quarry.page_for_key(&cid.to_bytes()).unwrap();
+            assert_eq!(page.kvs[&cid.to_bytes()].len(), 1);
+            assert_eq!(quarry.get(&cid).unwrap(), Some(b"v3".to_vec()));
+        });
+    }
+
+    #[test]
+    fn put_if_not_full() {
+        const MB: u64 = 1 << 20;
+        let config = QuarryConfig::default().with_max_total_bytes(MB);
+        with_config(config.clone(), |quarry, path| {
+            let blocks = raw_blocks(20, 1 << 16);
+
+            let mut inserted = 0;
+            for (cid, block) in &blocks {
+                match quarry.put_if_not_full(cid, block).unwrap() {
+                    PutResult::Inserted => inserted += 1,
+                    PutResult::QuotaExceeded { used, limit } => {
+                        assert_eq!(used, MB);
+                        assert_eq!(limit, MB);
+                    }
+                    PutResult::AlreadyPresent => unreachable!(),
+                }
+            }
+            assert_eq!(inserted, 16);
+
+            let (cid, block) = &blocks[0];
+            assert_eq!(
+                quarry.put_if_not_full(cid, block).unwrap(),
+                PutResult::AlreadyPresent
+            );
+            assert_eq!(quarry.get(cid).unwrap().as_ref(), Some(block));
+
+            // the counter is rebuilt from the pages on reopen
+            drop(quarry);
+            let quarry = Quarry::open_with_config(path, config).unwrap();
+            let (cid, block) = &blocks[19];
+            assert!(matches!(
+                quarry.put_if_not_full(cid, block).unwrap(),
+                PutResult::QuotaExceeded { .. }
+            ));
+
+            quarry.delete_block(&blocks[0].0).unwrap();
+            assert_eq!(
+                quarry.put_if_not_full(cid, block).unwrap(),
+                PutResult::Inserted
+            );
+        });
+    }
+
+    #[test]
+    fn put_if_not_full_lookalike() {
+        let config = QuarryConfig::default().with_max_total_bytes(1 << 20);
+        with_config(config, |quarry, _| {
+            // a plain block that looks like a compression header
+            let lookalike = b"\xffqzc\x01 not compressed".to_vec();
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&lookalike));
+            assert_eq!(
+                quarry.put_if_not_full(&cid, &lookalike).unwrap(),
+                PutResult::Inserted
+            );
+            assert_eq!(quarry.get(&cid).unwrap(), Some(lookalike));
+        });
+    }
+
+    #[test]
+    fn reorder_pages() {
+        with_instance(|quarry| {
+            let mut blocks: Vec<(Vec<u8>, Vec<u8>)> = (0..30u8)
+                .map(|i| {
+                    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&[i]));
+                    (cid.to_bytes(), vec![i])
+                })
+                .collect();
+            blocks.sort();
+
+            // allocate pages in reverse key order to scatter their ids
+            for chunk in blocks.chunks(10).rev() {
+                let page = Page {
+                    hi: None,
+                    lo: chunk[0].0.clone(),
+                    kvs: chunk
+                        .iter()
+                        .map(|(k, v)| (k.clone(), vec![(1, Some(v.clone()))]))
+                        .collect(),
+                };
+                quarry.allocate_page(page).unwrap();
+            }
+
+            // the initial page and the middle one are already in place
+            assert_eq!(quarry.reorder_pages().unwrap(), 2);
+            let ids: Vec<ObjectId> = quarry
+                .inner
+                .index
+                .read()
+                .unwrap()
+                .pages
+                .values()
+                .copied()
+                .collect();
+            assert_eq!(ids, vec![3, 4, 5, 6]);
+            assert_eq!(quarry.reorder_pages().unwrap(), 0);
+
+            for (key, value) in &blocks {
+                let cid = Cid::read_bytes(&key[..]).unwrap();
+                assert_eq!(quarry.get(&cid).unwrap().as_ref(), Some(value));
+            }
+
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"after"));
+            quarry.put_keyed(&cid, b"after").unwrap();
+            assert_eq!(quarry.get(&cid).unwrap(), Some(b"after".to_vec()));
+        });
+    }
+
+    #[test]
+    fn iter_cids_during_splits() {
+        let config = QuarryConfig::default().with_max_page_bytes(4 << 10);
+        with_config(config, |quarry, _| {
+            let cid = |t: u8, i: u8| Cid::new_v1(0x55, Code::Sha2_256.digest(&[t, i]));
+            for i in 0..200u8 {
+                quarry.put_keyed(&cid(0, i), &[0; 64]).unwrap();
+            }
+            let expected: BTreeSet<Cid> = (0..200u8).map(|i| cid(0, i)).collect();
+
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    for t in 1..4u8 {
+                        for i in 0..200u8 {
+                            quarry.put_keyed(&cid(t, i), &[t; 64]).unwrap();
+                        }
+                    }
+                });
+                for _ in 0..20 {
+                    let cids: Vec<Cid> = quarry.iter_cids().collect::<Result<_>>().unwrap();
+                    let unique: BTreeSet<Cid> = cids.iter().copied().collect();
+                    assert_eq!(unique.len(), cids.len());
+                    assert!(unique.is_superset(&expected));
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn commit_batch_from_threads() {
+        with_instance(|quarry| {
+            let cid = |t: u8, i: u8| Cid::new_v1(0x55, Code::Sha2_256.digest(&[t, i]));
+            let doomed = cid(9, 9);
+            quarry.put_keyed(&doomed, b"doomed").unwrap();
+
+            let batch = quarry.open_batch();
+            std::thread::scope(|s| {
+                for t in 0..4u8 {
+                    let batch = &batch;
+                    s.spawn(move || {
+                        for i in 0..25u8 {
+                            batch.put_keyed(&cid(t, i), &[t, i]);
+                        }
+                    });
+                }
+            });
+            batch.delete_block(&doomed);
+            assert_eq!(batch.len(), 101);
+
+            let stats = quarry.commit_batch(batch).unwrap();
+            assert_eq!(
+                stats,
+                BatchStats {
+                    inserts: 100,
+                    deletes: 1,
+                    pages_modified: 1,
+                }
+            );
+
+            for t in 0..4u8 {
+                for i in 0..25u8 {
+                    assert_eq!(quarry.get(&cid(t, i)).unwrap(), Some(vec![t, i]));
+                }
+            }
+            assert!(!quarry.has(&doomed).unwrap());
+        });
+    }
+
+    #[test]
+    fn batch_handle_flushes_every_n_ops() {
+        let config = QuarryConfig::default().with_max_batch_ops(10);
+        with_config(config, |quarry, _| {
+            let cid = |i: u8| Cid::new_v1(0x55, Code::Sha2_256.digest(&[i]));
+            let mut batch = quarry.begin_batch();
+            for i in 0..25u8 {
+                batch.put_keyed(&cid(i), &[i]).unwrap();
+            }
+            assert_eq!(batch.pending(), 5);
+            assert!(quarry.has(&cid(19)).unwrap());
+            assert!(!quarry.has(&cid(20)).unwrap());
+            batch.delete_block(&cid(0)).unwrap();
+            assert_eq!(batch.stats().inserts, 20);
+            batch.commit().unwrap();
+
+            assert!(!quarry.has(&cid(0)).unwrap());
+            for i in 1..25u8 {
+                assert_eq!(quarry.get(&cid(i)).unwrap(), Some(vec![i]));
+            }
+        });
+
+        // without a limit nothing lands before commit
+        with_instance(|quarry| {
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"later"));
+            let mut batch = quarry.begin_batch();
+            batch.put_keyed(&cid, b"later").unwrap();
+            assert!(!quarry.has(&cid).unwrap());
+            batch.commit().unwrap();
+            assert!(quarry.has(&cid).unwrap());
+        });
+    }
+
+    #[test]
+    fn stats() {
+        with_instance(|quarry| {
+            for (cid, block) in raw_blocks(20, 100) {
+                quarry.put_keyed(&cid, &block).unwrap();
+            }
+            let stats = quarry.stats().unwrap();
+            assert_eq!(stats.live_pages, 1);
+            assert_eq!(stats.total_kvs, 20);
+            assert_eq!(stats.total_data_bytes, 2000);
+            assert_eq!(stats.total_data_bytes, quarry.total_data_bytes().unwrap());
+            // the index and the page
+            assert_eq!(stats.live_marble_objects, 2);
+            assert!(stats.to_string().contains("data bytes:     2000"));
+        });
+    }
+
+    #[test]
+    fn vacuum() {
+        with_instance(|quarry| {
+            let blocks = raw_blocks(50, 4096);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            for (cid, _) in &blocks[10..] {
+                quarry.delete_block(cid).unwrap();
+            }
+            // empty pages left behind past every stored key
+            for lo in [0xf0u8, 0xf1, 0xf2] {
+                let page = Page {
+                    hi: None,
+                    lo: vec![lo],
+                    kvs: BTreeMap::new(),
+                };
+                quarry.allocate_page(page).unwrap();
+            }
+            // and an index entry whose page is gone
+            quarry
+                .inner
+                .index
+                .write()
+                .unwrap()
+                .pages
+                .insert(vec![0xf8], 999);
+
+            let stats = quarry.vacuum().unwrap();
+            assert_eq!(stats.pages_before, 5);
+            assert_eq!(stats.pages_after, 1);
+            assert!(stats.bytes_after < stats.bytes_before);
+
+            for (cid, block) in &blocks[..10] {
+                assert_eq!(quarry.get(cid).unwrap().as_ref(), Some(block));
+            }
+            assert!(!quarry.has(&blocks[10].0).unwrap());
+        });
+    }
+
+    // with auto_compact, writes leave no dead objects for compact to find
+    #[test]
+    #[cfg(not(feature = "auto_compact"))]
+    fn compact() {
+        with_instance(|quarry| {
+            for (cid, block) in raw_blocks(50, 4096) {
+                quarry.put_keyed(&cid, &block).unwrap();
+                quarry.delete_block(&cid).unwrap();
+            }
+            let before = quarry.stats().unwrap();
+            assert!(before.dead_marble_objects > before.live_marble_objects);
+
+            let report = quarry.compact().unwrap();
+            assert!(report.reclaimed_bytes > 0);
+            let after = quarry.stats().unwrap();
+            assert!(after.dead_marble_objects < before.dead_marble_objects);
+        });
+    }
+
+    #[test]
+    fn gc_thread_compacts_in_background() {
+        with_dir(|path| {
+            let quarry =
+                Quarry::open_with_gc_thread(path, std::time::Duration::from_millis(20)).unwrap();
+            for (cid, block) in raw_blocks(50, 4096) {
+                quarry.put_keyed(&cid, &block).unwrap();
+                quarry.delete_block(&cid).unwrap();
+            }
+            let compacted = (0..250).any(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                let stats = quarry.stats().unwrap();
+                stats.dead_marble_objects <= stats.live_marble_objects
+            });
+            assert!(compacted);
+
+            // the thread doesn't keep the store alive once every handle is gone
+            let inner = Arc::downgrade(&quarry.inner);
+            drop(quarry);
+            assert!((0..250).any(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                inner.upgrade().is_none()
+            }));
+        });
+    }
+
+    #[test]
+    fn put_keyed_with_codec() {
+        with_instance(|quarry| {
+            let block = b"hashed for me";
+            let cid = quarry
+                .put_keyed_with_codec(block, 0x71, Code::Blake3_256)
+                .unwrap();
+            assert_eq!(cid, Cid::new_v1(0x71, Code::Blake3_256.digest(block)));
+            assert_eq!(quarry.get(&cid).unwrap().unwrap(), block);
+        });
+    }
+
+    #[test]
+    fn inline_small_blocks() {
+        with_instance(|quarry| {
+            let small = b"sixteen bytes!!!";
+            let cid = quarry.put_keyed_or_inline(small).unwrap();
+            assert_eq!(cid.hash().code(), IDENTITY);
+            assert_eq!(quarry.get(&cid).unwrap().unwrap(), small);
+            assert!(quarry.has(&cid).unwrap());
+            assert_eq!(quarry.iter_cids().count(), 0);
+
+            let large = [7u8; 33];
+            let cid = quarry.put_keyed_or_inline(&large).unwrap();
+            assert_eq!(cid, Cid::new_v1(RAW, Code::Sha2_256.digest(&large)));
+            assert_eq!(quarry.get(&cid).unwrap().unwrap(), large);
+            assert_eq!(quarry.iter_cids().count(), 1);
+        });
+    }
+
+    #[test]
+    fn cid_v0_and_v1_share_blocks() {
+        with_instance(|quarry| {
+            let block = b"dag-pb block".to_vec();
+            let v0 = Cid::new_v0(Code::Sha2_256.digest(&block)).unwrap();
+            let v1 = Cid::new_v1(DAG_PB, *v0.hash());
+            quarry.put_keyed(&v0, &block).unwrap();
+            assert_eq!(quarry.get(&v1).unwrap(), Some(block.clone()));
+            assert_eq!(quarry.get(&v0).unwrap(), Some(block.clone()));
+            assert_eq!(quarry.iter_cids().next().unwrap().unwrap(), v1);
+
+            quarry.delete_block(&v1).unwrap();
+            assert!(!quarry.has(&v0).unwrap());
+
+            let batch = quarry.open_batch();
+            batch.put_keyed(&v1, &block);
+            quarry.commit_batch(batch).unwrap();
+            assert_eq!(quarry.get_block_size(&v0).unwrap(), Some(block.len()));
+        });
+    }
+
+    #[test]
+    fn codec_quota() {
+        const RAW: u64 = 0x55;
+        const DAG_CBOR: u64 = 0x71;
+        let config = QuarryConfig::default().with_codec_quota(RAW, 100 << 10);
+        with_config(config, |quarry, _| {
+            for (cid, block) in raw_blocks(10, 10 << 10) {
+                quarry.put_keyed(&cid, &block).unwrap();
+                // putting an already stored block is always accepted
+                quarry.put_keyed(&cid, &block).unwrap();
+            }
+
+            let block = vec![10u8; 10 << 10];
+            let cid = Cid::new_v1(RAW, Code::Sha2_256.digest(&block));
+            let err = quarry.put_keyed(&cid, &block).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<Error>(),
+                Some(&Error::CodecQuotaExceeded {
+                    codec: RAW,
+                    used: 100 << 10,
+                    limit: 100 << 10,
+                })
+            );
+            assert!(!quarry.has(&cid).unwrap());
+
+            let cid = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&block));
+            quarry.put_keyed(&cid, &block).unwrap();
+            assert!(quarry.has(&cid).unwrap());
+        });
+    }
+
+    #[test]
+    fn quotas_hold_under_racing_puts() {
+        let config = QuarryConfig::default()
+            .with_max_total_bytes(1 << 20)
+            .with_codec_quota(0x55, 256 << 10);
+        with_config(config, |quarry, _| {
+            std::thread::scope(|s| {
+                for t in 0..8u8 {
+                    let quarry = &quarry;
+                    s.spawn(move || {
+                        for i in 0..16u8 {
+                            let block = vec![t * 16 + i; 8 << 10];
+                            for codec in [0x55, 0x71] {
+                                let cid = Cid::new_v1(codec, Code::Sha2_256.digest(&block));
+                                if let Err(err) = quarry.put_if_not_full(&cid, &block) {
+                                    assert!(matches!(
+                                        err.downcast_ref::<Error>(),
+                                        Some(Error::CodecQuotaExceeded { .. })
+                                    ));
+                                }
+                            }
+                        }
+                    });
+                }
+            });
+            assert!(quarry.inner.used_bytes.load(SeqCst) <= 1 << 20);
+            assert_eq!(quarry.inner.codec_usage[&0x55].load(SeqCst), 256 << 10);
+        });
+    }
+
+    #[test]
+    fn sample() {
+        with_instance(|quarry| {
+            for i in 0..100u8 {
+                let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&[i]));
+                quarry.put_keyed(&cid, &[i]).unwrap();
+            }
+
+            let sample = quarry.sample(10).unwrap();
+            let distinct: std::collections::HashSet<_> = sample.iter().collect();
+            assert_eq!(distinct.len(), 10);
+            for cid in &sample {
+                assert!(quarry.get(cid).unwrap().is_some());
+            }
+
+            assert_eq!(quarry.sample(1000).unwrap().len(), 100);
+            for (cid, block) in quarry.sample_blocks(5).unwrap() {
+                assert_eq!(cid, Cid::new_v1(0x55, Code::Sha2_256.digest(&block)));
+            }
+        });
+    }
+
+    #[test]
+    fn get_range() {
+        with_instance(|quarry| {
+            let mut cids: Vec<Cid> = (0..100u8)
+                .map(|i| Cid::new_v1(0x55, Code::Sha2_256.digest(&[i])))
+                .collect();
+            for cid in &cids {
+                quarry.put_keyed(cid, &cid.hash().digest()[..4]).unwrap();
+            }
+            cids.sort_by_key(|cid| cid.to_bytes());
+
+            let keys = |blocks: Vec<(Cid, Vec<u8>)>| -> Vec<Cid> {
+                blocks.into_iter().map(|(cid, _)| cid).collect()
+            };
+            let range = quarry.get_range(&cids[10], &cids[30], 100).unwrap();
+            assert_eq!(range[0].1, &cids[10].hash().digest()[..4]);
+            assert_eq!(keys(range), cids[10..30]);
+            assert_eq!(
+                keys(quarry.get_range(&cids[10], &cids[30], 5).unwrap()),
+                cids[10..15]
+            );
+            assert_eq!(
+                keys(quarry.get_range(&cids[0], &cids[99], 1000).unwrap()),
+                cids[..99]
+            );
+            assert!(quarry
+                .get_range(&cids[30], &cids[10], 10)
+                .unwrap()
+                .is_empty());
+            assert!(quarry.get_range(&cids[5], &cids[6], 0).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn last_modified() {
+        let start = std::time::SystemTime::now();
+        with_instance(|quarry| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"hello"));
+            quarry.put_keyed(&cid, b"hello").unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+
+            assert!(quarry.last_modified().unwrap() > start);
+            assert_eq!(quarry.block_insertion_time(&cid).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn compact_to() {
+        with_instance(|quarry| {
+            let blocks = raw_blocks(50, 4096);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            for (cid, _) in &blocks[10..] {
+                quarry.delete_block(cid).unwrap();
+            }
+
+            with_dir(|dest| {
+                let compacted = quarry.compact_to(dest).unwrap();
+                assert!(compacted.size_on_disk().unwrap() < quarry.size_on_disk().unwrap());
+                for (cid, block) in &blocks[..10] {
+                    assert_eq!(compacted.get(cid).unwrap().as_ref(), Some(block));
+                }
+                for (cid, _) in &blocks[10..] {
+                    assert!(!compacted.has(cid).unwrap());
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn tombstones() {
+        with_instance(|quarry| {
+            let deleted = Cid::new_v1(0x55, Code::Sha2_256.digest(b"deleted"));
+            let empty = Cid::new_v1(0x55, Code::Sha2_256.digest(b""));
+            let kept = Cid::new_v1(0x55, Code::Sha2_256.digest(b"kept"));
+            quarry.put_keyed(&deleted, b"deleted").unwrap();
+            quarry.put_keyed(&empty, b"").unwrap();
+            quarry.put_keyed(&kept, b"kept").unwrap();
+
+            // the delete is kept as a tombstone while the snapshot is open
+            let snapshot = quarry.open_snapshot(quarry.current_seq());
+            quarry.delete_block(&deleted).unwrap();
+            drop(snapshot);
+
+            let mut expected = vec![deleted, empty];
+            expected.sort_by_key(|cid| cid.to_bytes());
+            assert_eq!(quarry.tombstone_check().unwrap(), expected);
+
+            assert_eq!(quarry.purge_tombstones().unwrap(), 2);
+            assert!(quarry.tombstone_check().unwrap().is_empty());
+            assert!(!quarry.has(&empty).unwrap());
+            assert_eq!(quarry.get(&kept).unwrap(), Some(b"kept".to_vec()));
+        });
+
+        let config = QuarryConfig::default().with_dead_object_warning_threshold(u64::MAX);
+        with_config(config, |quarry, _| {
+            let empty = Cid::new_v1(0x55, Code::Sha2_256.digest(b""));
+            quarry.put_keyed(&empty, b"").unwrap();
+            assert!(quarry.tombstone_check().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn iter_cids_with_size() {
+        with_instance(|quarry| {
+            let mut expected: Vec<(Cid, usize)> = (0..20usize)
+                .map(|i| {
+                    let block = vec![i as u8; i * 100];
+                    let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&block));
+                    quarry.put_keyed(&cid, &block).unwrap();
+                    (cid, block.len())
+                })
+                .collect();
+            expected.sort_by_key(|(cid, _)| cid.to_bytes());
+
+            let sizes: Vec<(Cid, usize)> =
+                quarry.iter_cids_with_size().collect::<Result<_>>().unwrap();
+            assert_eq!(sizes, expected);
+            assert_eq!(quarry.total_data_bytes().unwrap(), 19 * 20 * 100 / 2);
+        });
+    }
+
+    #[test]
+    fn access_log() {
+        with_dir(|dir| {
+            let log = dir.join("access.log");
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"logged"));
+
+            let config = QuarryConfig::default().with_access_log(&log);
+            let quarry = Quarry::open_with_config(dir.join("store"), config).unwrap();
+            quarry.get(&cid).unwrap();
+            quarry.put_keyed(&cid, b"logged").unwrap();
+            quarry.get(&cid).unwrap();
+            quarry.delete_block(&cid).unwrap();
+            drop(quarry);
+
+            let lines: Vec<String> = fs::read_to_string(&log)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    assert!(line.starts_with("{\"ts\":"));
+                    line.split_once(',').unwrap().1.to_string()
+                })
+                .collect();
+            let expected: Vec<String> = [
+                ("get", false),
+                ("put", false),
+                ("get", true),
+                ("delete", true),
+            ]
+            .iter()
+            .map(|(op, hit)| format!("\"op\":\"{}\",\"cid\":\"{}\",\"hit\":{}}}", op, cid, hit))
+            .collect();
+            assert_eq!(lines, expected);
+        });
+    }
+
+    #[test]
+    fn put_keyed_compressed() {
+        with_instance(|quarry| {
+            let compressible = vec![42u8; 64 << 10];
+            let compressed = Cid::new_v1(0x55, Code::Sha2_256.digest(&compressible));
+            quarry
+                .put_keyed_compressed(&compressed, &compressible, CompressionCodec::Zstd)
+                .unwrap();
+
+            let plain = b"plain block".to_vec();
+            let plain_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&plain));
+            quarry.put_keyed(&plain_cid, &plain).unwrap();
+
+            // a plain block that looks like a compression header
+            let lookalike = b"\xffqzc\x01 not compressed".to_vec();
+            let lookalike_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&lookalike));
+            quarry.put_keyed(&lookalike_cid, &lookalike).unwrap();
+
+            assert_eq!(quarry.get(&compressed).unwrap(), Some(compressible.clone()));
+            assert_eq!(quarry.get(&plain_cid).unwrap(), Some(plain.clone()));
+            assert_eq!(quarry.get(&lookalike_cid).unwrap(), Some(lookalike.clone()));
+
+            let sizes: HashMap<Cid, usize> =
+                quarry.iter_cids_with_size().collect::<Result<_>>().unwrap();
+            assert_eq!(sizes[&compressed], compressible.len());
+            assert_eq!(sizes[&lookalike_cid], lookalike.len());
+            assert!(quarry.inner.used_bytes.load(SeqCst) < 4 << 10);
+        });
+    }
+
+    #[test]
+    fn hot_backup_stream() {
+        with_instance(|quarry| {
+            let mut blocks = raw_blocks(100, 1000);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            blocks.sort_by_key(|(cid, _)| cid.to_bytes());
+            let roots = [blocks[0].0, blocks[50].0];
+            for root in &roots {
+                quarry.pin(root).unwrap();
+            }
+
+            let mut car = vec![];
+            quarry.hot_backup_stream().read_to_end(&mut car).unwrap();
+            let (decoded_roots, decoded_blocks) = wiresaw::codec::car::decode_car_v1(&car).unwrap();
+            assert_eq!(decoded_roots, roots);
+            assert_eq!(decoded_blocks, blocks);
+        });
+    }
+
+    #[test]
+    fn check_and_repair() {
+        with_instance(|quarry| {
+            let blocks = raw_blocks(10, 100);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            let empty = Page {
+                hi: None,
+                lo: vec![0xf0],
+                kvs: BTreeMap::new(),
+            };
+            quarry.allocate_page(empty).unwrap();
+            assert_eq!(
+                quarry.check_and_repair().unwrap(),
+                RepairSummary {
+                    pages_checked: 2,
+                    ..Default::default()
+                }
+            );
+
+            // one entry without a valid version, one with a valid older one
+            let (corrupt, restored) = (&blocks[0], &blocks[1]);
+            let first = quarry.inner.index.read().unwrap().page_for(&[]);
+            let mut page = quarry.read_page(first).unwrap();
+            page.kvs
+                .insert(corrupt.0.to_bytes(), vec![(100, Some(b"bit rot".to_vec()))]);
+            page.kvs
+                .get_mut(&restored.0.to_bytes())
+                .unwrap()
+                .push((101, Some(b"bit rot".to_vec())));
+            let garbage = vec![0xff; 64];
+            let last = quarry.inner.index.read().unwrap().page_for(&[0xff]);
+            quarry
+                .inner
+                .heap
+                .write_batch([
+                    (first, Some(format::encode(&page).unwrap())),
+                    (last, Some(garbage)),
+                ])
+                .unwrap();
+
+            let summary = quarry.check_and_repair().unwrap();
+            assert_eq!(
+                summary,
+                RepairSummary {
+                    pages_checked: 2,
+                    pages_repaired: 1,
+                    entries_deleted: 1,
+                    entries_recovered: 1,
+                }
+            );
+            assert_eq!(quarry.get(&corrupt.0).unwrap(), None);
+            for (cid, block) in &blocks[1..] {
+                assert_eq!(quarry.get(cid).unwrap().as_ref(), Some(block));
+            }
+            assert!(quarry.read_page(last).unwrap().kvs.is_empty());
+            assert_eq!(quarry.inner.used_bytes.load(SeqCst), 900);
+        });
+    }
+
+    #[test]
+    fn readonly_range() {
+        with_instance(|quarry| {
+            let mut cids: Vec<Cid> = (0..30u8)
+                .map(|i| Cid::new_v1(0x55, Code::Sha2_256.digest(&[i])))
+                .collect();
+            cids.sort_by_key(|cid| cid.to_bytes());
+            for cid in &cids[..20] {
+                quarry.put_keyed(cid, b"archived").unwrap();
+            }
+
+            assert_eq!(quarry.mark_readonly_range(&cids[5], &cids[25]).unwrap(), 15);
+            let rejected = |result: Result<()>| {
+                matches!(
+                    result.unwrap_err().downcast_ref::<Error>(),
+                    Some(Error::ImmutableRange { .. })
+                )
+            };
+            assert!(rejected(quarry.put_keyed(&cids[22], b"new")));
+            assert!(rejected(quarry.delete_block(&cids[5])));
+            let batch = quarry.open_batch();
+            batch.delete_block(&cids[10]);
+            assert!(quarry.commit_batch(batch).is_err());
+            quarry.put_keyed(&cids[25], b"new").unwrap();
+            quarry.delete_block(&cids[4]).unwrap();
+            assert!(quarry.has(&cids[10]).unwrap());
+
+            assert!(quarry.unmark_readonly_range(&cids[5], &cids[25]).unwrap());
+            assert!(!quarry.unmark_readonly_range(&cids[5], &cids[25]).unwrap());
+            quarry.delete_block(&cids[5]).unwrap();
+        });
+    }
+
+    #[test]
+    fn split_pages() {
+        let config = QuarryConfig::default().with_max_page_bytes(4 << 10);
+        with_config(config.clone(), |quarry, path| {
+            let blocks = raw_blocks(100, 500);
+            for (cid, block) in &blocks[..50] {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            let batch = quarry.open_batch();
+            for (cid, block) in &blocks[50..] {
+                batch.put_keyed(cid, block);
+            }
+            quarry.commit_batch(batch).unwrap();
+
+            let check = |quarry: &Quarry| {
+                let index = quarry.inner.index.read().unwrap();
+                assert!(index.pages.len() > 10);
+                for (lo, object_id) in &index.pages {
+                    let page = quarry.read_page(*object_id).unwrap();
+                    assert_eq!(&page.lo, lo);
+                    assert!(format::encode(&page).unwrap().len() <= 4 << 10);
+                    for key in page.kvs.keys() {
+                        assert!(key >= lo);
+                        assert!(page.hi.as_ref().is_none_or(|hi| key < hi));
+                    }
+                }
+                drop(index);
+                for (cid, block) in &blocks {
+                    assert_eq!(quarry.get(cid).unwrap().as_ref(), Some(block));
+                }
+            };
+            check(&quarry);
+
+            drop(quarry);
+            check(&Quarry::open_with_config(path, config).unwrap());
+        });
+    }
+
+    #[test]
+    fn merge_pages() {
+        let config = QuarryConfig::default().with_max_page_bytes(4 << 10);
+        with_config(config, |quarry, _| {
+            let blocks = raw_blocks(100, 500);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            let pages_before = quarry.inner.index.read().unwrap().pages.len();
+
+            for (cid, _) in blocks.iter().filter(|(_, block)| block[0] % 10 != 0) {
+                quarry.delete_block(cid).unwrap();
+            }
+            let index = quarry.inner.index.read().unwrap();
+            assert!(index.pages.len() < pages_before / 2);
+            let mut bounds = index.pages.keys().skip(1);
+            for (lo, object_id) in &index.pages {
+                let page = quarry.read_page(*object_id).unwrap();
+                assert_eq!(&page.lo, lo);
+                assert_eq!(page.hi.as_ref(), bounds.next());
+            }
+            drop(index);
+
+            for (cid, block) in &blocks {
+                let expected = (block[0] % 10 == 0).then_some(block);
+                assert_eq!(quarry.get(cid).unwrap().as_ref(), expected);
+            }
+        });
+    }
+
+    #[test]
+    fn put_many_keyed() {
+        let config = QuarryConfig::default().with_max_page_bytes(4 << 10);
+        with_config(config, |quarry, _| {
+            let blocks = raw_blocks(100, 500);
+            quarry.put_many_keyed(blocks.clone()).unwrap();
+
+            assert!(quarry.inner.index.read().unwrap().pages.len() > 1);
+            for (cid, block) in &blocks {
+                assert_eq!(quarry.get(cid).unwrap().as_ref(), Some(block));
+            }
+            assert_eq!(quarry.total_data_bytes().unwrap(), 100 * 500);
+        });
+    }
+
+    #[test]
+    fn scan() {
+        with_instance(|quarry| {
+            let mut blocks: Vec<(Cid, Vec<u8>)> = (0..1000u32)
+                .map(|i| {
+                    let block = i.to_le_bytes().to_vec();
+                    (Cid::new_v1(0x55, Code::Sha2_256.digest(&block)), block)
+                })
+                .collect();
+            quarry.put_many_keyed(blocks.clone()).unwrap();
+
+            let mut scanned: Vec<(Cid, Vec<u8>)> =
+                quarry.scan().unwrap().collect::<Result<_>>().unwrap();
+            blocks.sort();
+            scanned.sort();
+            assert_eq!(scanned, blocks);
+        });
+    }
+
+    #[test]
+    fn verify() {
+        with_instance(|quarry| {
+            let blocks = raw_blocks(10, 100);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            for lo in [vec![0xf0], vec![0xf8]] {
+                let empty = Page {
+                    hi: None,
+                    lo,
+                    kvs: BTreeMap::new(),
+                };
+                quarry.allocate_page(empty).unwrap();
+            }
+            let report = quarry.verify().unwrap();
+            assert!(report.is_consistent());
+            assert_eq!(report.pages_checked, 3);
+
+            let first = quarry.inner.index.read().unwrap().page_for(&[]);
+            let mut page = quarry.read_page(first).unwrap();
+            page.kvs.insert(
+                blocks[0].0.to_bytes(),
+                vec![(100, Some(b"bit rot".to_vec()))],
+            );
+            let middle = quarry.inner.index.read().unwrap().page_for(&[0xf0]);
+            let mut stray = quarry.read_page(middle).unwrap();
+            let (key, versions) = page.kvs.pop_last().unwrap();
+            stray.kvs.insert(key, versions);
+            let last = quarry.inner.index.read().unwrap().page_for(&[0xf8]);
+            quarry
+                .inner
+                .heap
+                .write_batch([
+                    (first, Some(format::encode(&page).unwrap())),
+                    (middle, Some(format::encode(&stray).unwrap())),
+                    (last, None),
+                ])
+                .unwrap();
+
+            assert_eq!(
+                quarry.verify().unwrap(),
+                VerifyReport {
+                    pages_checked: 3,
+                    missing_pages: 1,
+                    corrupt_pages: 0,
+                    keys_out_of_range: 1,
+                    cid_mismatches: 1,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn rebuild_index() {
+        let config = QuarryConfig::default().with_max_page_bytes(4 << 10);
+        with_config(config.clone(), |quarry, path| {
+            let blocks = raw_blocks(100, 500);
+            quarry.put_many_keyed(blocks.clone()).unwrap();
+            let pages = quarry.inner.index.read().unwrap().pages.clone();
+            assert!(pages.len() > 1);
+            quarry
+                .inner
+                .heap
+                .write_batch([(INDEX_OBJECT_ID, Some(vec![0xff; 64]))])
+                .unwrap();
+            drop(quarry);
+            assert!(Quarry::open_with_config(path, config.clone()).is_err());
+
+            let quarry = Quarry::rebuild_index(path, config).unwrap();
+            assert_eq!(quarry.inner.index.read().unwrap().pages, pages);
+            assert!(quarry.verify().unwrap().is_consistent());
+            for (cid, block) in &blocks {
+                assert_eq!(quarry.get(cid).unwrap().as_ref(), Some(block));
+            }
+        });
+    }
+
+    #[test]
+    fn block_sizes() {
+        with_instance(|quarry| {
+            let plain = vec![7u8; 300];
+            let plain_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&plain));
+            quarry.put_keyed(&plain_cid, &plain).unwrap();
+            let packed = vec![9u8; 4000];
+            let packed_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&packed));
+            quarry
+                .put_keyed_compressed(&packed_cid, &packed, CompressionCodec::Zstd)
+                .unwrap();
+
+            assert_eq!(quarry.get_block_size(&plain_cid).unwrap(), Some(300));
+            assert_eq!(quarry.get_block_size(&packed_cid).unwrap(), Some(4000));
+            let missing = Cid::new_v1(0x55, Code::Sha2_256.digest(b"missing"));
+            assert_eq!(quarry.get_block_size(&missing).unwrap(), None);
+
+            let stored = quarry.total_stored_size().unwrap();
+            assert!(stored > 300 && stored < 4300);
+            assert_eq!(quarry.total_data_bytes().unwrap(), 4300);
+        });
+    }
+
+    #[test]
+    fn bloom_filter() {
+        with_config(QuarryConfig::default(), |quarry, path| {
+            let blocks = raw_blocks(100, 10);
+            let (kept, deleted) = blocks.split_at(50);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            for (cid, _) in deleted {
+                quarry.delete_block(cid).unwrap();
+            }
+            for (cid, _) in kept {
+                assert!(quarry.has(cid).unwrap());
+            }
+            for (cid, _) in deleted {
+                assert!(!quarry.inner.bloom.may_contain(&cid.to_bytes()));
+                assert!(!quarry.has(cid).unwrap());
+            }
+            drop(quarry);
+
+            let quarry = Quarry::open(path).unwrap();
+            for (cid, _) in kept {
+                assert!(quarry.inner.bloom.may_contain(&cid.to_bytes()));
+                assert!(quarry.has(cid).unwrap());
+            }
+            for (cid, _) in deleted {
+                assert!(!quarry.has(cid).unwrap());
+            }
+        });
+    }
+
+    #[test]
+    fn page_cache() {
+        let config = QuarryConfig::default()
+            .with_max_page_bytes(4 << 10)
+            .with_cache_capacity(2);
+        with_config(config, |quarry, _| {
+            let blocks = raw_blocks(50, 500);
+            for (cid, block) in &blocks {
+                quarry.put_keyed(cid, block).unwrap();
+            }
+            for (cid, block) in &blocks {
+                assert_eq!(quarry.get(cid).unwrap().as_ref(), Some(block));
+            }
+
+            let (cid, _) = &blocks[0];
+            let object_id = quarry.inner.index.read().unwrap().page_for(&cid.to_bytes());
+            assert!(quarry.get(cid).unwrap().is_some());
+            assert!(quarry.inner.cache.get(object_id).is_some());
+            quarry.delete_block(cid).unwrap();
+            assert!(quarry.inner.cache.get(object_id).is_none());
+            assert_eq!(quarry.get(cid).unwrap(), None);
+            quarry.put_keyed(cid, b"replaced").unwrap();
+            assert_eq!(quarry.get(cid).unwrap(), Some(b"replaced".to_vec()));
+        });
+    }
+
+    #[test]
+    fn concurrent_handles() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Quarry>();
+
+        let config = QuarryConfig::default().with_max_page_bytes(4 << 10);
+        with_config(config, |quarry, _| {
+            const THREADS: u32 = 8;
+            const PER_THREAD: u32 = 10_000 / THREADS;
+            std::thread::scope(|scope| {
+                for t in 0..THREADS {
+                    let quarry = quarry.clone();
+                    scope.spawn(move || {
+                        for i in t * PER_THREAD..(t + 1) * PER_THREAD {
+                            let block = i.to_le_bytes();
+                            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&block));
+                            quarry.put_keyed(&cid, &block).unwrap();
+                            assert_eq!(quarry.get(&cid).unwrap(), Some(block.to_vec()));
+                        }
+                    });
+                }
+            });
+
+            assert_eq!(quarry.iter_cids().count(), 10_000);
+            for i in 0..10_000u32 {
+                let block = i.to_le_bytes();
+                let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&block));
+                assert!(quarry.has(&cid).unwrap());
+            }
+        });
+    }
+
+    #[test]
+    fn open_read_only() {
+        with_instance(|quarry| {
+            let path = quarry.inner.path.clone();
+            let content = b"read me";
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(content));
+            quarry.put_keyed(&cid, content).unwrap();
+            drop(quarry);
+
+            let reader = Quarry::open_read_only(&path).unwrap();
+            let store: &dyn Blockstore = &reader;
+            assert_eq!(store.get(&cid).unwrap(), Some(content.to_vec()));
+            assert!(store.has(&cid).unwrap());
+            assert_eq!(
+                reader
+                    .list_cids()
+                    .unwrap()
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap(),
+                vec![cid]
+            );
+            assert!(store.put_keyed(&cid, b"other").is_err());
+            assert!(store.delete_block(&cid).is_err());
+            assert_eq!(store.get(&cid).unwrap(), Some(content.to_vec()));
+
+            assert!(Quarry::open_read_only(path.join("missing")).is_err());
+        });
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        with_instance(|quarry| {
+            let blocks: Vec<(Cid, Vec<u8>)> = (0..2000u32)
+                .map(|i| {
+                    let block = i.to_le_bytes().repeat(10);
+                    (Cid::new_v1(0x55, Code::Sha2_256.digest(&block)), block)
+                })
+                .collect();
+            quarry.put_many_keyed(blocks.clone()).unwrap();
+            quarry.delete_block(&blocks[0].0).unwrap();
+            quarry.pin(&blocks[1].0).unwrap();
+            quarry.pin(&blocks[2].0).unwrap();
+
+            with_dir(|dir| {
+                let backup = dir.join("backup.car");
+                quarry.backup_to(&backup).unwrap();
+                let restored = Quarry::restore_from(&backup, dir.join("restored")).unwrap();
+                assert_eq!(restored.get(&blocks[0].0).unwrap(), None);
+                for (cid, block) in &blocks[1..] {
+                    assert_eq!(restored.get(cid).unwrap().as_ref(), Some(block));
+                }
+                assert_eq!(restored.iter_cids().count(), blocks.len() - 1);
+                assert_eq!(restored.pins(), quarry.pins());
+                drop(restored);
+
+                let err = Quarry::restore_from(&backup, dir.join("restored"))
+                    .err()
+                    .unwrap();
+                assert!(err.to_string().contains("not empty"));
+            });
+        });
+    }
+
+    #[test]
+    fn delete_range() {
+        const RAW: u64 = 0x55;
+        const DAG_CBOR: u64 = 0x71;
+        with_instance(|quarry| {
+            let blocks: Vec<(Cid, Vec<u8>)> = (0..50_000u32)
+                .map(|i| {
+                    let block = i.to_le_bytes().to_vec();
+                    let codec = if i % 2 == 0 { RAW } else { DAG_CBOR };
+                    (Cid::new_v1(codec, Code::Sha2_256.digest(&block)), block)
+                })
+                .collect();
+            quarry.put_many_keyed(blocks.clone()).unwrap();
+            let pages_before = quarry.inner.index.read().unwrap().pages.len();
+
+            // v1 CIDs start with their version then their codec
+            assert_eq!(quarry.delete_range(&[1, 0x55], &[1, 0x56]).unwrap(), 25_000);
+            assert_eq!(quarry.delete_range(&[1, 0x55], &[1, 0x56]).unwrap(), 0);
+            assert!(quarry.inner.index.read().unwrap().pages.len() < pages_before);
+            assert!(quarry.verify().unwrap().is_consistent());
+
+            for (cid, block) in blocks
+                .iter()
+                .step_by(1000)
+                .chain(blocks.iter().skip(1).step_by(1000))
+            {
+                let expected = (cid.codec() == DAG_CBOR).then_some(block);
+                assert_eq!(quarry.get(cid).unwrap().as_ref(), expected);
+            }
+            assert_eq!(quarry.iter_cids().count(), 25_000);
+            assert_eq!(quarry.inner.used_bytes.load(SeqCst), 25_000 * 4);
+        });
+    }
+
+    #[test]
+    fn scan_prefix() {
+        const DAG_CBOR: u64 = 0x71;
+        let config = QuarryConfig::default().with_max_page_bytes(4 << 10);
+        with_config(config, |quarry, _| {
+            let blocks: Vec<(Cid, Vec<u8>)> = (0..300u32)
+                .map(|i| {
+                    let block = i.to_le_bytes().to_vec();
+                    let codec = if i % 3 == 0 { DAG_CBOR } else { 0x55 };
+                    (Cid::new_v1(codec, Code::Sha2_256.digest(&block)), block)
+                })
+                .collect();
+            quarry.put_many_keyed(blocks.clone()).unwrap();
 
-            let result = quarry.get(&cid).unwrap();
-            assert_eq!(result, Some(content.to_vec()));
+            let mut scanned: Vec<(Cid, Vec<u8>)> = quarry
+                .scan_prefix(&[1, 0x71])
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            let mut expected: Vec<(Cid, Vec<u8>)> = blocks
+                .into_iter()
+                .filter(|(cid, _)| cid.codec() == DAG_CBOR)
+                .collect();
+            scanned.sort();
+            expected.sort();
+            assert_eq!(scanned, expected);
+            assert_eq!(quarry.scan_prefix(&[1, 0x70]).unwrap().count(), 0);
         });
     }
 }
@@ -0,0 +1,547 @@
+use crate::{
+    compression, format, read_page, Index, ObjectId, Page, Quarry, QuarryConfig, INDEX_OBJECT_ID,
+    PINS_OBJECT_ID,
+};
+use anyhow::Result;
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use marble::Marble;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{Arc, Weak};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Before and after figures of a `Quarry::vacuum`.
+#[derive(Debug, Clone)]
+pub struct VacuumStats {
+    pub pages_before: usize,
+    pub pages_after: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub duration: Duration,
+}
+
+/// What `Quarry::check_and_repair` found and fixed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairSummary {
+    pub pages_checked: usize,
+    /// Pages that could not be read and were replaced by empty ones.
+    pub pages_repaired: usize,
+    /// Entries whose block didn't match their CID, removed.
+    pub entries_deleted: usize,
+    /// Entries whose latest block didn't match their CID, rolled back to
+    /// an older version that did.
+    pub entries_recovered: usize,
+}
+
+/// What `Quarry::verify` found wrong with the store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub pages_checked: usize,
+    /// Pages listed in the index but absent from marble.
+    pub missing_pages: usize,
+    /// Pages present in marble that could not be deserialized.
+    pub corrupt_pages: usize,
+    /// Keys stored in a page whose `[lo, hi)` range doesn't cover them.
+    pub keys_out_of_range: usize,
+    /// Entries whose latest block doesn't match their CID.
+    pub cid_mismatches: usize,
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing_pages == 0
+            && self.corrupt_pages == 0
+            && self.keys_out_of_range == 0
+            && self.cid_mismatches == 0
+    }
+}
+
+/// What a `Quarry::compact` got back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Bytes the storage files shrank by.
+    pub reclaimed_bytes: u64,
+    pub duration_ms: u64,
+}
+
+/// The compaction thread started by `Quarry::open_with_gc_thread`.
+pub(crate) struct GcThread {
+    shutdown: Arc<AtomicBool>,
+    thread: Thread,
+}
+
+impl GcThread {
+    /// Makes the thread exit without waiting out its interval.
+    pub(crate) fn stop(&self) {
+        self.shutdown.store(true, SeqCst);
+        self.thread.unpark();
+    }
+}
+
+/// A snapshot of the size of a store, from `Quarry::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuarryStats {
+    pub live_pages: usize,
+    /// Keys held by the pages, deleted ones still kept for a snapshot
+    /// included.
+    pub total_kvs: usize,
+    /// Uncompressed bytes of the latest version of every block.
+    pub total_data_bytes: u64,
+    pub dead_marble_objects: usize,
+    pub live_marble_objects: usize,
+}
+
+impl fmt::Display for QuarryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pages:          {}", self.live_pages)?;
+        writeln!(f, "keys:           {}", self.total_kvs)?;
+        writeln!(f, "data bytes:     {}", self.total_data_bytes)?;
+        writeln!(f, "live objects:   {}", self.live_marble_objects)?;
+        write!(f, "dead objects:   {}", self.dead_marble_objects)
+    }
+}
+
+impl Quarry {
+    /// Has marble rewrite its storage files to drop dead objects. Writes no
+    /// longer trigger this on their own unless the `auto_compact` feature
+    /// is enabled, so long-running stores should call it periodically,
+    /// e.g. from a background thread.
+    pub fn compact(&self) -> Result<CompactionReport> {
+        let start = Instant::now();
+        // block writes hold the index write lock, so none can grow the files
+        // between the two measurements; only a pin update still can, by
+        // its few bytes
+        let _index = self.inner.index.read().unwrap();
+        let before = self.size_on_disk()?;
+        self.inner.heap.maintenance()?;
+        Ok(CompactionReport {
+            reclaimed_bytes: before.saturating_sub(self.size_on_disk()?),
+            duration_ms: start.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Opens the store at `path` along with a thread calling `compact`
+    /// every `interval` until the last handle to the store is dropped.
+    /// The thread only holds a weak reference in between runs, so it never
+    /// keeps the store open; a failed compaction is retried on the next
+    /// run.
+    pub fn open_with_gc_thread<P: AsRef<Path>>(path: P, interval: Duration) -> Result<Quarry> {
+        let quarry = Quarry::open(path)?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let flag = shutdown.clone();
+        let inner: Weak<_> = Arc::downgrade(&quarry.inner);
+        let handle = thread::Builder::new()
+            .name("quarry-gc".to_string())
+            .spawn(move || loop {
+                let deadline = Instant::now() + interval;
+                while !flag.load(SeqCst) && Instant::now() < deadline {
+                    thread::park_timeout(deadline.saturating_duration_since(Instant::now()));
+                }
+                if flag.load(SeqCst) {
+                    return;
+                }
+                let Some(inner) = inner.upgrade() else {
+                    return;
+                };
+                let _ = Quarry { inner }.compact();
+            })?;
+        *quarry.inner.gc_thread.lock().unwrap() = Some(GcThread {
+            shutdown,
+            thread: handle.thread().clone(),
+        });
+        Ok(quarry)
+    }
+
+    /// Walks every page to count keys and bytes, alongside marble's own
+    /// object counts.
+    pub fn stats(&self) -> Result<QuarryStats> {
+        let mut stats = QuarryStats::default();
+        for page in self.iter_pages() {
+            let page = page?;
+            stats.live_pages += 1;
+            stats.total_kvs += page.kvs.len();
+            stats.total_data_bytes += page
+                .iter()
+                .map(|(_, value)| compression::original_size(value) as u64)
+                .sum::<u64>();
+        }
+        // read under the index lock, as marble's figures can't be read
+        // consistently while a batch is being written
+        let index = self.inner.index.read().unwrap();
+        let heap = self.inner.heap.stats();
+        drop(index);
+        stats.live_marble_objects = heap.live_objects as usize;
+        stats.dead_marble_objects = heap.dead_objects as usize;
+        Ok(stats)
+    }
+
+    /// Opens the store at `path` after rebuilding its index from scratch,
+    /// for when the persisted one is lost or corrupt. Every live marble
+    /// object that deserializes as a page is registered under its `lo`
+    /// bound; read-only ranges are not recovered.
+    pub fn rebuild_index<P: AsRef<Path>>(path: P, config: QuarryConfig) -> Result<Quarry> {
+        let heap = config.heap_config(path.as_ref()).open()?;
+        let (pages, last_pid) = scan_pages(&heap);
+        let index = Index {
+            pages,
+            last_pid,
+            immutable_ranges: vec![],
+        };
+        heap.write_batch([(INDEX_OBJECT_ID, Some(format::encode(&index)?))])?;
+        heap.sync_all()?;
+        drop(heap);
+
+        Quarry::open_with_config(path, config)
+    }
+
+    /// Total size of the files backing the store.
+    pub fn size_on_disk(&self) -> Result<u64> {
+        Ok(files(&self.inner.path)?
+            .iter()
+            .map(|metadata| metadata.len())
+            .sum())
+    }
+
+    /// Most recent modification time of the files backing the store, a
+    /// proxy for the time of the last write.
+    pub fn last_modified(&self) -> Result<SystemTime> {
+        let mut latest = SystemTime::UNIX_EPOCH;
+        for metadata in files(&self.inner.path)? {
+            latest = latest.max(metadata.modified()?);
+        }
+        Ok(latest)
+    }
+
+    /// Time at which `k` was inserted. Insertion times are only known when
+    /// blocks carry a TTL, which this store doesn't track yet, so this is
+    /// always `None` for now.
+    pub fn block_insertion_time(&self, _k: &Cid) -> Result<Option<SystemTime>> {
+        Ok(None)
+    }
+
+    /// Writes a compacted copy of the store to `dest` and opens it: live
+    /// blocks are copied page by page, leaving no dead objects or
+    /// tombstones behind.
+    pub fn compact_to<P: AsRef<Path>>(&self, dest: P) -> Result<Quarry> {
+        let compacted = Quarry::open_with_config(dest, self.inner.config.clone())?;
+        for page in self.iter_pages() {
+            let batch = compacted.open_batch();
+            for (key, value) in page?.iter() {
+                batch.put_stored(key.clone(), value.clone());
+            }
+            compacted.commit_batch(batch)?;
+        }
+        compacted.inner.heap.sync_all()?;
+        Ok(compacted)
+    }
+
+    /// Lists the CIDs whose latest version is a tombstone, either a delete
+    /// kept around for a snapshot or a zero-length value. Pages are only
+    /// scanned once marble holds more dead objects than the configured
+    /// `dead_object_warning_threshold`.
+    pub fn tombstone_check(&self) -> Result<Vec<Cid>> {
+        if self.inner.heap.stats().dead_objects <= self.inner.config.dead_object_warning_threshold {
+            return Ok(vec![]);
+        }
+
+        let mut tombstones = vec![];
+        for page in self.iter_pages() {
+            for key in page?.tombstones() {
+                tombstones.push(Cid::read_bytes(&key[..])?);
+            }
+        }
+        Ok(tombstones)
+    }
+
+    /// Deletes every tombstone from the pages holding it and returns how
+    /// many were removed. Tombstones an open snapshot can still observe
+    /// are left in place.
+    pub fn purge_tombstones(&self) -> Result<usize> {
+        let mut batch: HashMap<ObjectId, Option<Vec<u8>>> = HashMap::new();
+        let mut purged = 0;
+        // held for writing to keep concurrent updates off the pages
+        #[allow(clippy::readonly_write_lock)]
+        let index = self.inner.index.write().unwrap();
+        for object_id in index.pages.values() {
+            let mut page = self.read_page(*object_id)?;
+            let keys: Vec<Vec<u8>> = page.tombstones().cloned().collect();
+            if keys.is_empty() {
+                continue;
+            }
+            for key in keys {
+                let seq = self.inner.seq.fetch_add(1, SeqCst) + 1;
+                page.insert(key.clone(), seq, None, self.oldest_snapshot());
+                if !page.kvs.contains_key(&key) {
+                    purged += 1;
+                }
+            }
+            batch.insert(*object_id, Some(format::encode(&page)?));
+        }
+        self.write_heap(batch)?;
+        Ok(purged)
+    }
+
+    /// Checks every page and every latest block against its CID, then
+    /// repairs what it can: unreadable pages are replaced by empty ones
+    /// covering the same keys, and entries whose block doesn't hash to
+    /// their CID are rolled back to the newest older version that does, or
+    /// deleted. Blocks hashed with a function quarry doesn't know are
+    /// assumed intact.
+    pub fn check_and_repair(&self) -> Result<RepairSummary> {
+        let mut summary = RepairSummary::default();
+        let mut batch: HashMap<ObjectId, Option<Vec<u8>>> = HashMap::new();
+        // held for writing to keep concurrent updates off the pages
+        #[allow(clippy::readonly_write_lock)]
+        let index = self.inner.index.write().unwrap();
+        let mut bounds = index.pages.keys().skip(1);
+        for (lo, object_id) in &index.pages {
+            let hi = bounds.next().cloned();
+            summary.pages_checked += 1;
+
+            let page = match self.inner.heap.read(*object_id)? {
+                Some(data) => format::decode::<Page>(&data).ok(),
+                None => None,
+            };
+            let mut page = match page {
+                Some(page) => page,
+                None => {
+                    summary.pages_repaired += 1;
+                    let page = Page {
+                        hi,
+                        lo: lo.clone(),
+                        kvs: Default::default(),
+                    };
+                    batch.insert(*object_id, Some(format::encode(&page)?));
+                    continue;
+                }
+            };
+
+            let mut modified = false;
+            page.kvs.retain(|key, versions| {
+                let intact = |version: &(u64, Option<Vec<u8>>)| match &version.1 {
+                    Some(stored) => block_matches(key, stored),
+                    None => true,
+                };
+                if versions.last().is_none_or(intact) {
+                    return true;
+                }
+                modified = true;
+                match versions.iter().rposition(|v| v.1.is_some() && intact(v)) {
+                    Some(valid) => {
+                        versions.truncate(valid + 1);
+                        summary.entries_recovered += 1;
+                        true
+                    }
+                    None => {
+                        summary.entries_deleted += 1;
+                        false
+                    }
+                }
+            });
+            if modified {
+                batch.insert(*object_id, Some(format::encode(&page)?));
+            }
+        }
+
+        self.write_heap(batch)?;
+        drop(index);
+        self.inner.heap.maintenance()?;
+        self.recount()?;
+        Ok(summary)
+    }
+
+    /// Checks every page listed in the index without modifying anything:
+    /// that it can be read, that its keys fall within its bounds and that
+    /// the latest block of each entry matches its CID.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let index = self.inner.index.read().unwrap();
+        let mut bounds = index.pages.keys().skip(1);
+        for (lo, object_id) in &index.pages {
+            let hi = bounds.next();
+            report.pages_checked += 1;
+
+            let page = match self.inner.heap.read(*object_id)? {
+                Some(data) => match format::decode::<Page>(&data) {
+                    Ok(page) => page,
+                    Err(_) => {
+                        report.corrupt_pages += 1;
+                        continue;
+                    }
+                },
+                None => {
+                    report.missing_pages += 1;
+                    continue;
+                }
+            };
+
+            for (key, stored) in page.iter() {
+                if key < lo || hi.is_some_and(|hi| key >= hi) {
+                    report.keys_out_of_range += 1;
+                }
+                if !block_matches(key, stored) {
+                    report.cid_mismatches += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs every maintenance step in turn: the index is rebuilt from the
+    /// pages marble holds, dropping entries to missing pages, tombstones
+    /// are purged, empty pages are folded into their predecessor, pages
+    /// are rewritten densely in key order and marble is left to reclaim
+    /// the space of dead objects.
+    pub fn vacuum(&self) -> Result<VacuumStats> {
+        let start = Instant::now();
+        let pages_before = self.inner.index.read().unwrap().pages.len();
+        let bytes_before = self.size_on_disk()?;
+
+        // first, as every later step reads each indexed page
+        self.reindex()?;
+        self.purge_tombstones()?;
+        self.drop_empty_pages()?;
+        self.reorder_pages()?;
+        self.compact()?;
+
+        Ok(VacuumStats {
+            pages_before,
+            pages_after: self.inner.index.read().unwrap().pages.len(),
+            bytes_before,
+            bytes_after: self.size_on_disk()?,
+            duration: start.elapsed(),
+        })
+    }
+
+    /// Rebuilds the index from the pages marble holds, like
+    /// `rebuild_index` but on an open store and keeping the read-only
+    /// ranges.
+    fn reindex(&self) -> Result<()> {
+        let mut index = self.inner.index.write().unwrap();
+        let (pages, last_pid) = scan_pages(&self.inner.heap);
+        if pages.is_empty() || pages == index.pages {
+            return Ok(());
+        }
+        index.pages = pages;
+        index.last_pid = index.last_pid.max(last_pid);
+        let batch = HashMap::from([(INDEX_OBJECT_ID, Some(format::encode(&*index)?))]);
+        self.write_heap(batch)
+    }
+
+    /// Removes pages without entries from the index, extending the key
+    /// range of the preceding page to cover them. The first page always
+    /// stays so that every key maps to a page.
+    fn drop_empty_pages(&self) -> Result<usize> {
+        let mut batch: HashMap<ObjectId, Option<Vec<u8>>> = HashMap::new();
+        let mut dropped = vec![];
+        // the page absorbing empty successors, and whether it needs a rewrite
+        let mut previous: Option<(ObjectId, Page, bool)> = None;
+
+        let mut index = self.inner.index.write().unwrap();
+        for (lo, object_id) in &index.pages {
+            let page = read_page(&self.inner.heap, *object_id)?;
+            if let (true, Some((_, absorbing, dirty))) = (page.kvs.is_empty(), previous.as_mut()) {
+                absorbing.hi = page.hi;
+                *dirty = true;
+                batch.insert(*object_id, None);
+                dropped.push(lo.clone());
+                continue;
+            }
+            if let Some((id, absorbing, true)) = previous.replace((*object_id, page, false)) {
+                batch.insert(id, Some(format::encode(&absorbing)?));
+            }
+        }
+        if let Some((id, absorbing, true)) = previous {
+            batch.insert(id, Some(format::encode(&absorbing)?));
+        }
+
+        if dropped.is_empty() {
+            return Ok(0);
+        }
+        for lo in &dropped {
+            index.pages.remove(lo);
+        }
+        batch.insert(INDEX_OBJECT_ID, Some(format::encode(&*index)?));
+        self.write_heap(batch)?;
+
+        Ok(dropped.len())
+    }
+}
+
+/// Metadata of every file under `dir`, marble keeping its data files in a
+/// subdirectory.
+fn files(dir: &Path) -> Result<Vec<fs::Metadata>> {
+    let mut found = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            found.extend(files(&entry.path())?);
+        } else if metadata.is_file() {
+            found.push(metadata);
+        }
+    }
+    Ok(found)
+}
+
+/// Every page among the live objects of `heap` keyed by its `lo` bound,
+/// and the highest object id holding one. Of several pages claiming the
+/// same bound, the most recently written one is kept.
+fn scan_pages(heap: &Marble) -> (BTreeMap<Vec<u8>, ObjectId>, u64) {
+    let mut pages: BTreeMap<Vec<u8>, (ObjectId, u64)> = BTreeMap::new();
+    let mut last_pid = 0;
+    for object_id in live_object_ids(heap) {
+        if object_id == INDEX_OBJECT_ID || object_id == PINS_OBJECT_ID {
+            continue;
+        }
+        let page = match read_page(heap, object_id) {
+            Ok(page) => page,
+            Err(_) => continue,
+        };
+        last_pid = last_pid.max(object_id);
+        let seq = page.max_seq();
+        if pages.get(&page.lo).is_none_or(|(_, newest)| seq > *newest) {
+            pages.insert(page.lo, (object_id, seq));
+        }
+    }
+    let pages = pages
+        .into_iter()
+        .map(|(lo, (object_id, _))| (lo, object_id))
+        .collect();
+    (pages, last_pid)
+}
+
+/// Object ids marble currently holds a value for.
+fn live_object_ids(heap: &Marble) -> Vec<ObjectId> {
+    let (max, free) = heap.free_object_ids();
+    let mut live = vec![true; max as usize];
+    for object_id in free {
+        live[object_id as usize] = false;
+    }
+    (0..max)
+        .filter(|object_id| live[*object_id as usize])
+        .collect()
+}
+
+/// Whether `stored` decodes to a block hashing to the CID in `key`.
+fn block_matches(key: &[u8], stored: &[u8]) -> bool {
+    let (cid, block) = match (Cid::read_bytes(key), compression::decode(stored)) {
+        (Ok(cid), Ok(block)) => (cid, block),
+        _ => return false,
+    };
+    digest_matches(&cid, &block)
+}
+
+/// Whether `block` hashes to the digest in `cid`. Blocks hashed with a
+/// function this build does not know are assumed to match.
+pub(crate) fn digest_matches(cid: &Cid, block: &[u8]) -> bool {
+    match Code::try_from(cid.hash().code()) {
+        Ok(code) => code.digest(block) == *cid.hash(),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,118 @@
+//! A blockstore keeping every block in memory, for tests and small
+//! workloads that don't need persistence.
+
+use crate::Blockstore;
+use anyhow::Result;
+use bytes::Bytes;
+use cid::Cid;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Debug, Default)]
+pub struct MemoryBlockstore {
+    blocks: RwLock<HashMap<Cid, Bytes>>,
+}
+
+impl MemoryBlockstore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The CIDs of every stored block, in no particular order.
+    pub fn cids(&self) -> Vec<Cid> {
+        self.blocks.read().unwrap().keys().copied().collect()
+    }
+}
+
+impl Blockstore for MemoryBlockstore {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        self.blocks.write().unwrap().remove(k);
+        Ok(())
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .blocks
+            .read()
+            .unwrap()
+            .get(k)
+            .map(|block| block.to_vec()))
+    }
+    /// Shares the stored buffer rather than copying it.
+    fn get_bytes(&self, k: &Cid) -> Result<Option<Bytes>> {
+        Ok(self.blocks.read().unwrap().get(k).cloned())
+    }
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.blocks
+            .write()
+            .unwrap()
+            .insert(*k, Bytes::copy_from_slice(block));
+        Ok(())
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        Ok(self.blocks.read().unwrap().contains_key(k))
+    }
+
+    /// Inserts every block under a single acquisition of the lock.
+    fn put_many_keyed<D, I>(&self, blocks: I) -> Result<()>
+    where
+        Self: Sized,
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = (Cid, D)>,
+    {
+        let mut stored = self.blocks.write().unwrap();
+        for (c, b) in blocks {
+            stored.insert(c, Bytes::copy_from_slice(b.as_ref()));
+        }
+        Ok(())
+    }
+}
+
+impl wiresaw::Getter for MemoryBlockstore {
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        Blockstore::get(self, k)
+    }
+}
+
+impl wiresaw::Storer for MemoryBlockstore {
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        Blockstore::put_keyed(self, k, block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::raw_blocks;
+
+    #[test]
+    fn memory_blockstore() {
+        let store = MemoryBlockstore::new();
+        assert!(store.is_empty());
+        let blocks = raw_blocks(10, 10);
+        store.put_many_keyed(blocks.clone()).unwrap();
+        assert_eq!(store.len(), 10);
+        for (cid, block) in &blocks {
+            assert!(store.has(cid).unwrap());
+            assert_eq!(Blockstore::get(&store, cid).unwrap().as_ref(), Some(block));
+        }
+        let (cid, block) = &blocks[1];
+        let shared = store.get_bytes(cid).unwrap().unwrap();
+        assert_eq!(&shared[..], &block[..]);
+        assert_eq!(
+            shared.as_ptr(),
+            store.get_bytes(cid).unwrap().unwrap().as_ptr()
+        );
+
+        store.delete_block(&blocks[0].0).unwrap();
+        assert!(!store.has(&blocks[0].0).unwrap());
+        assert_eq!(store.len(), 9);
+    }
+}
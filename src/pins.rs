@@ -0,0 +1,222 @@
+//! Pinned roots, and garbage collection of every block they don't reach.
+
+use crate::{format, storage_cid, storage_key, Blockstore, Quarry, PINS_OBJECT_ID};
+use anyhow::Result;
+use cid::Cid;
+use marble::Marble;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeSet, HashSet};
+
+/// The pinned roots persisted in `heap`, if any were ever pinned.
+pub(crate) fn read_pins(heap: &Marble) -> Result<BTreeSet<Cid>> {
+    match heap.read(PINS_OBJECT_ID)? {
+        Some(data) => {
+            let pins: Vec<ByteBuf> = format::decode(&data)?;
+            pins.iter()
+                .map(|cid| Ok(storage_cid(&Cid::read_bytes(&cid[..])?)))
+                .collect()
+        }
+        None => Ok(BTreeSet::new()),
+    }
+}
+
+impl Quarry {
+    /// Keeps `root` and every block reachable from it through `gc`. Like
+    /// blocks, a CIDv0 root is pinned as the equivalent CIDv1.
+    pub fn pin(&self, root: &Cid) -> Result<()> {
+        self.update_pins(|pins| pins.insert(storage_cid(root)))
+    }
+
+    /// Stops keeping `root` through `gc`, though blocks reachable from
+    /// another pin are still kept.
+    pub fn unpin(&self, root: &Cid) -> Result<()> {
+        self.update_pins(|pins| pins.remove(&storage_cid(root)))
+    }
+
+    /// The pinned roots, in CID byte order.
+    pub fn pins(&self) -> Vec<Cid> {
+        self.inner.pins.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Applies `f` to the pins, persisting them if it reports a change.
+    fn update_pins(&self, f: impl FnOnce(&mut BTreeSet<Cid>) -> bool) -> Result<()> {
+        let mut pins = self.inner.pins.lock().unwrap();
+        if f(&mut pins) {
+            let data: Vec<ByteBuf> = pins
+                .iter()
+                .map(|cid| ByteBuf::from(cid.to_bytes()))
+                .collect();
+            self.inner
+                .heap
+                .write_batch([(PINS_OBJECT_ID, Some(format::encode(&data)?))])?;
+        }
+        Ok(())
+    }
+
+    /// Deletes every block not reachable from a pinned root, following the
+    /// links of dag-cbor, dag-json and dag-pb nodes, and returns how many
+    /// were deleted. Blocks missing from a pinned DAG are skipped over, and
+    /// blocks within a range marked with `mark_readonly_range` are kept.
+    /// Blocks written while the collection runs may be deleted, so writers
+    /// should be paused.
+    pub fn gc(&self) -> Result<usize> {
+        let mut reachable = HashSet::new();
+        let mut stack = self.pins();
+        while let Some(cid) = stack.pop() {
+            // list_cids yields CIDv0 blocks under their CIDv1
+            let cid = storage_cid(&cid);
+            if !reachable.insert(cid) {
+                continue;
+            }
+            if let Some(block) = self.get(&cid)? {
+                stack.extend(wiresaw::links_of(&cid, &block)?);
+            }
+        }
+
+        let mut unreachable = vec![];
+        for cid in self.list_cids()? {
+            let cid = cid?;
+            if !reachable.contains(&cid) {
+                unreachable.push(cid);
+            }
+        }
+        let batch = self.open_batch();
+        let index = self.inner.index.read().unwrap();
+        for cid in unreachable {
+            if !index.is_immutable(&storage_key(&cid)) {
+                batch.delete_block(&cid);
+            }
+        }
+        drop(index);
+        let deleted = batch.len();
+        self.commit_batch(batch)?;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{assert_dag_complete, with_dir, with_instance};
+    use cid::multihash::{Code, MultihashDigest};
+    use rand::prelude::*;
+    use wiresaw::codec::unixfs;
+    use wiresaw::{ChunkReader, DagBuilder, DagCodec, DAG_JSON};
+
+    #[test]
+    fn gc_unpinned_blocks() {
+        with_dir(|path| {
+            let quarry = Quarry::open(path).unwrap();
+
+            let build = |seed: u64| {
+                let mut bytes = vec![0u8; 20 << 10];
+                StdRng::seed_from_u64(seed).fill(&mut bytes[..]);
+                let mut reader = ChunkReader::with_chunk_size(1 << 10, &bytes[..]);
+                reader.set_content_size(bytes.len() as u64);
+                DagBuilder::new(reader, &quarry).balanced().unwrap().root
+            };
+            let kept = build(1);
+            let dropped = build(2);
+            let stray = Cid::new_v1(0x55, Code::Sha2_256.digest(b"stray"));
+            quarry.put_keyed(&stray, b"stray").unwrap();
+
+            quarry.pin(&kept).unwrap();
+            quarry.pin(&dropped).unwrap();
+            quarry.unpin(&dropped).unwrap();
+            assert_eq!(quarry.pins(), vec![kept]);
+
+            // 20 leaves and 3 nodes per DAG
+            assert_eq!(quarry.gc().unwrap(), 24);
+            assert!(quarry.has(&kept).unwrap());
+            assert!(!quarry.has(&dropped).unwrap());
+            assert!(!quarry.has(&stray).unwrap());
+            assert_eq!(quarry.list_cids().unwrap().count(), 23);
+            assert_eq!(quarry.gc().unwrap(), 0);
+
+            drop(quarry);
+            let quarry = Quarry::open(path).unwrap();
+            assert_eq!(quarry.pins(), vec![kept]);
+            quarry.unpin(&kept).unwrap();
+            assert_eq!(quarry.gc().unwrap(), 23);
+        });
+    }
+
+    #[test]
+    fn gc_keeps_cidv0_pins() {
+        with_instance(|quarry| {
+            let block = unixfs::encode_leaf(b"a dag-pb root");
+            let v0 = Cid::new_v0(Code::Sha2_256.digest(&block)).unwrap();
+            quarry.put_keyed(&v0, &block).unwrap();
+            quarry.pin(&v0).unwrap();
+            assert_eq!(quarry.pins(), vec![Cid::new_v1(0x70, *v0.hash())]);
+
+            assert_eq!(quarry.gc().unwrap(), 0);
+            assert!(quarry.has(&v0).unwrap());
+            quarry.unpin(&v0).unwrap();
+            assert_eq!(quarry.gc().unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn gc_follows_dag_json_links() {
+        with_instance(|quarry| {
+            let mut bytes = vec![0u8; 20 << 10];
+            StdRng::seed_from_u64(3).fill(&mut bytes[..]);
+            let mut reader = ChunkReader::with_chunk_size(1 << 10, &bytes[..]);
+            reader.set_content_size(bytes.len() as u64);
+            let root = DagBuilder::new(reader, &quarry)
+                .with_codec(DagCodec::DagJson)
+                .balanced()
+                .unwrap()
+                .root;
+            assert_eq!(root.codec(), DAG_JSON);
+            let stray = Cid::new_v1(0x55, Code::Sha2_256.digest(b"stray"));
+            quarry.put_keyed(&stray, b"stray").unwrap();
+
+            quarry.pin(&root).unwrap();
+            assert_eq!(quarry.gc().unwrap(), 1);
+            // 20 leaves and 3 nodes
+            assert_eq!(quarry.list_cids().unwrap().count(), 23);
+            assert_dag_complete(&root, &quarry);
+        });
+    }
+
+    #[test]
+    fn gc_follows_dag_pb_links() {
+        with_instance(|quarry| {
+            let bytes: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+            let reader = ChunkReader::with_chunk_size(1 << 10, &bytes[..]);
+            let root = DagBuilder::new(reader, &quarry).unixfs_file().unwrap().root;
+            assert_eq!(root.codec(), unixfs::DAG_PB);
+            let stray = Cid::new_v1(0x55, Code::Sha2_256.digest(b"stray"));
+            quarry.put_keyed(&stray, b"stray").unwrap();
+
+            quarry.pin(&root).unwrap();
+            assert_eq!(quarry.gc().unwrap(), 1);
+            // 20 leaves and the root
+            assert_eq!(quarry.list_cids().unwrap().count(), 21);
+            assert_dag_complete(&root, &quarry);
+        });
+    }
+
+    #[test]
+    fn gc_keeps_readonly_ranges() {
+        with_instance(|quarry| {
+            let mut cids: Vec<Cid> = (0..10u8)
+                .map(|i| Cid::new_v1(0x55, Code::Sha2_256.digest(&[i])))
+                .collect();
+            cids.sort_by_key(|cid| cid.to_bytes());
+            for cid in &cids {
+                quarry.put_keyed(cid, b"unpinned").unwrap();
+            }
+            assert_eq!(quarry.mark_readonly_range(&cids[2], &cids[6]).unwrap(), 4);
+
+            assert_eq!(quarry.gc().unwrap(), 6);
+            let kept: Vec<Cid> = quarry.list_cids().unwrap().map(Result::unwrap).collect();
+            assert_eq!(kept, cids[2..6]);
+
+            quarry.unmark_readonly_range(&cids[2], &cids[6]).unwrap();
+            assert_eq!(quarry.gc().unwrap(), 4);
+        });
+    }
+}
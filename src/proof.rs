@@ -0,0 +1,134 @@
+//! Self-contained inclusion proofs: the encoded nodes on the way from a root
+//! to a block, so a light client holding only the root CID can check that
+//! the block belongs to the DAG.
+//!
+//! `wiresaw::proof::MerkleProof` records the same kind of path by CID only,
+//! leaf first, and is checked against a store holding the nodes. An
+//! `InclusionProof` carries the nodes themselves, root first, so it can be
+//! sent to a verifier without access to any store, and it follows links
+//! of any codec `wiresaw::links_of` decodes rather than dag-cbor only.
+
+use crate::Blockstore;
+use anyhow::{anyhow, Result};
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// One node on the path: its encoding and the position, within the links
+/// `wiresaw::links_of` decodes from it, of the link leading down to the
+/// next step.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub node: Vec<u8>,
+    pub position: u32,
+}
+
+/// The nodes linking a root down to a target block, root first. Empty when
+/// the target is the root itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub steps: Vec<ProofStep>,
+}
+
+/// Finds a path from `root` down to `target` through the nodes held in
+/// `store` and returns the proof made of the nodes along it.
+pub fn generate_proof(root: &Cid, target: &Cid, store: &dyn Blockstore) -> Result<InclusionProof> {
+    let mut steps = vec![];
+    if !find_path(root, target, store, &mut HashSet::new(), &mut steps)? {
+        return Err(anyhow!("{} is not reachable from {}", target, root));
+    }
+    steps.reverse();
+    Ok(InclusionProof { steps })
+}
+
+/// Pushes the steps from `cid` down to `target`, deepest first, returning
+/// whether `target` was found.
+fn find_path(
+    cid: &Cid,
+    target: &Cid,
+    store: &dyn Blockstore,
+    seen: &mut HashSet<Cid>,
+    steps: &mut Vec<ProofStep>,
+) -> Result<bool> {
+    if cid == target {
+        return Ok(true);
+    }
+    let block = store
+        .get(cid)?
+        .ok_or_else(|| anyhow!("block {} is missing from the store", cid))?;
+    for (position, link) in wiresaw::links_of(cid, &block)?.iter().enumerate() {
+        if seen.insert(*link) && find_path(link, target, store, seen, steps)? {
+            steps.push(ProofStep {
+                node: block,
+                position: position as u32,
+            });
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Checks that each step hashes to the CID the step above links to, the
+/// first one to `root`, and that the last link leads to `target`.
+pub fn verify_proof(root: &Cid, target: &Cid, proof: &InclusionProof) -> bool {
+    let mut expected = *root;
+    for step in &proof.steps {
+        let code = match Code::try_from(expected.hash().code()) {
+            Ok(code) => code,
+            Err(_) => return false,
+        };
+        if code.digest(&step.node) != *expected.hash() {
+            return false;
+        }
+        let links = match wiresaw::links_of(&expected, &step.node) {
+            Ok(links) => links,
+            Err(_) => return false,
+        };
+        match links.get(step.position as usize) {
+            Some(link) => expected = *link,
+            None => return false,
+        }
+    }
+    expected == *target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemoryBlockstore;
+    use rand::prelude::*;
+    use wiresaw::{ChunkReader, DagBuilder};
+
+    #[test]
+    fn inclusion_proofs() {
+        let mut bytes = vec![0u8; 300 << 10];
+        thread_rng().fill(&mut bytes[..]);
+        let mut reader = ChunkReader::with_chunk_size(1 << 10, &bytes[..]);
+        reader.set_content_size(bytes.len() as u64);
+        let store = MemoryBlockstore::new();
+        let info = DagBuilder::new(reader, &store).balanced().unwrap();
+
+        let leaf = Cid::new_v1(0x55, Code::Sha2_256.digest(&bytes[150 << 10..151 << 10]));
+        let proof = generate_proof(&info.root, &leaf, &store).unwrap();
+        assert_eq!(proof.steps.len(), 3);
+        assert!(verify_proof(&info.root, &leaf, &proof));
+        assert!(generate_proof(&info.root, &info.root, &store)
+            .unwrap()
+            .steps
+            .is_empty());
+
+        let decoded: InclusionProof =
+            bincode::deserialize(&bincode::serialize(&proof).unwrap()).unwrap();
+        assert_eq!(decoded, proof);
+
+        let other = Cid::new_v1(0x55, Code::Sha2_256.digest(&bytes[..1 << 10]));
+        assert!(!verify_proof(&info.root, &other, &proof));
+        let mut tampered = proof.clone();
+        tampered.steps[1].node.push(0);
+        assert!(!verify_proof(&info.root, &leaf, &tampered));
+
+        let absent = Cid::new_v1(0x55, Code::Sha2_256.digest(b"absent"));
+        assert!(generate_proof(&info.root, &absent, &store).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! CARv1 archives exported to and imported from S3, behind the `s3`
+//! feature.
+//!
+//! Requests go through an `aws_sdk_s3::Client`, so credentials, region,
+//! endpoint and TLS are whatever the caller configured it with, usually
+//! through `aws_config::load_from_env`.
+
+use crate::car::{import_car, CarEncoder};
+use crate::Quarry;
+use anyhow::{anyhow, Result};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use cid::Cid;
+
+/// Archives up to this size are uploaded with a single `PutObject`, larger
+/// ones in parts of this size through a multipart upload.
+pub const MULTIPART_THRESHOLD: usize = 50 << 20;
+
+fn s3_error<E: std::error::Error>(op: &str, key: &str, err: E) -> anyhow::Error {
+    anyhow!("{} {}: {}", op, key, DisplayErrorContext(err))
+}
+
+fn etag(etag: Option<&str>, key: &str) -> Result<String> {
+    etag.map(str::to_string)
+        .ok_or_else(|| anyhow!("S3 returned no ETag for {}", key))
+}
+
+impl Quarry {
+    /// Uploads a CARv1 archive of every block reachable from `roots` to
+    /// `key` in `bucket` and returns the object's ETag, quotes included.
+    /// Archives larger than `MULTIPART_THRESHOLD` go up through a multipart
+    /// upload as they are encoded, so at most one part is held in memory;
+    /// the upload is aborted if any part fails.
+    pub async fn export_car_to_s3(
+        &self,
+        roots: &[Cid],
+        bucket: &str,
+        key: &str,
+        client: &Client,
+    ) -> Result<String> {
+        self.export_car_in_parts(roots, bucket, key, client, MULTIPART_THRESHOLD)
+            .await
+    }
+
+    async fn export_car_in_parts(
+        &self,
+        roots: &[Cid],
+        bucket: &str,
+        key: &str,
+        client: &Client,
+        part_size: usize,
+    ) -> Result<String> {
+        let mut frames = CarEncoder::new(roots, self);
+        let mut part = vec![];
+        if !fill_part(&mut frames, &mut part, part_size)? {
+            let output = client
+                .put_object()
+                .bucket(bucket)
+                .key(key)
+                .body(ByteStream::from(part))
+                .send()
+                .await
+                .map_err(|err| s3_error("PutObject", key, err))?;
+            return etag(output.e_tag(), key);
+        }
+
+        let upload = client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|err| s3_error("CreateMultipartUpload", key, err))?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| anyhow!("S3 returned no upload id for {}", key))?;
+        let result = upload_parts(frames, part, part_size, bucket, key, upload_id, client).await;
+        if result.is_err() {
+            let _ = client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+        }
+        result
+    }
+
+    /// Downloads the CARv1 archive under `key` in `bucket`, stores its
+    /// blocks and returns the roots listed in its header, checking blocks
+    /// like `car::import_car`.
+    pub async fn import_from_s3(
+        &self,
+        bucket: &str,
+        key: &str,
+        client: &Client,
+    ) -> Result<Vec<Cid>> {
+        let object = client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|err| s3_error("GetObject", key, err))?;
+        let car = object.body.collect().await?.into_bytes();
+        import_car(self, &car[..])
+    }
+}
+
+/// Appends frames to `part` until it holds more than `part_size` bytes.
+/// Returns false if the frames ran out first.
+fn fill_part(
+    frames: &mut impl Iterator<Item = Result<Vec<u8>>>,
+    part: &mut Vec<u8>,
+    part_size: usize,
+) -> Result<bool> {
+    while part.len() <= part_size {
+        match frames.next() {
+            Some(frame) => part.extend_from_slice(&frame?),
+            None => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+/// Uploads `part` followed by the rest of `frames` in parts of `part_size`
+/// bytes, numbered from 1, and completes the upload.
+async fn upload_parts(
+    mut frames: impl Iterator<Item = Result<Vec<u8>>>,
+    mut part: Vec<u8>,
+    part_size: usize,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    client: &Client,
+) -> Result<String> {
+    let mut completed = vec![];
+    loop {
+        let full = fill_part(&mut frames, &mut part, part_size)?;
+        let rest = match full {
+            true => part.split_off(part_size),
+            false => vec![],
+        };
+        if part.is_empty() {
+            break;
+        }
+        let part_number = completed.len() as i32 + 1;
+        let output = client
+            .upload_part()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(part))
+            .send()
+            .await
+            .map_err(|err| s3_error("UploadPart", key, err))?;
+        completed.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .e_tag(etag(output.e_tag(), key)?)
+                .build(),
+        );
+        if !full {
+            break;
+        }
+        part = rest;
+    }
+    let output = client
+        .complete_multipart_upload()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(completed))
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(|err| s3_error("CompleteMultipartUpload", key, err))?;
+    etag(output.e_tag(), key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::with_instance;
+    use crate::walk::DagWalker;
+    use crate::Blockstore;
+    use aws_sdk_s3::operation::abort_multipart_upload::AbortMultipartUploadOutput;
+    use aws_sdk_s3::operation::complete_multipart_upload::CompleteMultipartUploadOutput;
+    use aws_sdk_s3::operation::create_multipart_upload::CreateMultipartUploadOutput;
+    use aws_sdk_s3::operation::get_object::GetObjectOutput;
+    use aws_sdk_s3::operation::put_object::PutObjectOutput;
+    use aws_sdk_s3::operation::upload_part::{UploadPartError, UploadPartOutput};
+    use aws_smithy_mocks::{mock, mock_client, RuleMode};
+    use std::collections::BTreeMap;
+    use std::future::Future;
+    use std::sync::{Arc, Mutex};
+    use wiresaw::{ChunkReader, DagBuilder};
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn build_dag(quarry: &Quarry, data: &[u8]) -> Cid {
+        let mut chunks = ChunkReader::with_chunk_size(256, data);
+        chunks.set_content_size(data.len() as u64);
+        DagBuilder::new(chunks, quarry).trickle().unwrap().root
+    }
+
+    /// Rules keeping what a single `PutObject` or the parts of a multipart
+    /// upload stored and serving it back from `GetObject`.
+    fn object_rules(object: &Arc<Mutex<BTreeMap<i32, Vec<u8>>>>) -> Vec<aws_smithy_mocks::Rule> {
+        let (put, part, get) = (object.clone(), object.clone(), object.clone());
+        vec![
+            mock!(Client::put_object).then_compute_output(move |req| {
+                let body = req.body().bytes().unwrap().to_vec();
+                put.lock().unwrap().insert(1, body);
+                PutObjectOutput::builder().e_tag("\"single\"").build()
+            }),
+            mock!(Client::create_multipart_upload).then_output(|| {
+                CreateMultipartUploadOutput::builder()
+                    .upload_id("upload-1")
+                    .build()
+            }),
+            mock!(Client::upload_part).then_compute_output(move |req| {
+                assert_eq!(req.upload_id(), Some("upload-1"));
+                let part_number = req.part_number().unwrap();
+                let body = req.body().bytes().unwrap().to_vec();
+                part.lock().unwrap().insert(part_number, body);
+                UploadPartOutput::builder()
+                    .e_tag(format!("\"part-{}\"", part_number))
+                    .build()
+            }),
+            mock!(Client::complete_multipart_upload).then_compute_output(|req| {
+                let parts = req.multipart_upload().unwrap().parts();
+                for (i, part) in parts.iter().enumerate() {
+                    assert_eq!(part.part_number(), Some(i as i32 + 1));
+                    assert_eq!(part.e_tag(), Some(format!("\"part-{}\"", i + 1).as_str()));
+                }
+                CompleteMultipartUploadOutput::builder()
+                    .e_tag("\"multipart\"")
+                    .build()
+            }),
+            mock!(Client::get_object).then_compute_output(move |req| {
+                assert_eq!(req.key(), Some("archive.car"));
+                let body: Vec<u8> = get.lock().unwrap().values().flatten().copied().collect();
+                GetObjectOutput::builder()
+                    .body(ByteStream::from(body))
+                    .build()
+            }),
+        ]
+    }
+
+    #[test]
+    fn export_and_import_through_s3() {
+        let data: Vec<u8> = (0..16u32 << 10).map(|i| (i % 251) as u8).collect();
+        with_instance(|source| {
+            let root = build_dag(&source, &data);
+            for (part_size, expected_etag) in [
+                (MULTIPART_THRESHOLD, "\"single\""),
+                (4 << 10, "\"multipart\""),
+            ] {
+                let object = Arc::new(Mutex::new(BTreeMap::new()));
+                let rules = object_rules(&object);
+                let client = mock_client!(aws_sdk_s3, RuleMode::MatchAny, &rules);
+
+                let etag = block_on(source.export_car_in_parts(
+                    &[root],
+                    "bucket",
+                    "archive.car",
+                    &client,
+                    part_size,
+                ))
+                .unwrap();
+                assert_eq!(etag, expected_etag);
+                let parts: Vec<usize> = object.lock().unwrap().values().map(Vec::len).collect();
+                assert_eq!(parts.len() > 1, part_size < MULTIPART_THRESHOLD);
+                assert!(parts[..parts.len() - 1].iter().all(|len| *len == part_size));
+
+                with_instance(|dest| {
+                    let roots =
+                        block_on(dest.import_from_s3("bucket", "archive.car", &client)).unwrap();
+                    assert_eq!(roots, vec![root]);
+                    for entry in DagWalker::new(&source, &[root]) {
+                        let (cid, block) = entry.unwrap();
+                        assert_eq!(dest.get(&cid).unwrap(), Some(block));
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn failed_multipart_upload_is_aborted() {
+        let data: Vec<u8> = (0..16u32 << 10).map(|i| (i % 251) as u8).collect();
+        with_instance(|quarry| {
+            let root = build_dag(&quarry, &data);
+            let create = mock!(Client::create_multipart_upload).then_output(|| {
+                CreateMultipartUploadOutput::builder()
+                    .upload_id("upload-1")
+                    .build()
+            });
+            let upload = mock!(Client::upload_part)
+                .sequence()
+                .output(|| UploadPartOutput::builder().e_tag("\"part-1\"").build())
+                .error(|| UploadPartError::unhandled("no space left"))
+                .build();
+            let abort = mock!(Client::abort_multipart_upload)
+                .match_requests(|req| req.upload_id() == Some("upload-1"))
+                .then_output(|| AbortMultipartUploadOutput::builder().build());
+            let client = mock_client!(aws_sdk_s3, RuleMode::MatchAny, [&create, &upload, &abort]);
+
+            let result = block_on(quarry.export_car_in_parts(
+                &[root],
+                "bucket",
+                "archive.car",
+                &client,
+                4 << 10,
+            ));
+            assert!(result.is_err());
+            assert_eq!(upload.num_calls(), 2);
+            assert_eq!(abort.num_calls(), 1);
+        });
+    }
+}
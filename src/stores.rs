@@ -0,0 +1,636 @@
+//! Blockstores composed out of other blockstores.
+
+use crate::{compression, storage_cid, Blockstore, Buffered, Quarry};
+use anyhow::{anyhow, Result};
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_PROMOTION_THRESHOLD: u32 = 3;
+
+const DEFAULT_PROMOTION_WINDOW: Duration = Duration::from_secs(60);
+
+const DEFAULT_HOT_CAPACITY: usize = 1024;
+
+/// Reads from `hot` first and falls back to `cold`, writing to `hot` only.
+/// Paired with a memory store over a disk store, it makes a write-back
+/// cache.
+pub struct DelegatingBlockstore<A, B> {
+    hot: A,
+    cold: B,
+    promote: bool,
+}
+
+impl<A: Blockstore, B: Blockstore> DelegatingBlockstore<A, B> {
+    pub fn new(hot: A, cold: B) -> DelegatingBlockstore<A, B> {
+        DelegatingBlockstore {
+            hot,
+            cold,
+            promote: false,
+        }
+    }
+
+    /// When enabled, blocks only found in the cold store are copied into
+    /// the hot one as they are read.
+    pub fn promote_on_read(mut self, promote: bool) -> Self {
+        self.promote = promote;
+        self
+    }
+
+    pub fn hot(&self) -> &A {
+        &self.hot
+    }
+
+    pub fn cold(&self) -> &B {
+        &self.cold
+    }
+}
+
+impl<A: Blockstore, B: Blockstore> Blockstore for DelegatingBlockstore<A, B> {
+    /// Deletes the block from both stores so the cold copy can't resurface.
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        self.hot.delete_block(k)?;
+        self.cold.delete_block(k)
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        if let Some(block) = self.hot.get(k)? {
+            return Ok(Some(block));
+        }
+        let block = self.cold.get(k)?;
+        if let (true, Some(block)) = (self.promote, &block) {
+            self.hot.put_keyed(k, block)?;
+        }
+        Ok(block)
+    }
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.hot.put_keyed(k, block)
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        Ok(self.hot.has(k)? || self.cold.has(k)?)
+    }
+}
+
+/// How a `CompressedBlockstore` compresses the blocks it writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// zstd at the given level.
+    Zstd(i32),
+    /// LZ4, faster than zstd at any level but compressing less.
+    Lz4,
+}
+
+/// Compresses blocks before handing them to the wrapped store and
+/// decompresses them on the way out. CIDs keep addressing the uncompressed
+/// bytes. Each stored value says how it was compressed, so the setting can
+/// change between writes.
+pub struct CompressedBlockstore<S> {
+    store: S,
+    compression: Compression,
+}
+
+impl<S: Blockstore> CompressedBlockstore<S> {
+    pub fn new(store: S, compression: Compression) -> CompressedBlockstore<S> {
+        CompressedBlockstore { store, compression }
+    }
+
+    /// The wrapped store, holding blocks in their compressed form.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+}
+
+impl<S: Blockstore> Blockstore for CompressedBlockstore<S> {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        self.store.delete_block(k)
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        self.store
+            .get(k)?
+            .map(|stored| compression::decode(&stored))
+            .transpose()
+    }
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        let stored = match self.compression {
+            Compression::None => compression::encode_plain(block),
+            Compression::Zstd(level) => compression::encode_zstd(block, level)?,
+            Compression::Lz4 => compression::encode_lz4(block),
+        };
+        self.store.put_keyed(k, &stored)
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        self.store.has(k)
+    }
+}
+
+/// Counters describing how reads were served by a `TieredBlockstore`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TierStats {
+    pub hot_hits: usize,
+    pub cold_hits: usize,
+    pub promotions: usize,
+    /// Blocks written to the hot tier and copied to the cold one when
+    /// evicted.
+    pub write_backs: usize,
+}
+
+/// A cold store fronted by a bounded in-memory hot tier. Blocks read from
+/// the cold store more than a threshold number of times within a window
+/// are promoted to the hot tier; writes land in the hot tier and reach the
+/// cold store once evicted, on `write_back` or when the store is dropped.
+/// Dropping it ignores write-back errors, so call `write_back` first to see
+/// them.
+pub struct TieredBlockstore<B: Blockstore> {
+    cold: B,
+    promotion_threshold: u32,
+    window: Duration,
+    hot_capacity: usize,
+    state: Mutex<TierState>,
+}
+
+#[derive(Default)]
+struct TierState {
+    // each hot block, whether it still has to be written back, and the
+    // tick of its last use
+    hot: HashMap<Cid, (Vec<u8>, bool, u64)>,
+    // hot blocks by the tick of their last use, oldest first
+    recency: BTreeMap<u64, Cid>,
+    tick: u64,
+    // cold reads of each block since the start of its window
+    accesses: HashMap<Cid, (u32, Instant)>,
+    stats: TierStats,
+}
+
+impl TierState {
+    fn touch(&mut self, k: &Cid) {
+        if let Some((_, _, last_used)) = self.hot.get_mut(k) {
+            self.recency.remove(last_used);
+            self.tick += 1;
+            *last_used = self.tick;
+            self.recency.insert(self.tick, *k);
+        }
+    }
+}
+
+impl<B: Blockstore> TieredBlockstore<B> {
+    pub fn new(cold: B) -> TieredBlockstore<B> {
+        TieredBlockstore {
+            cold,
+            promotion_threshold: DEFAULT_PROMOTION_THRESHOLD,
+            window: DEFAULT_PROMOTION_WINDOW,
+            hot_capacity: DEFAULT_HOT_CAPACITY,
+            state: Mutex::new(TierState::default()),
+        }
+    }
+
+    /// Promotes blocks read from the cold store more than `reads` times
+    /// within the promotion window.
+    pub fn with_promotion_threshold(mut self, reads: u32) -> Self {
+        self.promotion_threshold = reads;
+        self
+    }
+
+    pub fn with_promotion_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Keeps up to `blocks` blocks in the hot tier.
+    pub fn with_hot_capacity(mut self, blocks: usize) -> Self {
+        self.hot_capacity = blocks;
+        self
+    }
+
+    pub fn cold(&self) -> &B {
+        &self.cold
+    }
+
+    pub fn tier_stats(&self) -> TierStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    /// Copies every hot block not yet in the cold store to it.
+    pub fn write_back(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let TierState { hot, stats, .. } = &mut *state;
+        for (cid, (block, dirty, _)) in hot.iter_mut().filter(|(_, (_, dirty, _))| *dirty) {
+            self.cold.put_keyed(cid, block)?;
+            *dirty = false;
+            stats.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    fn insert_hot(
+        &self,
+        state: &mut TierState,
+        k: &Cid,
+        block: Vec<u8>,
+        dirty: bool,
+    ) -> Result<()> {
+        if let Some((_, _, last_used)) = state.hot.remove(k) {
+            state.recency.remove(&last_used);
+        }
+        state.tick += 1;
+        state.hot.insert(*k, (block, dirty, state.tick));
+        state.recency.insert(state.tick, *k);
+
+        while state.hot.len() > self.hot_capacity {
+            let (&last_used, &evicted) = state
+                .recency
+                .first_key_value()
+                .expect("every hot block has a tick");
+            // a block the cold store refuses stays hot and dirty
+            let (block, dirty, _) = &state.hot[&evicted];
+            if *dirty {
+                self.cold.put_keyed(&evicted, block)?;
+                state.stats.write_backs += 1;
+            }
+            state.recency.remove(&last_used);
+            state.hot.remove(&evicted);
+        }
+        Ok(())
+    }
+
+    /// Counts a cold read of `k`, returning whether it earned a promotion.
+    fn record_cold_read(&self, state: &mut TierState, k: &Cid) -> bool {
+        let now = Instant::now();
+        if state.accesses.len() > 4 * self.hot_capacity {
+            let window = self.window;
+            state
+                .accesses
+                .retain(|_, (_, start)| now.duration_since(*start) < window);
+        }
+        let (count, start) = state.accesses.entry(*k).or_insert((0, now));
+        if now.duration_since(*start) >= self.window {
+            *count = 0;
+            *start = now;
+        }
+        *count += 1;
+        if *count > self.promotion_threshold {
+            state.accesses.remove(k);
+            return true;
+        }
+        false
+    }
+}
+
+impl<B: Blockstore> Drop for TieredBlockstore<B> {
+    fn drop(&mut self) {
+        let _ = self.write_back();
+    }
+}
+
+impl<B: Blockstore> Blockstore for TieredBlockstore<B> {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, _, last_used)) = state.hot.remove(k) {
+            state.recency.remove(&last_used);
+        }
+        state.accesses.remove(k);
+        self.cold.delete_block(k)
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some((block, _, _)) = state.hot.get(k) {
+            let block = block.clone();
+            state.touch(k);
+            state.stats.hot_hits += 1;
+            return Ok(Some(block));
+        }
+        let block = match self.cold.get(k)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        state.stats.cold_hits += 1;
+        if self.record_cold_read(&mut state, k) {
+            state.stats.promotions += 1;
+            self.insert_hot(&mut state, k, block.clone(), false)?;
+        }
+        Ok(Some(block))
+    }
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.insert_hot(&mut state, k, block.to_vec(), true)
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        if self.state.lock().unwrap().hot.contains_key(k) {
+            return Ok(true);
+        }
+        self.cold.has(k)
+    }
+}
+
+/// Re-hashes every block read from the wrapped store and fails the read
+/// if it does not match its CID, catching corruption in backends that are
+/// not trusted to return what they were given. Blocks hashed with a
+/// function this build cannot compute are refused as well.
+pub struct VerifyingBlockstore<S: Blockstore>(pub S);
+
+impl<S: Blockstore> Blockstore for VerifyingBlockstore<S> {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        self.0.delete_block(k)
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        let block = match self.0.get(k)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let code = Code::try_from(k.hash().code()).map_err(|_| {
+            anyhow!(
+                "cannot verify {}: unsupported multihash code {:#x}",
+                k,
+                k.hash().code()
+            )
+        })?;
+        if code.digest(&block) != *k.hash() {
+            return Err(anyhow!("block {} does not match its digest", k));
+        }
+        Ok(Some(block))
+    }
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.0.put_keyed(k, block)
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        self.0.has(k)
+    }
+}
+
+/// Holds writes in memory until `flush` persists the ones a root reaches,
+/// so intermediate blocks that end up unreferenced never hit the disk.
+pub struct BufferedQuarry {
+    quarry: Quarry,
+    // keyed by `storage_cid`, so either CID version finds a block
+    buffer: RwLock<HashMap<Cid, Vec<u8>>>,
+}
+
+impl BufferedQuarry {
+    pub fn new(quarry: Quarry) -> BufferedQuarry {
+        BufferedQuarry {
+            quarry,
+            buffer: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The wrapped store, holding only what was flushed.
+    pub fn inner(&self) -> &Quarry {
+        &self.quarry
+    }
+}
+
+impl Blockstore for BufferedQuarry {
+    fn delete_block(&self, k: &Cid) -> Result<()> {
+        self.buffer.write().unwrap().remove(&storage_cid(k));
+        self.quarry.delete_block(k)
+    }
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        if let Some(block) = self.buffer.read().unwrap().get(&storage_cid(k)) {
+            return Ok(Some(block.clone()));
+        }
+        self.quarry.get(k)
+    }
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.buffer
+            .write()
+            .unwrap()
+            .insert(storage_cid(k), block.to_vec());
+        Ok(())
+    }
+    fn has(&self, k: &Cid) -> Result<bool> {
+        if self.buffer.read().unwrap().contains_key(&storage_cid(k)) {
+            return Ok(true);
+        }
+        self.quarry.has(k)
+    }
+}
+
+impl Buffered for BufferedQuarry {
+    /// Writes the buffered blocks reachable from `root` in one batch and
+    /// drops the rest of the buffer. Links are followed through buffered
+    /// nodes only, blocks outside the buffer being already stored.
+    fn flush(&self, root: &Cid) -> Result<()> {
+        let mut buffer = self.buffer.write().unwrap();
+        let mut reachable = HashMap::new();
+        let mut stack = vec![*root];
+        while let Some(cid) = stack.pop() {
+            // buffered blocks are keyed like in the store
+            let cid = storage_cid(&cid);
+            if reachable.contains_key(&cid) {
+                continue;
+            }
+            let block = match buffer.get(&cid) {
+                Some(block) => block.clone(),
+                None => continue,
+            };
+            stack.extend(wiresaw::links_of(&cid, &block)?);
+            reachable.insert(cid, block);
+        }
+        self.quarry.put_many_keyed(reachable)?;
+        buffer.clear();
+        Ok(())
+    }
+}
+
+impl wiresaw::Storer for BufferedQuarry {
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        Blockstore::put_keyed(self, k, block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{raw_blocks, with_instance};
+    use crate::MemoryBlockstore;
+
+    #[test]
+    fn delegating_promotes_on_read() {
+        with_instance(|cold| {
+            let stored = b"cold block";
+            let cold_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(stored));
+            cold.put_keyed(&cold_cid, stored).unwrap();
+
+            let store = DelegatingBlockstore::new(MemoryBlockstore::default(), cold.clone())
+                .promote_on_read(true);
+            assert!(store.has(&cold_cid).unwrap());
+            assert!(!store.hot().has(&cold_cid).unwrap());
+            assert_eq!(store.get(&cold_cid).unwrap(), Some(stored.to_vec()));
+            assert!(store.hot().has(&cold_cid).unwrap());
+
+            let written = b"hot block";
+            let hot_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(written));
+            store.put_keyed(&hot_cid, written).unwrap();
+            assert!(store.hot().has(&hot_cid).unwrap());
+            assert!(!cold.has(&hot_cid).unwrap());
+
+            store.delete_block(&cold_cid).unwrap();
+            assert_eq!(store.get(&cold_cid).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn tiered_promotes_and_writes_back() {
+        with_instance(|cold| {
+            let blocks = raw_blocks(3, 10);
+            for (cid, block) in &blocks {
+                cold.put_keyed(cid, block).unwrap();
+            }
+
+            let store = TieredBlockstore::new(cold.clone())
+                .with_promotion_threshold(2)
+                .with_hot_capacity(2);
+            let (a, b, c) = (&blocks[0], &blocks[1], &blocks[2]);
+            for _ in 0..4 {
+                assert_eq!(store.get(&a.0).unwrap().as_ref(), Some(&a.1));
+            }
+            assert_eq!(
+                store.tier_stats(),
+                TierStats {
+                    hot_hits: 1,
+                    cold_hits: 3,
+                    promotions: 1,
+                    write_backs: 0,
+                }
+            );
+
+            let written = b"written hot";
+            let written_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(written));
+            store.put_keyed(&written_cid, written).unwrap();
+            assert!(store.has(&written_cid).unwrap());
+            assert!(!cold.has(&written_cid).unwrap());
+
+            // promoting b evicts a, then promoting c evicts the written block
+            for (cid, _) in [b, b, b, c, c, c] {
+                store.get(cid).unwrap();
+            }
+            let stats = store.tier_stats();
+            assert_eq!((stats.promotions, stats.write_backs), (3, 1));
+            assert_eq!(cold.get(&written_cid).unwrap(), Some(written.to_vec()));
+            assert_eq!(store.get(&c.0).unwrap().as_ref(), Some(&c.1));
+            assert_eq!(store.tier_stats().hot_hits, 2);
+        });
+    }
+
+    #[test]
+    fn tiered_keeps_blocks_the_cold_store_refuses() {
+        with_instance(|cold| {
+            let mut blocks = raw_blocks(3, 10);
+            blocks.sort();
+            let (a, b, c) = (&blocks[0], &blocks[1], &blocks[2]);
+            cold.mark_readonly_range(&a.0, &b.0).unwrap();
+
+            let store = TieredBlockstore::new(cold.clone()).with_hot_capacity(1);
+            store.put_keyed(&a.0, &a.1).unwrap();
+            assert!(store.put_keyed(&c.0, &c.1).is_err());
+            assert_eq!(store.get(&a.0).unwrap().as_ref(), Some(&a.1));
+            assert_eq!(store.tier_stats().write_backs, 0);
+
+            cold.unmark_readonly_range(&a.0, &b.0).unwrap();
+            drop(store);
+            assert_eq!(cold.get(&a.0).unwrap().as_ref(), Some(&a.1));
+            assert_eq!(cold.get(&c.0).unwrap().as_ref(), Some(&c.1));
+        });
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let text = include_bytes!("../wiresaw/src/fixture.txt");
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(text));
+        let lookalike = b"\xffqzc starts like a compression header";
+        let lookalike_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(lookalike));
+
+        for compression in [
+            Compression::None,
+            Compression::Zstd(1),
+            Compression::Zstd(19),
+            Compression::Lz4,
+        ] {
+            let store = CompressedBlockstore::new(MemoryBlockstore::new(), compression);
+            store.put_keyed(&cid, text).unwrap();
+            store.put_keyed(&lookalike_cid, lookalike).unwrap();
+            assert_eq!(store.get(&cid).unwrap().as_deref(), Some(&text[..]));
+            assert_eq!(
+                store.get(&lookalike_cid).unwrap().as_deref(),
+                Some(&lookalike[..])
+            );
+
+            let stored = Blockstore::get(store.inner(), &cid).unwrap().unwrap();
+            match compression {
+                Compression::None => assert_eq!(stored, text),
+                Compression::Zstd(_) | Compression::Lz4 => assert!(stored.len() < text.len()),
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_rejects_oversized_headers() {
+        let store = CompressedBlockstore::new(MemoryBlockstore::new(), Compression::Lz4);
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"block"));
+        for codec in [1, 2] {
+            let mut stored = b"\xffqzc".to_vec();
+            stored.push(codec);
+            stored.extend_from_slice(&(1u64 << 60).to_le_bytes());
+            stored.extend_from_slice(b"payload");
+            store.inner().put_keyed(&cid, &stored).unwrap();
+            assert!(store.get(&cid).is_err());
+        }
+    }
+
+    #[test]
+    fn verifying_rejects_corrupt_blocks() {
+        let store = VerifyingBlockstore(MemoryBlockstore::new());
+        for code in [
+            Code::Sha2_256,
+            Code::Sha3_512,
+            Code::Blake2b256,
+            Code::Blake3_256,
+        ] {
+            let block = format!("block hashed with {:?}", code).into_bytes();
+            let cid = Cid::new_v1(0x55, code.digest(&block));
+            store.put_keyed(&cid, &block).unwrap();
+            assert_eq!(store.get(&cid).unwrap(), Some(block));
+
+            store.0.put_keyed(&cid, b"flipped bits").unwrap();
+            assert!(store.get(&cid).is_err());
+        }
+
+        let unknown = Cid::new_v1(
+            0x55,
+            cid::multihash::Multihash::wrap(0x300000, b"digest").unwrap(),
+        );
+        store.0.put_keyed(&unknown, b"block").unwrap();
+        assert!(store.get(&unknown).is_err());
+        assert_eq!(
+            store
+                .get(&Cid::new_v1(0x55, Code::Sha2_256.digest(b"absent")))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn buffered_flushes_reachable_blocks() {
+        with_instance(|quarry| {
+            let store = BufferedQuarry::new(quarry);
+
+            let leaf = b"kept leaf";
+            let leaf_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(leaf));
+            let root = wiresaw::DagBuilder::new(std::iter::once(leaf.to_vec()), &store)
+                .trickle()
+                .unwrap()
+                .root;
+            let orphan = b"orphan";
+            let orphan_cid = Cid::new_v1(0x55, Code::Sha2_256.digest(orphan));
+            store.put_keyed(&orphan_cid, orphan).unwrap();
+
+            assert!(store.has(&orphan_cid).unwrap());
+            assert!(!store.inner().has(&root).unwrap());
+            store.flush(&root).unwrap();
+            assert!(store.inner().has(&root).unwrap());
+            assert_eq!(store.inner().get(&leaf_cid).unwrap(), Some(leaf.to_vec()));
+            assert!(!store.has(&orphan_cid).unwrap());
+        });
+    }
+}
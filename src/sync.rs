@@ -0,0 +1,218 @@
+use crate::walk::DagWalker;
+use crate::{Blockstore, Quarry};
+use anyhow::Result;
+use cid::Cid;
+use std::collections::HashSet;
+
+/// Counters describing the outcome of a sync.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncStats {
+    pub blocks_transferred: usize,
+    pub blocks_skipped: usize,
+    pub bytes_transferred: u64,
+}
+
+/// Copies the DAGs reachable from a set of roots from one store to another,
+/// skipping blocks the destination already has.
+pub struct SyncBlockstore<'a, A, B> {
+    source: &'a A,
+    dest: &'a B,
+    dry_run: bool,
+}
+
+impl<'a, A: Blockstore, B: Blockstore> SyncBlockstore<'a, A, B> {
+    pub fn new(source: &'a A, dest: &'a B) -> SyncBlockstore<'a, A, B> {
+        SyncBlockstore {
+            source,
+            dest,
+            dry_run: false,
+        }
+    }
+
+    /// When enabled, stats are computed as usual but nothing is written to
+    /// the destination.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn sync(&self, roots: &[Cid]) -> Result<SyncStats> {
+        let mut stats = SyncStats::default();
+        for entry in DagWalker::new(self.source, roots) {
+            let (cid, block) = entry?;
+            if self.dest.has(&cid)? {
+                stats.blocks_skipped += 1;
+                continue;
+            }
+            if !self.dry_run {
+                self.dest.put_keyed(&cid, &block)?;
+            }
+            stats.blocks_transferred += 1;
+            stats.bytes_transferred += block.len() as u64;
+        }
+        Ok(stats)
+    }
+}
+
+/// Copies every block reachable from `roots` that `dest` does not have yet.
+pub fn sync<A: Blockstore, B: Blockstore>(
+    source: &A,
+    dest: &B,
+    roots: &[Cid],
+) -> Result<SyncStats> {
+    SyncBlockstore::new(source, dest).sync(roots)
+}
+
+/// Blocks to move so that a store holds one set of DAGs instead of another.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StoreDiff {
+    /// Blocks of the wanted DAGs the other store lacks, in traversal order.
+    pub to_send: Vec<Cid>,
+    /// Blocks of the current DAGs no wanted DAG links to.
+    pub to_delete: Vec<Cid>,
+    pub in_common: usize,
+}
+
+impl Quarry {
+    /// Compares the DAGs rooted at `roots_a` in this store with those
+    /// rooted at `roots_b` in `other`, listing what `other` has to gain and
+    /// lose to hold the former instead of the latter.
+    pub fn diff_with<B: Blockstore>(
+        &self,
+        other: &B,
+        roots_a: &[Cid],
+        roots_b: &[Cid],
+    ) -> Result<StoreDiff> {
+        let a = reachable_cids(self, roots_a)?;
+        let b = reachable_cids(other, roots_b)?;
+        let (wanted, current): (HashSet<&Cid>, HashSet<&Cid>) =
+            (a.iter().collect(), b.iter().collect());
+        Ok(StoreDiff {
+            to_send: a
+                .iter()
+                .filter(|cid| !current.contains(cid))
+                .copied()
+                .collect(),
+            to_delete: b
+                .iter()
+                .filter(|cid| !wanted.contains(cid))
+                .copied()
+                .collect(),
+            in_common: wanted.intersection(&current).count(),
+        })
+    }
+}
+
+/// Every CID reachable from `roots`, in traversal order.
+fn reachable_cids<B: Blockstore>(store: &B, roots: &[Cid]) -> Result<Vec<Cid>> {
+    DagWalker::new(store, roots)
+        .map(|entry| entry.map(|(cid, _)| cid))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{assert_dag_complete, with_instance};
+    use crate::MemoryBlockstore;
+    use std::panic::AssertUnwindSafe;
+    use wiresaw::{ChunkReader, DagBuilder};
+
+    fn build_dag(store: &MemoryBlockstore, data: &[u8]) -> Cid {
+        let mut reader = ChunkReader::with_chunk_size(1 << 10, data);
+        reader.set_content_size(data.len() as u64);
+        DagBuilder::new(reader, store).trickle().unwrap().root
+    }
+
+    #[test]
+    fn sync_is_incremental() {
+        let source = MemoryBlockstore::default();
+        let dest = MemoryBlockstore::default();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let root = build_dag(&source, &data);
+        let blocks = source.len();
+
+        let stats = sync(&source, &dest, &[root]).unwrap();
+        assert_eq!(stats.blocks_transferred, blocks);
+        assert_eq!(stats.blocks_skipped, 0);
+        for cid in source.cids() {
+            assert_eq!(dest.get(&cid).unwrap(), source.get(&cid).unwrap());
+        }
+        assert_dag_complete(&root, &dest);
+
+        let stats = sync(&source, &dest, &[root]).unwrap();
+        assert_eq!(stats.blocks_transferred, 0);
+        assert_eq!(stats.blocks_skipped, blocks);
+        assert_eq!(stats.bytes_transferred, 0);
+    }
+
+    #[test]
+    fn sync_dry_run() {
+        let source = MemoryBlockstore::default();
+        let dest = MemoryBlockstore::default();
+        let root = build_dag(&source, b"a dry run only counts what it would copy");
+
+        let stats = SyncBlockstore::new(&source, &dest)
+            .dry_run(true)
+            .sync(&[root])
+            .unwrap();
+        assert_eq!(stats.blocks_transferred, 2);
+        assert!(dest.is_empty());
+    }
+
+    #[test]
+    fn incomplete_dag_names_path() {
+        let store = MemoryBlockstore::default();
+        let data: Vec<u8> = (0..5_000u32).map(|i| (i % 251) as u8).collect();
+        let root = build_dag(&store, &data);
+        assert_dag_complete(&root, &store);
+
+        let leaf = store.cids().into_iter().find(|cid| *cid != root).unwrap();
+        store.delete_block(&leaf).unwrap();
+        let panic =
+            std::panic::catch_unwind(AssertUnwindSafe(|| assert_dag_complete(&root, &store)))
+                .unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.contains(&format!("path: {} -> {}", root, leaf)));
+    }
+
+    #[test]
+    fn diff_with() {
+        with_instance(|quarry| {
+            let other = MemoryBlockstore::default();
+
+            // both DAGs share their first 4 KiB of chunks
+            let shared: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+            let a_data = [&shared[..], &[1u8; 3000]].concat();
+            let b_data = [&shared[..], &[2u8; 2000]].concat();
+            let scratch = MemoryBlockstore::default();
+            let root_a = build_dag(&scratch, &a_data);
+            sync(&scratch, &quarry, &[root_a]).unwrap();
+            let root_b = build_dag(&other, &b_data);
+
+            let diff = quarry.diff_with(&other, &[root_a], &[root_b]).unwrap();
+            let a: HashSet<Cid> = reachable_cids(&quarry, &[root_a])
+                .unwrap()
+                .into_iter()
+                .collect();
+            let b: HashSet<Cid> = reachable_cids(&other, &[root_b])
+                .unwrap()
+                .into_iter()
+                .collect();
+            let to_send: HashSet<Cid> = diff.to_send.iter().copied().collect();
+            assert_eq!(to_send, a.difference(&b).copied().collect());
+            assert_eq!(diff.to_delete.len(), b.difference(&a).count());
+            assert_eq!(diff.in_common, 4);
+
+            for cid in &diff.to_send {
+                let block = quarry.get(cid).unwrap().unwrap();
+                Blockstore::put_keyed(&other, cid, &block).unwrap();
+            }
+            for cid in &diff.to_delete {
+                other.delete_block(cid).unwrap();
+            }
+            assert_dag_complete(&root_a, &other);
+            assert!(!other.has(&root_b).unwrap());
+        });
+    }
+}
@@ -0,0 +1,72 @@
+//! Assertions and fixtures for tests exercising blockstores, available to
+//! downstream crates through the `testing` feature.
+
+use crate::walk::DagWalker;
+use crate::{Blockstore, Quarry, QuarryConfig};
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+
+const TEST_DIR: &str = "test_dir";
+
+static TEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Removes its directory when dropped, unwinding included.
+struct TestDir(PathBuf);
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `f` with a fresh, empty directory under `test_dir`, removed once
+/// `f` returns or panics.
+pub fn with_dir<F: FnOnce(&Path)>(f: F) {
+    let subdir = format!("test_{}", TEST_COUNTER.fetch_add(1, SeqCst));
+    let dir = TestDir(Path::new(TEST_DIR).join(subdir));
+    let _ = fs::remove_dir_all(&dir.0);
+    fs::create_dir_all(&dir.0).unwrap();
+    f(&dir.0)
+}
+
+/// Runs `f` with a store opened with `config` in a fresh directory, also
+/// passing the directory so the store can be reopened.
+pub fn with_config<F: FnOnce(Quarry, &Path)>(config: QuarryConfig, f: F) {
+    with_dir(|path| f(Quarry::open_with_config(path, config).unwrap(), path))
+}
+
+/// Runs `f` with a fresh store.
+pub fn with_instance<F: FnOnce(Quarry)>(f: F) {
+    with_config(QuarryConfig::default(), |quarry, _| f(quarry))
+}
+
+/// `n` raw blocks of `len` bytes with their CIDs, block `i` filled with the
+/// byte `i`.
+pub fn raw_blocks(n: u8, len: usize) -> Vec<(Cid, Vec<u8>)> {
+    (0..n)
+        .map(|i| {
+            let block = vec![i; len];
+            (Cid::new_v1(0x55, Code::Sha2_256.digest(&block)), block)
+        })
+        .collect()
+}
+
+/// Panics unless every block reachable from `root` is in `store`, naming
+/// the chain of links leading to the first block found missing.
+pub fn assert_dag_complete<B: Blockstore>(root: &Cid, store: &B) {
+    let mut walker = DagWalker::new(store, &[*root]);
+    while let Some(block) = walker.next() {
+        if let Err(e) = block {
+            let path: Vec<String> = walker.path().iter().map(Cid::to_string).collect();
+            panic!(
+                "DAG rooted at {} is incomplete: {}\npath: {}",
+                root,
+                e,
+                path.join(" -> ")
+            );
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::Blockstore;
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use std::collections::HashSet;
+
+/// Depth-first traversal over every block reachable from a set of roots.
+///
+/// Links of dag-cbor, dag-json and dag-pb nodes are followed through
+/// `wiresaw::links_of`; any other codec is treated as a leaf. Each block is yielded once
+/// even if it is linked from several parents.
+pub struct DagWalker<'a, B> {
+    store: &'a B,
+    stack: Vec<(Cid, usize)>,
+    path: Vec<Cid>,
+    seen: HashSet<Cid>,
+}
+
+impl<'a, B: Blockstore> DagWalker<'a, B> {
+    pub fn new(store: &'a B, roots: &[Cid]) -> DagWalker<'a, B> {
+        DagWalker {
+            store,
+            stack: roots.iter().rev().map(|root| (*root, 0)).collect(),
+            path: vec![],
+            seen: HashSet::new(),
+        }
+    }
+
+    /// The chain of CIDs from a root down to the last visited block.
+    pub fn path(&self) -> &[Cid] {
+        &self.path
+    }
+
+    fn visit(&mut self, cid: Cid, depth: usize) -> Result<(Cid, Vec<u8>)> {
+        self.path.truncate(depth);
+        self.path.push(cid);
+
+        let block = self
+            .store
+            .get(&cid)?
+            .ok_or_else(|| anyhow!("block {} is missing from the store", cid))?;
+
+        for link in wiresaw::links_of(&cid, &block)?.into_iter().rev() {
+            self.stack.push((link, depth + 1));
+        }
+
+        Ok((cid, block))
+    }
+}
+
+impl<'a, B: Blockstore> Iterator for DagWalker<'a, B> {
+    type Item = Result<(Cid, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((cid, depth)) = self.stack.pop() {
+            if self.seen.insert(cid) {
+                return Some(self.visit(cid, depth));
+            }
+        }
+        None
+    }
+}
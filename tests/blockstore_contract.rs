@@ -0,0 +1,141 @@
+//! Checks `MemoryBlockstore` and `Quarry` against the `Blockstore` contract
+//! by replaying random operations against a `HashMap` model.
+
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use proptest::prelude::*;
+use quarry::testing::with_instance;
+use quarry::{Blockstore, MemoryBlockstore};
+use std::collections::HashMap;
+
+// each Quarry case opens fresh stores on disk, so it runs fewer cases
+const QUARRY_CASES: u32 = 24;
+
+const MAX_OPS: usize = 64;
+
+// few enough keys that operations keep hitting the same blocks
+const KEYS: u8 = 12;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Put(Cid, Vec<u8>),
+    Get(Cid),
+    Has(Cid),
+    Delete(Cid),
+    PutMany(Vec<(Cid, Vec<u8>)>),
+}
+
+fn key(i: u8) -> Cid {
+    Cid::new_v1(0x55, Code::Sha2_256.digest(&[i]))
+}
+
+fn any_key() -> impl Strategy<Value = Cid> {
+    (0..KEYS).prop_map(key)
+}
+
+fn any_block() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..1024)
+}
+
+fn any_blocks(max: usize) -> impl Strategy<Value = Vec<(Cid, Vec<u8>)>> {
+    prop::collection::vec((any_key(), any_block()), 0..max)
+}
+
+fn any_op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (any_key(), any_block()).prop_map(|(k, data)| Op::Put(k, data)),
+        any_key().prop_map(Op::Get),
+        any_key().prop_map(Op::Has),
+        any_key().prop_map(Op::Delete),
+        any_blocks(6).prop_map(Op::PutMany),
+    ]
+}
+
+fn any_ops() -> impl Strategy<Value = Vec<Op>> {
+    prop::collection::vec(any_op(), 0..MAX_OPS)
+}
+
+/// Replays `ops` on `store`, checking every read against the model.
+fn check<S: Blockstore>(ops: &[Op], store: &S) {
+    let mut model: HashMap<Cid, Vec<u8>> = HashMap::new();
+    for (step, op) in ops.iter().cloned().enumerate() {
+        let context = format!("step {}: {:?}", step, op);
+        match op {
+            Op::Put(k, data) => {
+                store.put_keyed(&k, &data).expect(&context);
+                assert!(store.has(&k).expect(&context), "{}", context);
+                assert_eq!(
+                    store.get(&k).expect(&context),
+                    Some(data.clone()),
+                    "{}",
+                    context
+                );
+                model.insert(k, data);
+            }
+            Op::Get(k) => {
+                assert_eq!(
+                    store.get(&k).expect(&context),
+                    model.get(&k).cloned(),
+                    "{}",
+                    context
+                );
+            }
+            Op::Has(k) => {
+                assert_eq!(
+                    store.has(&k).expect(&context),
+                    model.contains_key(&k),
+                    "{}",
+                    context
+                );
+            }
+            Op::Delete(k) => {
+                store.delete_block(&k).expect(&context);
+                assert_eq!(store.get(&k).expect(&context), None, "{}", context);
+                assert!(!store.has(&k).expect(&context), "{}", context);
+                model.remove(&k);
+            }
+            Op::PutMany(blocks) => {
+                store.put_many_keyed(blocks.clone()).expect(&context);
+                model.extend(blocks);
+            }
+        }
+    }
+    for i in 0..KEYS {
+        assert_eq!(store.get(&key(i)).unwrap(), model.get(&key(i)).cloned());
+    }
+}
+
+/// Puts the same blocks through `put_many_keyed` on one store and through
+/// sequential `put_keyed` calls on the other, then compares every key.
+fn check_put_many<S: Blockstore>(blocks: &[(Cid, Vec<u8>)], batched: &S, sequential: &S) {
+    batched.put_many_keyed(blocks.to_vec()).unwrap();
+    for (k, data) in blocks {
+        sequential.put_keyed(k, data).unwrap();
+    }
+    for i in 0..KEYS {
+        assert_eq!(
+            batched.get(&key(i)).unwrap(),
+            sequential.get(&key(i)).unwrap()
+        );
+    }
+}
+
+proptest! {
+    #[test]
+    fn memory_blockstore_contract(ops in any_ops(), blocks in any_blocks(32)) {
+        check(&ops, &MemoryBlockstore::new());
+        check_put_many(&blocks, &MemoryBlockstore::new(), &MemoryBlockstore::new());
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(QUARRY_CASES))]
+
+    #[test]
+    fn quarry_contract(ops in any_ops(), blocks in any_blocks(32)) {
+        with_instance(|quarry| check(&ops, &quarry));
+        with_instance(|batched| {
+            with_instance(|sequential| check_put_many(&blocks, &batched, &sequential))
+        });
+    }
+}
@@ -0,0 +1,200 @@
+//! CARv1 archives: a dag-cbor header listing the root CIDs followed by one
+//! `varint(len) | cid | data` frame per block.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read};
+use unsigned_varint::{decode, encode};
+
+const CAR_VERSION: u64 = 1;
+
+/// A block as stored in an archive.
+pub type CarBlock = (Cid, Vec<u8>);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CarHeader {
+    roots: Vec<Cid>,
+    version: u64,
+}
+
+fn write_frame(out: &mut Vec<u8>, parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    out.extend_from_slice(encode::u64(len as u64, &mut encode::u64_buffer()));
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+}
+
+fn read_frame(bytes: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (len, rest) = decode::u64(bytes)?;
+    let len = len as usize;
+    if rest.len() < len {
+        return Err(anyhow!(
+            "truncated car frame: expected {} bytes, got {}",
+            len,
+            rest.len()
+        ));
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Reads the next frame off `reader`, or `None` if it is at its end.
+fn read_frame_from<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut buf = encode::u64_buffer();
+    for i in 0..buf.len() {
+        if let Err(e) = reader.read_exact(&mut buf[i..=i]) {
+            if i == 0 && e.kind() == ErrorKind::UnexpectedEof {
+                return Ok(None);
+            }
+            return Err(e.into());
+        }
+        if decode::is_last(buf[i]) {
+            let (len, _) = decode::u64(&buf[..=i])?;
+            let mut frame = vec![];
+            reader.take(len).read_to_end(&mut frame)?;
+            if (frame.len() as u64) < len {
+                return Err(anyhow!(
+                    "truncated car frame: expected {} bytes, got {}",
+                    len,
+                    frame.len()
+                ));
+            }
+            return Ok(Some(frame));
+        }
+    }
+    Err(anyhow!("car frame length does not fit in a u64"))
+}
+
+/// Encodes a CARv1 archive holding `blocks` under the given roots.
+pub fn encode_car_v1(roots: &[Cid], blocks: impl IntoIterator<Item = CarBlock>) -> Vec<u8> {
+    let mut out = encode_car_v1_header(roots);
+    for (cid, data) in blocks {
+        out.extend_from_slice(&encode_car_v1_block(&cid, &data));
+    }
+    out
+}
+
+/// Encodes the header frame opening a CARv1 archive, for writers streaming
+/// the block frames themselves.
+pub fn encode_car_v1_header(roots: &[Cid]) -> Vec<u8> {
+    let header = CarHeader {
+        roots: roots.to_vec(),
+        version: CAR_VERSION,
+    };
+    let header = serde_ipld_dagcbor::to_vec(&header).expect("car header is always encodable");
+
+    let mut out = vec![];
+    write_frame(&mut out, &[&header]);
+    out
+}
+
+/// Encodes the frame of a single block.
+pub fn encode_car_v1_block(cid: &Cid, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    write_frame(&mut out, &[&cid.to_bytes(), data]);
+    out
+}
+
+/// Decodes a CARv1 archive into its roots and blocks, in archive order.
+pub fn decode_car_v1(bytes: &[u8]) -> Result<(Vec<Cid>, Vec<CarBlock>)> {
+    let (header, mut rest) = read_frame(bytes)?;
+    let header: CarHeader = serde_ipld_dagcbor::from_slice(header)?;
+    if header.version != CAR_VERSION {
+        return Err(anyhow!("unsupported car version {}", header.version));
+    }
+
+    let mut blocks = vec![];
+    while !rest.is_empty() {
+        let (frame, next) = read_frame(rest)?;
+        let mut data = frame;
+        let cid = Cid::read_bytes(&mut data)?;
+        blocks.push((cid, data.to_vec()));
+        rest = next;
+    }
+    Ok((header.roots, blocks))
+}
+
+/// Reads the header frame opening a CARv1 archive and returns its roots,
+/// leaving `reader` at the first block frame.
+pub fn read_car_v1_header<R: Read>(reader: &mut R) -> Result<Vec<Cid>> {
+    let header = read_frame_from(reader)?.ok_or_else(|| anyhow!("car archive is empty"))?;
+    let header: CarHeader = serde_ipld_dagcbor::from_slice(&header)?;
+    if header.version != CAR_VERSION {
+        return Err(anyhow!("unsupported car version {}", header.version));
+    }
+    Ok(header.roots)
+}
+
+/// Reads the next block frame off `reader`, or `None` once the archive is
+/// exhausted.
+pub fn read_car_v1_block<R: Read>(reader: &mut R) -> Result<Option<CarBlock>> {
+    let frame = match read_frame_from(reader)? {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    let mut data = &frame[..];
+    let cid = Cid::read_bytes(&mut data)?;
+    Ok(Some((cid, data.to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::{Code, MultihashDigest};
+
+    fn raw_block(data: &[u8]) -> CarBlock {
+        (
+            Cid::new_v1(0x55, Code::Sha2_256.digest(data)),
+            data.to_vec(),
+        )
+    }
+
+    #[test]
+    fn car_round_trip() {
+        let blocks: Vec<_> = (0..10u8)
+            .map(|i| raw_block(&vec![i; i as usize * 100]))
+            .collect();
+        let roots = vec![blocks[0].0, blocks[9].0];
+
+        let car = encode_car_v1(&roots, blocks.clone());
+        let (decoded_roots, decoded_blocks) = decode_car_v1(&car).unwrap();
+        assert_eq!(decoded_roots, roots);
+        assert_eq!(decoded_blocks, blocks);
+    }
+
+    #[test]
+    fn car_edge_cases() {
+        let (roots, blocks) = decode_car_v1(&encode_car_v1(&[], vec![])).unwrap();
+        assert!(roots.is_empty());
+        assert!(blocks.is_empty());
+
+        let block = raw_block(b"lonely block");
+        let car = encode_car_v1(&[block.0], vec![block.clone()]);
+        assert_eq!(decode_car_v1(&car).unwrap(), (vec![block.0], vec![block]));
+
+        assert!(decode_car_v1(&car[..car.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn car_streaming_read() {
+        let blocks: Vec<_> = (0..10u8)
+            .map(|i| raw_block(&vec![i; i as usize * 100]))
+            .collect();
+        let car = encode_car_v1(&[blocks[3].0], blocks.clone());
+
+        let mut reader = &car[..];
+        assert_eq!(read_car_v1_header(&mut reader).unwrap(), vec![blocks[3].0]);
+        let mut read = vec![];
+        while let Some(block) = read_car_v1_block(&mut reader).unwrap() {
+            read.push(block);
+        }
+        assert_eq!(read, blocks);
+
+        let mut truncated = &car[..car.len() - 1];
+        read_car_v1_header(&mut truncated).unwrap();
+        assert!((0..10)
+            .map(|_| read_car_v1_block(&mut truncated))
+            .any(|block| block.is_err()));
+    }
+}
@@ -0,0 +1,126 @@
+//! dag-json encoding of `Node`s: the same fields as the dag-cbor encoding,
+//! with CIDs written as `{"/": "<cid>"}` and bytes as
+//! `{"/": {"bytes": "<unpadded base64>"}}`. Map keys are sorted, so a node
+//! always encodes to the same bytes.
+
+use crate::{Link, Node};
+use anyhow::{anyhow, Result};
+use cid::multibase::{self, Base};
+use cid::Cid;
+use serde_json::{json, Map, Value};
+
+pub fn encode(node: &Node) -> Result<Vec<u8>> {
+    let mut map = Map::new();
+    if let Some(data) = &node.data {
+        // multibase prefixes base64 with 'm'
+        let bytes = &multibase::encode(Base::Base64, data)[1..];
+        map.insert("data".to_string(), json!({ "/": { "bytes": bytes } }));
+    }
+    let links = node.links.iter().map(encode_link).collect();
+    map.insert("links".to_string(), Value::Array(links));
+    Ok(serde_json::to_vec(&Value::Object(map))?)
+}
+
+fn encode_link(link: &Link) -> Value {
+    let mut map = Map::new();
+    map.insert("cid".to_string(), json!({ "/": link.cid.to_string() }));
+    if let Some(name) = &link.name {
+        map.insert("name".to_string(), json!(name));
+    }
+    if let Some(size) = link.size {
+        map.insert("size".to_string(), json!(size));
+    }
+    Value::Object(map)
+}
+
+pub fn decode(data: &[u8]) -> Result<Node> {
+    let value: Value = serde_json::from_slice(data)?;
+    let map = value
+        .as_object()
+        .ok_or_else(|| anyhow!("dag-json node is not an object"))?;
+    let data = match map.get("data") {
+        Some(data) => {
+            let bytes = data
+                .get("/")
+                .and_then(|slash| slash.get("bytes"))
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow!("dag-json node data is not bytes"))?;
+            Some(multibase::decode(format!("m{}", bytes))?.1)
+        }
+        None => None,
+    };
+    let links = map
+        .get("links")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("dag-json node has no links"))?
+        .iter()
+        .map(decode_link)
+        .collect::<Result<_>>()?;
+    Ok(Node { data, links })
+}
+
+fn decode_link(value: &Value) -> Result<Link> {
+    let cid = value
+        .get("cid")
+        .and_then(|cid| cid.get("/"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("dag-json link has no cid"))?;
+    let name = match value.get("name") {
+        Some(name) => Some(
+            name.as_str()
+                .ok_or_else(|| anyhow!("dag-json link name is not a string"))?
+                .to_string(),
+        ),
+        None => None,
+    };
+    let size = match value.get("size") {
+        Some(size) => Some(
+            size.as_u64()
+                .ok_or_else(|| anyhow!("dag-json link size is not an integer"))?,
+        ),
+        None => None,
+    };
+    Ok(Link {
+        cid: Cid::try_from(cid)?,
+        name,
+        size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cid::multihash::{Code, MultihashDigest};
+
+    #[test]
+    fn round_trip() {
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(b"leaf"));
+        let node = Node {
+            data: Some(b"some data".to_vec()),
+            links: vec![
+                Link::sized(cid, 4),
+                Link {
+                    cid,
+                    name: Some("named".to_string()),
+                    size: None,
+                },
+            ],
+        };
+        let enc = encode(&node).unwrap();
+        assert_eq!(
+            String::from_utf8(enc.clone()).unwrap(),
+            format!(
+                r#"{{"data":{{"/":{{"bytes":"c29tZSBkYXRh"}}}},"links":[{{"cid":{{"/":"{0}"}},"size":4}},{{"cid":{{"/":"{0}"}},"name":"named"}}]}}"#,
+                cid
+            )
+        );
+        let decoded = decode(&enc).unwrap();
+        assert_eq!(decoded, node);
+        assert_eq!(encode(&decoded).unwrap(), enc);
+
+        let empty = Node::with_links_cap(0);
+        assert_eq!(encode(&empty).unwrap(), br#"{"links":[]}"#);
+        assert_eq!(decode(br#"{"links":[]}"#).unwrap(), empty);
+        assert!(decode(br#"{"data":"raw"}"#).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+//! Encodings for moving blocks in and out of wiresaw.
+
+pub mod car;
+pub mod dag_json;
+pub mod unixfs;
@@ -0,0 +1,135 @@
+//! UnixFS files: dag-pb nodes wrapping protobuf `Data` messages, the layout
+//! IPFS imports files as.
+//!
+//! Only the subset needed for files is encoded: `PBNode { Links, Data }`,
+//! `PBLink { Hash, Name, Tsize }` and `Data { Type, Data, filesize,
+//! blocksizes }`, with fields written in the canonical order.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use unsigned_varint::{decode, encode};
+
+/// Codec of dag-pb blocks.
+pub const DAG_PB: u64 = 0x70;
+
+/// `Data.Type` of a file node.
+const TYPE_FILE: u64 = 2;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_BYTES: u64 = 2;
+
+fn write_varint(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(encode::u64(value, &mut encode::u64_buffer()));
+}
+
+fn write_key(out: &mut Vec<u8>, field: u64, wire: u64) {
+    write_varint(out, field << 3 | wire);
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(out, field, WIRE_BYTES);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_uint(out: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(out, field, WIRE_VARINT);
+    write_varint(out, value);
+}
+
+/// Encodes a UnixFS `Data` message of a file.
+fn file_data(data: Option<&[u8]>, filesize: u64, blocksizes: &[u64]) -> Vec<u8> {
+    let mut out = vec![];
+    write_uint(&mut out, 1, TYPE_FILE);
+    if let Some(data) = data {
+        write_bytes(&mut out, 2, data);
+    }
+    write_uint(&mut out, 3, filesize);
+    for size in blocksizes {
+        write_uint(&mut out, 4, *size);
+    }
+    out
+}
+
+/// Encodes a file leaf holding `data`.
+pub fn encode_leaf(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    write_bytes(&mut out, 1, &file_data(Some(data), data.len() as u64, &[]));
+    out
+}
+
+/// Encodes a file node linking `(cid, block size, content size)` children
+/// in order.
+pub fn encode_node(children: &[(Cid, u64, u64)]) -> Vec<u8> {
+    let mut out = vec![];
+    for (cid, tsize, _) in children {
+        let mut link = vec![];
+        write_bytes(&mut link, 1, &cid.to_bytes());
+        write_bytes(&mut link, 2, b"");
+        write_uint(&mut link, 3, *tsize);
+        write_bytes(&mut out, 2, &link);
+    }
+    let blocksizes: Vec<u64> = children.iter().map(|(_, _, size)| *size).collect();
+    let filesize = blocksizes.iter().sum();
+    write_bytes(&mut out, 1, &file_data(None, filesize, &blocksizes));
+    out
+}
+
+/// A protobuf field. Only byte payloads are read back, with their field
+/// number.
+enum Field<'a> {
+    Varint,
+    Bytes(u64, &'a [u8]),
+}
+
+fn read_fields(mut bytes: &[u8]) -> Result<Vec<Field<'_>>> {
+    let mut fields = vec![];
+    while !bytes.is_empty() {
+        let (key, rest) = decode::u64(bytes)?;
+        let (field, rest) = match key & 7 {
+            WIRE_VARINT => {
+                let (_, rest) = decode::u64(rest)?;
+                (Field::Varint, rest)
+            }
+            WIRE_BYTES => {
+                let (len, rest) = decode::u64(rest)?;
+                if (rest.len() as u64) < len {
+                    return Err(anyhow!("truncated protobuf field"));
+                }
+                let (value, rest) = rest.split_at(len as usize);
+                (Field::Bytes(key >> 3, value), rest)
+            }
+            wire => return Err(anyhow!("unsupported protobuf wire type {}", wire)),
+        };
+        fields.push(field);
+        bytes = rest;
+    }
+    Ok(fields)
+}
+
+/// Decodes a dag-pb file node into its linked CIDs and the content bytes it
+/// holds itself.
+pub fn decode_node(block: &[u8]) -> Result<(Vec<Cid>, Vec<u8>)> {
+    let mut links = vec![];
+    let mut content = vec![];
+    for field in read_fields(block)? {
+        match field {
+            Field::Bytes(1, data) => {
+                for field in read_fields(data)? {
+                    if let Field::Bytes(2, data) = field {
+                        content.extend_from_slice(data);
+                    }
+                }
+            }
+            Field::Bytes(2, link) => {
+                for field in read_fields(link)? {
+                    if let Field::Bytes(1, hash) = field {
+                        links.push(Cid::try_from(hash)?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((links, content))
+}
@@ -0,0 +1,26 @@
+//! Directory nodes: a dag-cbor `Node` whose links carry a name and size.
+
+use crate::{Getter, Node, DAG_CBOR};
+use anyhow::{anyhow, Result};
+use cid::Cid;
+
+/// A named directory entry: `(name, cid, size)`.
+pub type Entry = (String, Cid, u64);
+
+/// Lists the entries of the directory node stored under `root`.
+pub fn list(root: &Cid, store: &impl Getter) -> Result<Vec<Entry>> {
+    if root.codec() != DAG_CBOR {
+        return Err(anyhow!("{} is not a dag-cbor directory node", root));
+    }
+    let data = store
+        .get(root)?
+        .ok_or_else(|| anyhow!("directory node {} not found", root))?;
+    let node = Node::from_dag_cbor(&data)?;
+    node.links()
+        .iter()
+        .map(|link| match (link.name(), link.size()) {
+            (Some(name), Some(size)) => Ok((name.to_string(), *link.cid(), size)),
+            _ => Err(anyhow!("link to {} is not a directory entry", link.cid())),
+        })
+        .collect()
+}
@@ -0,0 +1,184 @@
+//! Sharded directories: a hash array mapped trie over entry names, laid out
+//! per the IPLD HAMT spec, so that directories with many entries don't end
+//! up in one oversized node.
+
+use crate::{Storer, DAG_CBOR};
+use anyhow::{anyhow, Result};
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Bits of the hash consumed per level, so every node has 256 slots.
+const BIT_WIDTH: usize = 8;
+
+/// Entries a slot holds before it is split into a child node.
+const BUCKET_SIZE: usize = 3;
+
+/// Multicodec of the hash applied to keys.
+const HASH_ALG: u64 = 0x12;
+
+const SLOTS: usize = 1 << BIT_WIDTH;
+
+/// What a name maps to: the entry's CID and size.
+type Value = (Cid, u64);
+
+enum Element {
+    Bucket(Vec<(Vec<u8>, Value)>),
+    Child(Box<HamtNode>),
+}
+
+#[derive(Default)]
+struct HamtNode {
+    slots: BTreeMap<u8, Element>,
+}
+
+impl HamtNode {
+    fn insert(&mut self, hash: &[u8], depth: usize, key: Vec<u8>, value: Value) -> Result<()> {
+        let index = *hash
+            .get(depth)
+            .ok_or_else(|| anyhow!("hamt is out of hash bits for {:?}", key))?;
+        let bucket = match self.slots.get_mut(&index) {
+            None => {
+                self.slots
+                    .insert(index, Element::Bucket(vec![(key, value)]));
+                return Ok(());
+            }
+            Some(Element::Child(child)) => return child.insert(hash, depth + 1, key, value),
+            Some(Element::Bucket(bucket)) => bucket,
+        };
+        match bucket.binary_search_by(|(other, _)| other.cmp(&key)) {
+            Ok(position) => bucket[position].1 = value,
+            Err(position) if bucket.len() < BUCKET_SIZE => bucket.insert(position, (key, value)),
+            Err(_) => {
+                let mut child = HamtNode::default();
+                for (key, value) in std::mem::take(bucket) {
+                    child.insert(&key_hash(&key), depth + 1, key, value)?;
+                }
+                child.insert(hash, depth + 1, key, value)?;
+                self.slots.insert(index, Element::Child(Box::new(child)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the children of the node, returning its encoding.
+    fn encode<S: Storer>(&self, store: &S) -> Result<EncodedNode> {
+        let mut map = vec![0u8; SLOTS / 8];
+        let mut data = Vec::with_capacity(self.slots.len());
+        for (index, element) in &self.slots {
+            // the bitfield is a big-endian integer with bit `index` set
+            let index = *index as usize;
+            map[SLOTS / 8 - 1 - index / 8] |= 1 << (index % 8);
+            data.push(match element {
+                Element::Bucket(bucket) => EncodedElement::Bucket(
+                    bucket
+                        .iter()
+                        .map(|(key, value)| BucketEntry(key.clone(), *value))
+                        .collect(),
+                ),
+                Element::Child(child) => {
+                    let enc = serde_ipld_dagcbor::to_vec(&child.encode(store)?)?;
+                    let cid = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&enc));
+                    store.put_keyed(&cid, &enc)?;
+                    EncodedElement::Link(cid)
+                }
+            });
+        }
+        Ok(EncodedNode(map, data))
+    }
+}
+
+fn key_hash(key: &[u8]) -> Vec<u8> {
+    Code::Sha2_256.digest(key).digest().to_vec()
+}
+
+#[derive(Serialize)]
+struct EncodedNode(#[serde(with = "serde_bytes")] Vec<u8>, Vec<EncodedElement>);
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum EncodedElement {
+    Link(Cid),
+    Bucket(Vec<BucketEntry>),
+}
+
+#[derive(Serialize)]
+struct BucketEntry(#[serde(with = "serde_bytes")] Vec<u8>, Value);
+
+/// Fields are declared in dag-cbor's canonical key order.
+#[derive(Serialize)]
+struct EncodedRoot {
+    hamt: EncodedNode,
+    #[serde(rename = "hashAlg")]
+    hash_alg: u64,
+    #[serde(rename = "bucketSize")]
+    bucket_size: usize,
+}
+
+/// A directory sharded over a HAMT with a bit width of 8, buckets of 3
+/// entries and SHA2-256 hashed names. Entries are kept in memory until
+/// `finish` stores the nodes. The layout only depends on the set of
+/// entries, not on the order they were inserted in.
+pub struct HamtDirectory<S> {
+    store: S,
+    root: HamtNode,
+}
+
+impl<S: Storer> HamtDirectory<S> {
+    pub fn new(store: S) -> HamtDirectory<S> {
+        HamtDirectory {
+            store,
+            root: HamtNode::default(),
+        }
+    }
+
+    /// Adds an entry, replacing any entry of the same name.
+    pub fn insert(&mut self, name: &str, cid: Cid, size: u64) -> Result<()> {
+        let key = name.as_bytes().to_vec();
+        self.root.insert(&key_hash(&key), 0, key, (cid, size))
+    }
+
+    /// Stores every node and returns the CID of the root.
+    pub fn finish(&mut self) -> Result<Cid> {
+        let root = EncodedRoot {
+            hamt: self.root.encode(&self.store)?,
+            hash_alg: HASH_ALG,
+            bucket_size: BUCKET_SIZE,
+        };
+        let enc = serde_ipld_dagcbor::to_vec(&root)?;
+        let cid = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&enc));
+        self.store.put_keyed(&cid, &enc)?;
+        Ok(cid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::MemoryBlockstore;
+
+    fn build(names: impl Iterator<Item = usize>) -> (Cid, MemoryBlockstore) {
+        let store = MemoryBlockstore::default();
+        let mut dir = HamtDirectory::new(&store);
+        for i in names {
+            let name = format!("file-{:06}", i);
+            let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(name.as_bytes()));
+            dir.insert(&name, cid, i as u64).unwrap();
+        }
+        let root = dir.finish().unwrap();
+        (root, store)
+    }
+
+    #[test]
+    fn hamt_directory() {
+        let (root, store) = build(0..100_000);
+        assert_eq!(build(0..100_000).0, root);
+        assert_eq!(build((0..100_000).rev()).0, root);
+        assert_ne!(build(0..1000).0, build(0..999).0);
+
+        let blocks = store.blocks.borrow();
+        assert!(blocks.len() > 256);
+        assert!(blocks.values().all(|block| block.len() < 64 << 10));
+    }
+}
@@ -0,0 +1,145 @@
+//! Poseidon hashing of DAG blocks, behind the `poseidon` feature.
+//!
+//! [`PoseidonHasher`] is built on neptune's arity-2 Poseidon over the
+//! scalar field of BLS12-381 with the standard-strength Merkle tree
+//! constants, the parameter set Filecoin proofs use. Blocks are padded with
+//! a `0x01` byte and zeros to a multiple of 31 bytes, each 31 bytes read as
+//! a little-endian field element, and chained: the state starts as the
+//! block's length in bytes and each element is hashed into it as
+//! `state = poseidon(state, element)`. The digest is the final state in 32
+//! little-endian bytes.
+
+use blstrs::Scalar;
+use cid::multihash::{Error, Multihash, MultihashDigest};
+use ff::PrimeField;
+use generic_array::typenum::U2;
+use neptune::poseidon::PoseidonConstants;
+use neptune::Poseidon;
+use std::sync::OnceLock;
+
+/// The multihash code of `PoseidonHasher` digests.
+///
+/// The multicodec table registers Filecoin's parameter set as
+/// `poseidon-bls12_381-a2-fc1` (0xb401), but that code hashes exactly two
+/// field elements, while these digests cover byte strings of any length.
+/// They carry 0xb401 moved into the private-use range (0x300000 to
+/// 0x3fffff), which the table leaves unassigned for codes agreed on outside
+/// of it, so only readers that know this module can tell what they are.
+pub const POSEIDON_CODE: u64 = 0x30_b401;
+
+/// Input bytes per field element, few enough to stay below the modulus.
+const LIMB_BYTES: usize = 31;
+
+/// Poseidon as a multihash, for `DagBuilder::with_hasher`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoseidonHasher;
+
+impl MultihashDigest<64> for PoseidonHasher {
+    fn digest(&self, input: &[u8]) -> Multihash {
+        Multihash::wrap(POSEIDON_CODE, &poseidon(input)).expect("32 byte digests fit")
+    }
+
+    fn wrap(&self, digest: &[u8]) -> Result<Multihash, Error> {
+        Multihash::wrap(POSEIDON_CODE, digest)
+    }
+}
+
+impl TryFrom<u64> for PoseidonHasher {
+    type Error = Error;
+
+    fn try_from(code: u64) -> Result<Self, Error> {
+        match code {
+            POSEIDON_CODE => Ok(PoseidonHasher),
+            _ => Err(Error::UnsupportedCode(code)),
+        }
+    }
+}
+
+impl From<PoseidonHasher> for u64 {
+    fn from(_: PoseidonHasher) -> u64 {
+        POSEIDON_CODE
+    }
+}
+
+// generating the round constants and matrices takes longer than hashing
+// a block, so they are built once
+fn constants() -> &'static PoseidonConstants<Scalar, U2> {
+    static CONSTANTS: OnceLock<PoseidonConstants<Scalar, U2>> = OnceLock::new();
+    CONSTANTS.get_or_init(PoseidonConstants::new)
+}
+
+/// One arity-2 Poseidon hash, as Filecoin hashes a pair of tree nodes.
+fn hash_pair(left: Scalar, right: Scalar) -> Scalar {
+    Poseidon::new_with_preimage(&[left, right], constants()).hash()
+}
+
+/// The chain over `data`, see the module documentation.
+fn poseidon(data: &[u8]) -> [u8; 32] {
+    let mut padded = data.to_vec();
+    padded.push(0x01);
+    padded.resize(padded.len().div_ceil(LIMB_BYTES) * LIMB_BYTES, 0);
+
+    let mut state = Scalar::from(data.len() as u64);
+    for limb in padded.chunks(LIMB_BYTES) {
+        let mut repr = [0u8; 32];
+        repr[..LIMB_BYTES].copy_from_slice(limb);
+        let element = Scalar::from_repr_vartime(repr).expect("31 bytes are below the modulus");
+        state = hash_pair(state, element);
+    }
+    state.to_bytes_le()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn scalar_from_u64s(limbs: [u64; 4]) -> Scalar {
+        Scalar::from_u64s_le(&limbs).unwrap()
+    }
+
+    #[test]
+    fn matches_neptune_reference() {
+        // neptune's standard-strength test vector for hashing (0, 1) with
+        // arity 2, which is also the whole chain over an empty block
+        let expected = scalar_from_u64s([
+            0x2e203c369a02e7ff,
+            0xa6fba9339d05a69d,
+            0x739e0fd902efe161,
+            0x396508d75e76a56b,
+        ]);
+        assert_eq!(hash_pair(Scalar::from(0), Scalar::from(1)), expected);
+        assert_eq!(poseidon(b""), expected.to_bytes_le());
+    }
+
+    #[test]
+    fn chains_limbs_from_the_length() {
+        let data: Vec<u8> = (0..40).collect();
+        let mut first = [0u8; 32];
+        first[..31].copy_from_slice(&data[..31]);
+        let mut second = [0u8; 32];
+        second[..9].copy_from_slice(&data[31..]);
+        second[9] = 0x01;
+
+        let state = hash_pair(Scalar::from(40), Scalar::from_repr_vartime(first).unwrap());
+        let state = hash_pair(state, Scalar::from_repr_vartime(second).unwrap());
+        assert_eq!(poseidon(&data), state.to_bytes_le());
+    }
+
+    #[test]
+    fn padding_keeps_lengths_apart() {
+        let digests: HashSet<[u8; 32]> = (0..64).map(|len| poseidon(&vec![0; len])).collect();
+        assert_eq!(digests.len(), 64);
+        assert_ne!(poseidon(&[0x01]), poseidon(&[]));
+    }
+
+    #[test]
+    fn multihash_code() {
+        let hash = PoseidonHasher.digest(b"block");
+        assert_eq!(hash.code(), POSEIDON_CODE);
+        assert_eq!(hash.digest(), poseidon(b"block"));
+        assert_eq!(u64::from(PoseidonHasher), POSEIDON_CODE);
+        assert!(PoseidonHasher::try_from(POSEIDON_CODE).is_ok());
+        assert!(PoseidonHasher::try_from(0xb401).is_err());
+    }
+}
@@ -1,20 +1,48 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cid::{
     multihash::{Code, Multihash, MultihashDigest},
     Cid,
 };
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+
+pub mod codec;
+use codec::{dag_json, unixfs};
+pub mod directory;
+pub mod hamt;
+#[cfg(feature = "poseidon")]
+pub mod hash;
+#[cfg(unix)]
+pub mod mmap;
+pub mod proof;
+pub mod stream;
 
 pub const DAG_CBOR: u64 = 0x71;
 
+pub const DAG_JSON: u64 = 0x0129;
+
 const DEFAULT_CHUNK_SIZE: usize = 1 << 18;
 
-#[derive(Debug, Serialize, Deserialize)]
+const DEFAULT_LINES_PER_CHUNK: usize = 1 << 10;
+
+/// Chunks `trickle_parallel` reads ahead per rayon worker thread.
+const PARALLEL_CHUNKS_PER_THREAD: usize = 4;
+
+/// Number of subtrees of each depth a trickle node links after its leaves.
+const TRICKLE_DEPTH_REPEAT: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Node {
-    #[serde(with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
     data: Option<Vec<u8>>,
     links: Vec<Link>,
 }
@@ -26,17 +54,149 @@ impl Node {
             links: Vec::with_capacity(size),
         }
     }
+
+    /// Decodes a node from its dag-cbor encoding.
+    pub fn from_dag_cbor(data: &[u8]) -> Result<Node> {
+        Ok(serde_ipld_dagcbor::from_slice(data)?)
+    }
+
+    /// Encodes the node as dag-cbor.
+    pub fn to_dag_cbor(&self) -> Result<Vec<u8>> {
+        Ok(serde_ipld_dagcbor::to_vec(self)?)
+    }
+
+    /// Decodes a node from its dag-json encoding.
+    pub fn from_dag_json(data: &[u8]) -> Result<Node> {
+        dag_json::decode(data)
+    }
+
+    /// Encodes the node as dag-json.
+    pub fn to_dag_json(&self) -> Result<Vec<u8>> {
+        dag_json::encode(self)
+    }
+
+    pub fn data(&self) -> Option<&[u8]> {
+        self.data.as_deref()
+    }
+
+    pub fn links(&self) -> &[Link] {
+        &self.links
+    }
+
+    /// Bytes of content under the node: its own data plus the sizes of its
+    /// links, unsized links counting for nothing.
+    pub fn content_size(&self) -> u64 {
+        let data = self.data.as_ref().map_or(0, |data| data.len() as u64);
+        data + self.links.iter().filter_map(|link| link.size).sum::<u64>()
+    }
+
+    /// Combines two nodes, concatenating their data and appending the links
+    /// of `other` whose CID is not already linked.
+    pub fn merge(&self, other: &Node) -> Node {
+        let data = match (&self.data, &other.data) {
+            (None, None) => None,
+            (a, b) => Some([a.as_deref().unwrap_or(&[]), b.as_deref().unwrap_or(&[])].concat()),
+        };
+        let mut seen = HashSet::new();
+        let links = self
+            .links
+            .iter()
+            .chain(&other.links)
+            .filter(|link| seen.insert(link.cid))
+            .cloned()
+            .collect();
+        Node { data, links }
+    }
+
+    /// Returns a copy of the node without the links whose CID is linked by
+    /// `other`.
+    pub fn subtract(&self, other: &Node) -> Node {
+        let removed: HashSet<&Cid> = other.links.iter().map(|link| &link.cid).collect();
+        Node {
+            data: self.data.clone(),
+            links: self
+                .links
+                .iter()
+                .filter(|link| !removed.contains(&link.cid))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Orders the links by name, links without a name going last.
+    pub fn sort_links_by_name(&mut self) {
+        self.links.sort_by(Link::cmp_by_name);
+    }
+
+    /// Orders the links by the bytes of their CID.
+    pub fn sort_links_by_cid(&mut self) {
+        self.links.sort_by_key(|link| link.cid.to_bytes());
+    }
+
+    /// Whether the links are in the order `sort_links_by_name` puts them.
+    pub fn is_sorted_by_name(&self) -> bool {
+        self.links
+            .windows(2)
+            .all(|pair| Link::cmp_by_name(&pair[0], &pair[1]) != Ordering::Greater)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Link {
     cid: Cid,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     name: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     size: Option<u64>,
 }
 
+impl Link {
+    pub fn cid(&self) -> &Cid {
+        &self.cid
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Links `cid` as standing for `size` bytes of content.
+    fn sized(cid: Cid, size: u64) -> Link {
+        Link {
+            cid,
+            name: None,
+            size: Some(size),
+        }
+    }
+
+    /// Link standing for `size` zero bytes that are never stored: its CID
+    /// is an identity multihash with an empty digest.
+    fn hole(size: u64) -> Result<Link> {
+        Ok(Link {
+            cid: Cid::new_v1(0x55, Multihash::wrap(0x00, &[])?),
+            name: None,
+            size: Some(size),
+        })
+    }
+
+    /// Whether the link stands for zeros rather than a stored block.
+    fn is_hole(&self) -> bool {
+        self.cid.hash().code() == 0x00 && self.cid.hash().digest().is_empty()
+    }
+
+    fn cmp_by_name(&self, other: &Link) -> Ordering {
+        match (&self.name, &other.name) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+}
+
 impl From<Cid> for Link {
     fn from(cid: Cid) -> Link {
         Link {
@@ -52,6 +212,8 @@ pub struct ChunkReader<R> {
     content_size: u64,
     chunk_size: usize,
     rem_size: u64,
+    consumed: u64,
+    chunks: usize,
 }
 
 impl<R: Read> ChunkReader<R> {
@@ -67,6 +229,8 @@ impl<R: Read> ChunkReader<R> {
             chunk_size: size,
             content_size: 0,
             rem_size: 0,
+            consumed: 0,
+            chunks: 0,
         }
     }
 
@@ -80,6 +244,35 @@ impl<R: Read> ChunkReader<R> {
         self.content_size = size;
         self.rem_size = size;
     }
+
+    /// Returns `(bytes_consumed, content_size)`. The content size is 0
+    /// unless `set_content_size` was called, so callers computing a ratio
+    /// must handle that case.
+    pub fn progress(&self) -> (u64, u64) {
+        (self.consumed, self.content_size)
+    }
+
+    /// Bytes left to read out of the content size, 0 unless
+    /// `set_content_size` was called.
+    pub fn remaining_bytes(&self) -> u64 {
+        self.rem_size
+    }
+
+    /// Number of chunks yielded so far.
+    pub fn chunks_consumed(&self) -> usize {
+        self.chunks
+    }
+
+    /// Calls `f` with `progress()` after each chunk is yielded.
+    pub fn with_progress<F: FnMut(u64, u64) + 'static>(
+        self,
+        f: F,
+    ) -> ChunkReaderWithProgress<R, F> {
+        ChunkReaderWithProgress {
+            reader: self,
+            callback: f,
+        }
+    }
 }
 
 impl<R: Read> ChunkReader<R> {
@@ -91,6 +284,46 @@ impl<R: Read> ChunkReader<R> {
         r.set_content_size(metadata.len());
         Ok(r)
     }
+
+    /// Moves to `offset` bytes from the start by reading and discarding
+    /// the bytes in between, for sources that cannot seek. Fails if
+    /// `offset` was already read past.
+    pub fn discard_to(&mut self, offset: u64) -> Result<()> {
+        if offset < self.consumed {
+            return Err(anyhow!(
+                "cannot move back to offset {} after reading {} bytes",
+                offset,
+                self.consumed
+            ));
+        }
+        let skip = offset - self.consumed;
+        let discarded = std::io::copy(&mut (&mut self.inner).take(skip), &mut std::io::sink())?;
+        if discarded != skip {
+            return Err(anyhow!("source ends before offset {}", offset));
+        }
+        self.skipped_to(offset);
+        Ok(())
+    }
+
+    /// Accounts for a move to `offset` as if every byte before it had been
+    /// chunked.
+    fn skipped_to(&mut self, offset: u64) {
+        self.consumed = offset;
+        self.rem_size = self.content_size.saturating_sub(offset);
+        self.chunks = offset.div_ceil(self.chunk_size as u64) as usize;
+    }
+}
+
+impl<R: Read + Seek> ChunkReader<R> {
+    /// Moves to `offset` bytes from the start without reading the bytes in
+    /// between, e.g. to resume a build. Chunks are then cut from `offset`,
+    /// and `chunks_consumed` counts the chunks before it, a partial one
+    /// included.
+    pub fn skip_to(&mut self, offset: u64) -> Result<()> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.skipped_to(offset);
+        Ok(())
+    }
 }
 
 impl<R: Read> Iterator for ChunkReader<R> {
@@ -108,7 +341,9 @@ impl<R: Read> Iterator for ChunkReader<R> {
             if n != self.chunk_size {
                 chunk.resize(n, 0);
             }
-            self.rem_size -= n as u64;
+            self.rem_size = self.rem_size.saturating_sub(n as u64);
+            self.consumed += n as u64;
+            self.chunks += 1;
             return Some(chunk);
         }
         None
@@ -119,24 +354,406 @@ impl<R: Read> Iterator for ChunkReader<R> {
         if self.rem_size == 0 {
             return (0, Some(0));
         }
-        let size = (self.rem_size / self.chunk_size as u64) as usize;
+        // the final chunk may be partial
+        let size = self.rem_size.div_ceil(self.chunk_size as u64) as usize;
         (size, Some(size))
     }
 }
 
+/// A `ChunkReader` reporting its progress to a callback, made with
+/// `ChunkReader::with_progress`.
+pub struct ChunkReaderWithProgress<R, F> {
+    reader: ChunkReader<R>,
+    callback: F,
+}
+
+impl<R: Read, F: FnMut(u64, u64)> Iterator for ChunkReaderWithProgress<R, F> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.reader.next()?;
+        let (consumed, total) = self.reader.progress();
+        (self.callback)(consumed, total);
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.reader.size_hint()
+    }
+}
+
+/// Chunks several sources as one continuous stream, so a chunk may span the
+/// end of one source and the start of the next.
+pub struct MultiSourceChunkReader<R> {
+    sources: VecDeque<R>,
+    chunk_size: usize,
+}
+
+impl<R: Read> MultiSourceChunkReader<R> {
+    /// Creates a new `MultiSourceChunkReader<R>` with a default chunk size.
+    pub fn new(sources: Vec<R>) -> MultiSourceChunkReader<R> {
+        MultiSourceChunkReader::with_chunk_size(DEFAULT_CHUNK_SIZE, sources)
+    }
+
+    /// Creates a new `MultiSourceChunkReader<R>` with a given chunk size.
+    pub fn with_chunk_size(size: usize, sources: Vec<R>) -> MultiSourceChunkReader<R> {
+        MultiSourceChunkReader {
+            sources: sources.into(),
+            chunk_size: size,
+        }
+    }
+
+    /// Changes the chunk size of the reader.
+    pub fn set_chunk_size(&mut self, size: usize) {
+        self.chunk_size = size;
+    }
+}
+
+impl MultiSourceChunkReader<File> {
+    /// Opens a reader over the concatenation of the files at `paths`.
+    pub fn from_files<P: AsRef<Path>>(paths: &[P]) -> Result<MultiSourceChunkReader<File>> {
+        let files = paths.iter().map(File::open).collect::<Result<_, _>>()?;
+        Ok(MultiSourceChunkReader::new(files))
+    }
+}
+
+impl<R: Read> Iterator for MultiSourceChunkReader<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = vec![0u8; self.chunk_size];
+        let mut filled = 0;
+
+        while filled < self.chunk_size {
+            let source = self.sources.front_mut()?;
+            match source.read(&mut chunk[filled..]) {
+                Ok(0) | Err(_) => {
+                    self.sources.pop_front();
+                    if self.sources.is_empty() {
+                        break;
+                    }
+                }
+                Ok(n) => filled += n,
+            }
+        }
+
+        if filled == 0 {
+            return None;
+        }
+        chunk.truncate(filled);
+        Some(chunk)
+    }
+}
+
+/// A source of chunks that can be assembled into a DAG. Any iterator of
+/// byte vectors is one.
+pub trait Chunker: Iterator<Item = Vec<u8>> {}
+
+impl<I: Iterator<Item = Vec<u8>>> Chunker for I {}
+
+/// Cuts a stream into chunks of the same size, the default strategy.
+pub type FixedSizeChunker<R> = ChunkReader<R>;
+
+/// Cuts a stream where its content says so rather than at fixed offsets,
+/// using a gear rolling hash as in FastCDC. Inserting or removing bytes
+/// only changes the chunks around the edit, so similar streams share most
+/// of their chunks.
+pub struct ContentDefinedChunker<R> {
+    inner: R,
+    min_size: usize,
+    max_size: usize,
+    mask: u64,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> ContentDefinedChunker<R> {
+    /// Creates a new `ContentDefinedChunker<R>` averaging the default chunk
+    /// size.
+    pub fn new(inner: R) -> ContentDefinedChunker<R> {
+        ContentDefinedChunker::with_chunk_sizes(
+            DEFAULT_CHUNK_SIZE / 4,
+            DEFAULT_CHUNK_SIZE,
+            DEFAULT_CHUNK_SIZE * 4,
+            inner,
+        )
+    }
+
+    /// Creates a new `ContentDefinedChunker<R>` cutting chunks of `min` to
+    /// `max` bytes, around `avg` bytes on average. `avg` is rounded up to a
+    /// power of two.
+    pub fn with_chunk_sizes(
+        min: usize,
+        avg: usize,
+        max: usize,
+        inner: R,
+    ) -> ContentDefinedChunker<R> {
+        assert!(
+            0 < min && min <= avg && avg <= max,
+            "chunk sizes must satisfy 0 < min <= avg <= max"
+        );
+        ContentDefinedChunker {
+            inner,
+            min_size: min,
+            max_size: max,
+            mask: (avg.next_power_of_two() - 1) as u64,
+            buf: Vec::with_capacity(max),
+            eof: false,
+        }
+    }
+
+    /// Length of the next chunk at the start of the buffer.
+    fn cut_point(&self) -> usize {
+        let end = self.buf.len().min(self.max_size);
+        if end <= self.min_size {
+            return end;
+        }
+        let mut hash = 0u64;
+        for (i, byte) in self.buf[self.min_size..end].iter().enumerate() {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & self.mask == 0 {
+                return self.min_size + i + 1;
+            }
+        }
+        end
+    }
+}
+
+impl<R: Read> Iterator for ContentDefinedChunker<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.eof && self.buf.len() < self.max_size {
+            let filled = self.buf.len();
+            self.buf.resize(self.max_size, 0);
+            match self.inner.read(&mut self.buf[filled..]) {
+                Ok(0) | Err(_) => {
+                    self.buf.truncate(filled);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(filled + n),
+            }
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let cut = self.cut_point();
+        Some(self.buf.drain(..cut).collect())
+    }
+}
+
+/// The FastCDC chunker from Xia et al.: a gear hash judged against a
+/// stricter mask before the average size and a looser one after it, which
+/// narrows the spread of chunk sizes compared to `ContentDefinedChunker`.
+pub struct FastCdcChunker<R> {
+    inner: R,
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    mask_small: u64,
+    mask_large: u64,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> FastCdcChunker<R> {
+    /// Creates a new `FastCdcChunker<R>` cutting chunks of `min` to `max`
+    /// bytes, normalized around `avg` bytes. `avg` is rounded up to a
+    /// power of two.
+    pub fn new(inner: R, min: usize, avg: usize, max: usize) -> FastCdcChunker<R> {
+        assert!(
+            0 < min && min <= avg && avg <= max,
+            "chunk sizes must satisfy 0 < min <= avg <= max"
+        );
+        let bits = avg.next_power_of_two().trailing_zeros();
+        FastCdcChunker {
+            inner,
+            min_size: min,
+            avg_size: avg,
+            max_size: max,
+            mask_small: high_bits(bits + 1),
+            mask_large: high_bits(bits.saturating_sub(1)),
+            buf: Vec::with_capacity(max),
+            eof: false,
+        }
+    }
+
+    /// Length of the next chunk at the start of the buffer.
+    fn cut_point(&self) -> usize {
+        let end = self.buf.len().min(self.max_size);
+        if end <= self.min_size {
+            return end;
+        }
+        let normal = end.min(self.avg_size);
+        let mut hash = 0u64;
+        for i in self.min_size..end {
+            hash = (hash << 1).wrapping_add(GEAR[self.buf[i] as usize]);
+            let mask = if i < normal {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+impl<R: Read> Iterator for FastCdcChunker<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.eof && self.buf.len() < self.max_size {
+            let filled = self.buf.len();
+            self.buf.resize(self.max_size, 0);
+            match self.inner.read(&mut self.buf[filled..]) {
+                Ok(0) | Err(_) => {
+                    self.buf.truncate(filled);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(filled + n),
+            }
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let cut = self.cut_point();
+        Some(self.buf.drain(..cut).collect())
+    }
+}
+
+/// A mask of the `n` most significant bits, the ones the gear hash mixes
+/// over the longest window.
+fn high_bits(n: u32) -> u64 {
+    match n {
+        0 => 0,
+        n if n >= 64 => u64::MAX,
+        n => !(u64::MAX >> n),
+    }
+}
+
+/// Random values mixed into the rolling hash, one per byte value.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so the table is fixed without being written out
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Chunks a text stream into groups of whole lines so chunk boundaries
+/// always align with line boundaries.
+pub struct LineChunker<R> {
+    inner: R,
+    lines_per_chunk: usize,
+}
+
+impl<R: BufRead> LineChunker<R> {
+    /// Creates a new `LineChunker<R>` with a default number of lines per chunk.
+    pub fn new(inner: R) -> LineChunker<R> {
+        LineChunker::with_lines_per_chunk(DEFAULT_LINES_PER_CHUNK, inner)
+    }
+
+    /// Creates a new `LineChunker<R>` with a given number of lines per chunk.
+    pub fn with_lines_per_chunk(lines: usize, inner: R) -> LineChunker<R> {
+        LineChunker {
+            inner,
+            lines_per_chunk: lines,
+        }
+    }
+
+    /// Changes the number of lines grouped in each chunk.
+    pub fn set_lines_per_chunk(&mut self, lines: usize) {
+        self.lines_per_chunk = lines;
+    }
+}
+
+impl<R: BufRead> Iterator for LineChunker<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = vec![];
+        for _ in 0..self.lines_per_chunk {
+            match self.inner.read_until(b'\n', &mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+        if chunk.is_empty() {
+            return None;
+        }
+        Some(chunk)
+    }
+}
+
 pub trait Storer {
     fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()>;
 }
 
+impl<S: Storer + ?Sized> Storer for &S {
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        (**self).put_keyed(k, block)
+    }
+}
+
+pub trait Getter {
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>>;
+}
+
+impl<G: Getter + ?Sized> Getter for &G {
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        (**self).get(k)
+    }
+}
+
+/// Validates a chunk before it is hashed and stored.
+type ChunkValidator = Rc<dyn Fn(&[u8]) -> Result<()>>;
+
+/// The codecs `DagBuilder::with_codec` can encode nodes with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DagCodec {
+    DagCbor,
+    DagJson,
+}
+
+/// Encodes an intermediate node, returning its codec and bytes.
+type NodeSerializer = Rc<dyn Fn(&Node) -> Result<(u64, Vec<u8>)>>;
+
+/// Hashes chunks and nodes.
+type HashFn = Arc<dyn Fn(&[u8]) -> Multihash + Send + Sync>;
+
+fn hash_fn<H: MultihashDigest<64>>(hasher: H) -> HashFn {
+    Arc::new(move |data| hasher.digest(data))
+}
+
 pub struct DagBuilder<C, S> {
     chunks: C,
     store: S,
     max_links: usize,
+    validator: Option<ChunkValidator>,
+    serializer: Option<NodeSerializer>,
+    cancel: Option<Arc<AtomicBool>>,
+    hash: HashFn,
+    names: Vec<String>,
+    // files added with `add_file`, with the leaves and bytes of each DAG
+    files: Vec<(directory::Entry, usize, u64)>,
 }
 
 impl<C, S> DagBuilder<C, S>
 where
-    C: Iterator<Item = Vec<u8>>,
+    C: Chunker,
     S: Storer,
 {
     pub fn new(chunks: C, store: S) -> DagBuilder<C, S> {
@@ -144,63 +761,1004 @@ where
             chunks,
             store,
             max_links: 11,
+            validator: None,
+            serializer: None,
+            cancel: None,
+            hash: hash_fn(Code::Sha2_256),
+            names: vec![],
+            files: vec![],
         }
     }
 
+    /// Same as `new`, hashing with BLAKE3 (multihash code 0x1e), which is
+    /// much faster than SHA2-256 on large chunks. IPFS gateways and most
+    /// IPFS implementations don't accept BLAKE3 CIDs yet, so this is best
+    /// kept for DAGs that stay in local storage.
+    pub fn with_blake3(chunks: C, store: S) -> DagBuilder<C, S> {
+        let mut builder = DagBuilder::new(chunks, store);
+        builder.set_hash_fn(Code::Blake3_256);
+        builder
+    }
+
+    /// Changes the multihash function used for the CIDs of both chunks and
+    /// intermediate nodes. Defaults to SHA2-256; `Code::Blake3_256`,
+    /// `Code::Sha3_256` and `Code::Blake2b256`, the hash Filecoin uses, are
+    /// supported as well.
+    pub fn set_hash_fn(&mut self, code: Code) {
+        self.hash = hash_fn(code);
+    }
+
+    /// Same as `set_hash_fn`, with any multihash implementation rather
+    /// than one of the built-in `Code`s, e.g. `hash::PoseidonHasher`.
+    pub fn with_hasher<H: MultihashDigest<64>>(mut self, hasher: H) -> Self {
+        self.hash = hash_fn(hasher);
+        self
+    }
+
+    /// Names the links `trickle` makes to the chunks after `names`, in
+    /// order. Chunks beyond the last name are linked unnamed.
+    pub fn with_named_links(mut self, names: Vec<String>) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Runs `f` on every chunk before it is hashed and stored. Building
+    /// fails with a `ChunkValidationError` on the first rejected chunk.
+    pub fn with_chunk_validator<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&[u8]) -> Result<()> + 'static,
+    {
+        self.validator = Some(Rc::new(f));
+        self
+    }
+
     pub fn trickle(&mut self) -> Result<DagInfo> {
+        Ok(self.build_trickle(None, None)?.0)
+    }
+
+    /// Same DAG as `trickle`, hashing chunks on rayon's thread pool. A
+    /// window of chunks is read ahead and hashed concurrently, then stored
+    /// in order from the calling thread since the store need not be `Sync`.
+    pub fn trickle_parallel(&mut self) -> Result<DagInfo> {
+        let window = PARALLEL_CHUNKS_PER_THREAD * rayon::current_num_threads();
+        let hash = self.hash.clone();
         let mut node = Node::with_links_cap(self.max_links);
-        while let Some(data) = self.chunks.next() {
-            let hash: Multihash = Code::Sha2_256.digest(&data);
-            let cid = Cid::new_v1(0x55, hash);
-            self.store.put_keyed(&cid, &data)?;
-            node.links.push(cid.into());
-        }
-        let enc = serde_ipld_dagcbor::to_vec(&node)?;
-        let root = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&enc));
-        self.store.put_keyed(&root, &enc)?;
+        let mut total_size = 0;
+        loop {
+            let chunks: Vec<Vec<u8>> = self.chunks.by_ref().take(window).collect();
+            if chunks.is_empty() {
+                break;
+            }
+            for (i, data) in chunks.iter().enumerate() {
+                self.validate(node.links.len() + i, data)?;
+            }
+            let hashed: Vec<(Vec<u8>, Cid)> = chunks
+                .into_par_iter()
+                .map(|data| {
+                    let cid = leaf_cid(&hash, &data);
+                    (data, cid)
+                })
+                .collect();
+            for (data, cid) in hashed {
+                self.store_leaf(&cid, &data)?;
+                total_size += data.len() as u64;
+                let mut link = Link::sized(cid, data.len() as u64);
+                link.name = self.names.get(node.links.len()).cloned();
+                node.links.push(link);
+            }
+        }
+        let (root, root_size) = self.store_node(&node)?;
         Ok(DagInfo {
             root,
             leaves: node.links.len(),
-            root_size: enc.len(),
+            root_size,
+            total_size: total_size + root_size as u64,
+            deduplicated_chunks: 0,
         })
     }
-}
-
-#[derive(Debug)]
-pub struct DagInfo {
-    pub root: Cid,
-    pub leaves: usize,
-    pub root_size: usize,
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rand::prelude::*;
-    use std::cell::RefCell;
-    use std::collections::HashMap;
-    use std::fs::File;
 
-    #[derive(Debug, Default, Clone)]
-    struct MemoryBlockstore {
-        blocks: RefCell<HashMap<Cid, Vec<u8>>>,
+    /// Builds a recursive trickle DAG as IPFS lays it out: each node links
+    /// up to `max_links` leaves, then `TRICKLE_DEPTH_REPEAT` subtrees of
+    /// depth 1, as many of depth 2, and so on up to one level less than
+    /// the node's own depth. Chunks are read in order, so a depth-first
+    /// walk of the links yields the content back. Fails if the content
+    /// does not fit under `max_depth` levels; `usize::MAX` never does.
+    pub fn trickle_with_depth(&mut self, max_depth: usize) -> Result<DagInfo> {
+        let mut next = self.chunks.next();
+        let mut leaves = 0;
+        let mut total_size = 0;
+        let (root, root_size) =
+            self.fill_trickle(&mut next, &mut leaves, &mut total_size, max_depth)?;
+        let root = root.cid;
+        if next.is_some() {
+            return Err(anyhow!(
+                "content does not fit in a trickle dag of depth {}",
+                max_depth
+            ));
+        }
+        Ok(DagInfo {
+            root,
+            leaves,
+            root_size,
+            total_size,
+            deduplicated_chunks: 0,
+        })
     }
 
-    impl MemoryBlockstore {
-        pub fn new() -> Self {
-            Self::default()
+    /// Builds a trickle subtree of at most `depth` levels from `next` and
+    /// the chunks after it, returning a link to it and its encoded size.
+    /// The size of every block stored is added to `total_size`.
+    fn fill_trickle(
+        &mut self,
+        next: &mut Option<Vec<u8>>,
+        leaves: &mut usize,
+        total_size: &mut u64,
+        depth: usize,
+    ) -> Result<(Link, usize)> {
+        let mut node = Node::with_links_cap(self.max_links);
+        while node.links.len() < self.max_links {
+            let Some(data) = next.take() else { break };
+            self.validate(*leaves, &data)?;
+            let cid = self.leaf_cid(&data);
+            self.store_leaf(&cid, &data)?;
+            node.links.push(Link::sized(cid, data.len() as u64));
+            *leaves += 1;
+            *total_size += data.len() as u64;
+            *next = self.chunks.next();
+        }
+        'fill: for subtree_depth in 1..depth {
+            for _ in 0..TRICKLE_DEPTH_REPEAT {
+                if next.is_none() {
+                    break 'fill;
+                }
+                let (link, _) = self.fill_trickle(next, leaves, total_size, subtree_depth)?;
+                node.links.push(link);
+            }
         }
+        let (cid, size) = self.store_node(&node)?;
+        *total_size += size as u64;
+        Ok((Link::sized(cid, node.content_size()), size))
     }
 
-    impl Storer for MemoryBlockstore {
-        fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
-            self.blocks.borrow_mut().insert(*k, block.into());
-            Ok(())
+    /// Builds a tree of logarithmic depth: leaves are grouped under nodes
+    /// of up to `max_links` links, those nodes under further nodes, and so
+    /// on until a single root remains. Where `trickle` links every leaf
+    /// from the root, a chunk here is reached by descending one node per
+    /// level, without loading the links of unrelated subtrees.
+    ///
+    /// ```text
+    /// trickle:        root              balanced:        root
+    ///           /  /  |  |  \  \                      /    \
+    ///          l0 l1 l2 .. l11 l12               n0      n1
+    ///                                          / .. \   /  \
+    ///                                         l0 .. l10 l11 l12
+    /// ```
+    ///
+    /// Up to `max_links` chunks, both build the same DAG.
+    pub fn balanced(&mut self) -> Result<DagInfo> {
+        let mut level = vec![];
+        let mut total_size = 0;
+        while let Some(data) = self.chunks.next() {
+            self.validate(level.len(), &data)?;
+            let cid = self.leaf_cid(&data);
+            self.store_leaf(&cid, &data)?;
+            level.push(Link::sized(cid, data.len() as u64));
+            total_size += data.len() as u64;
         }
-    }
+        let leaves = level.len();
 
-    #[test]
-    fn chunk_file() {
+        loop {
+            let mut parents = Vec::with_capacity(level.len().div_ceil(self.max_links));
+            let mut root_size = 0;
+            for group in level.chunks(self.max_links).chain(
+                // an empty input still gets a root
+                level.is_empty().then_some(&[][..]),
+            ) {
+                let node = Node {
+                    data: None,
+                    links: group.to_vec(),
+                };
+                let (cid, size) = self.store_node(&node)?;
+                parents.push(Link::sized(cid, node.content_size()));
+                root_size = size;
+                total_size += size as u64;
+            }
+            if parents.len() == 1 {
+                return Ok(DagInfo {
+                    root: parents[0].cid,
+                    leaves,
+                    root_size,
+                    total_size,
+                    deduplicated_chunks: 0,
+                });
+            }
+            level = parents;
+        }
+    }
+
+    /// Builds the DAG as a UnixFS file, in dag-pb rather than dag-cbor, so
+    /// IPFS nodes can serve it as is. Each chunk is a file leaf and the root
+    /// links them all, recording the total size; a single chunk is its own
+    /// root.
+    pub fn unixfs_file(&mut self) -> Result<DagInfo> {
+        let mut children = vec![];
+        while let Some(data) = self.chunks.next() {
+            self.validate(children.len(), &data)?;
+            let enc = unixfs::encode_leaf(&data);
+            let cid = Cid::new_v1(unixfs::DAG_PB, (self.hash)(&enc));
+            self.store_leaf(&cid, &enc)?;
+            children.push((cid, enc.len() as u64, data.len() as u64));
+        }
+        let leaves = children.len();
+        let mut total_size = children.iter().map(|(_, size, _)| size).sum();
+        let (root, root_size) = match children[..] {
+            [(cid, size, _)] => (cid, size as usize),
+            _ => {
+                let enc = unixfs::encode_node(&children);
+                let cid = Cid::new_v1(unixfs::DAG_PB, (self.hash)(&enc));
+                self.store.put_keyed(&cid, &enc)?;
+                total_size += enc.len() as u64;
+                (cid, enc.len())
+            }
+        };
+        Ok(DagInfo {
+            root,
+            leaves,
+            root_size,
+            total_size,
+            deduplicated_chunks: 0,
+        })
+    }
+
+    /// Same as `trickle`, also returning an inclusion proof for every leaf.
+    /// A leaf linked several times is proven through its first link.
+    pub fn trickle_with_proofs(&mut self) -> Result<(DagInfo, proof::ProofRegistry)> {
+        let (info, node) = self.build_trickle(None, None)?;
+        let mut proofs = proof::ProofRegistry::new();
+        for (position, link) in node.links.iter().enumerate() {
+            proofs
+                .entry(link.cid)
+                .or_insert_with(|| proof::MerkleProof {
+                    path: vec![(info.root, position as u32)],
+                });
+        }
+        Ok((info, proofs))
+    }
+
+    /// Stores a directory node linking each `(name, cid, size)` entry as is,
+    /// without reading any chunks, and returns its CID.
+    pub fn build_with_links(&self, links: Vec<directory::Entry>) -> Result<Cid> {
+        let node = Node {
+            data: None,
+            links: links
+                .into_iter()
+                .map(|(name, cid, size)| Link {
+                    cid,
+                    name: Some(name),
+                    size: Some(size),
+                })
+                .collect(),
+        };
+        Ok(self.store_node(&node)?.0)
+    }
+
+    /// Builds a DAG for every file under `dir` and links them all from a
+    /// directory node, naming each entry by its path relative to `dir`.
+    /// The builder's own chunks are left untouched.
+    pub fn build_from_dir<P: AsRef<Path>>(&self, dir: P) -> Result<DagInfo> {
+        let mut paths = vec![];
+        collect_files(dir.as_ref(), &mut paths)?;
+        paths.sort();
+
+        let mut node = Node::with_links_cap(paths.len());
+        let mut leaves = 0;
+        let mut total_size = 0;
+        for path in paths {
+            let reader = ChunkReader::<File>::from_file(&path)?;
+            let size = reader.content_size;
+            let file = self.child_builder(reader).trickle()?;
+            leaves += file.leaves;
+            total_size += file.total_size;
+
+            let name = path.strip_prefix(dir.as_ref())?;
+            let name = name
+                .iter()
+                .map(|part| part.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            node.links.push(Link {
+                cid: file.root,
+                name: Some(name),
+                size: Some(size),
+            });
+        }
+        let (root, root_size) = self.store_node(&node)?;
+        Ok(DagInfo {
+            root,
+            leaves,
+            root_size,
+            total_size: total_size + root_size as u64,
+            deduplicated_chunks: 0,
+        })
+    }
+
+    /// Builds a DAG from `reader` without storing zero-filled chunks: each
+    /// one is linked through an identity CID with an empty digest, the link
+    /// size telling how many zero bytes it stands for. The builder's own
+    /// chunks are left untouched. Every chunk is still read to find the
+    /// zeros; on Linux, `build_sparse_file` skips the holes of a file.
+    pub fn build_sparse<R: Read + Seek>(&self, mut reader: R) -> Result<DagInfo> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut chunks = ChunkReader::new(reader);
+        chunks.set_content_size(len);
+        self.link_sparse(chunks.map(|data| Ok((data.len() as u64, Some(data)))))
+    }
+
+    /// Same as `build_sparse`, looking up the holes of `file` with
+    /// `lseek(SEEK_DATA)` so chunks lying entirely in one are linked as
+    /// holes without being read. The DAG is the same `build_sparse` builds.
+    #[cfg(target_os = "linux")]
+    pub fn build_sparse_file(&self, mut file: &File) -> Result<DagInfo> {
+        let len = file.metadata()?.len();
+        let mut offset = 0;
+        // no data lies between `offset` and the next data offset found
+        let mut data_at = None;
+        let chunks = std::iter::from_fn(|| {
+            if offset >= len {
+                return None;
+            }
+            let size = (len - offset).min(DEFAULT_CHUNK_SIZE as u64);
+            let mut chunk = || -> Result<(u64, Option<Vec<u8>>)> {
+                if !matches!(data_at, Some(at) if at >= offset) {
+                    data_at = Some(next_data(file, offset, len)?);
+                }
+                if data_at >= Some(offset + size) {
+                    return Ok((size, None));
+                }
+                let mut data = vec![0; size as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut data)?;
+                Ok((size, Some(data)))
+            };
+            let chunk = chunk();
+            offset += size;
+            Some(chunk)
+        });
+        self.link_sparse(chunks)
+    }
+
+    /// Links the chunks of a sparse DAG from a single node, chunks without
+    /// data or holding only zeros as holes of their size.
+    fn link_sparse<I>(&self, chunks: I) -> Result<DagInfo>
+    where
+        I: Iterator<Item = Result<(u64, Option<Vec<u8>>)>>,
+    {
+        let mut node = Node::with_links_cap(self.max_links);
+        let mut total_size = 0;
+        for (chunk_index, chunk) in chunks.enumerate() {
+            let data = match chunk? {
+                (_, Some(data)) => data,
+                (size, None) => {
+                    if self.validator.is_some() {
+                        self.validate(chunk_index, &vec![0; size as usize])?;
+                    }
+                    node.links.push(Link::hole(size)?);
+                    continue;
+                }
+            };
+            self.validate(chunk_index, &data)?;
+            if data.iter().all(|byte| *byte == 0) {
+                node.links.push(Link::hole(data.len() as u64)?);
+                continue;
+            }
+            let cid = self.leaf_cid(&data);
+            self.store_leaf(&cid, &data)?;
+            node.links.push(Link::sized(cid, data.len() as u64));
+            total_size += data.len() as u64;
+        }
+        let (root, root_size) = self.store_node(&node)?;
+        Ok(DagInfo {
+            root,
+            leaves: node.links.len(),
+            root_size,
+            total_size: total_size + root_size as u64,
+            deduplicated_chunks: 0,
+        })
+    }
+
+    /// Builds a DAG of exactly `total_size` bytes, padding the chunks with
+    /// zeros. The last chunk read is padded up to the size of the first
+    /// one, further padding is linked as holes like in `build_sparse`.
+    /// Fails if the chunks add up to more than `total_size`.
+    pub fn build_padded(&mut self, total_size: u64) -> Result<DagInfo> {
+        let mut node = Node::with_links_cap(self.max_links);
+        let mut chunk_size = 0;
+        let mut read = 0;
+        let mut stored = 0;
+        let mut last: Option<Vec<u8>> = None;
+        while let Some(data) = self.chunks.next() {
+            self.validate(node.links.len() + last.is_some() as usize, &data)?;
+            read += data.len() as u64;
+            if read > total_size {
+                return Err(anyhow!(
+                    "content exceeds the padded size of {} bytes",
+                    total_size
+                ));
+            }
+            chunk_size = chunk_size.max(data.len() as u64);
+            if let Some(previous) = last.replace(data) {
+                let cid = self.leaf_cid(&previous);
+                self.store_leaf(&cid, &previous)?;
+                node.links.push(Link::sized(cid, previous.len() as u64));
+                stored += previous.len() as u64;
+            }
+        }
+        if chunk_size == 0 {
+            chunk_size = DEFAULT_CHUNK_SIZE as u64;
+        }
+
+        let mut padding = total_size - read;
+        if let Some(mut data) = last {
+            let fill = padding.min(chunk_size - data.len() as u64);
+            data.resize(data.len() + fill as usize, 0);
+            padding -= fill;
+            let cid = self.leaf_cid(&data);
+            self.store_leaf(&cid, &data)?;
+            node.links.push(Link::sized(cid, data.len() as u64));
+            stored += data.len() as u64;
+        }
+        while padding > 0 {
+            let size = padding.min(chunk_size);
+            node.links.push(Link::hole(size)?);
+            padding -= size;
+        }
+
+        let (root, root_size) = self.store_node(&node)?;
+        Ok(DagInfo {
+            root,
+            leaves: node.links.len(),
+            root_size,
+            total_size: stored + root_size as u64,
+            deduplicated_chunks: 0,
+        })
+    }
+
+    /// Builds a trickle DAG for the content of `reader` and queues it as a
+    /// directory entry named `name`, returning the file's root. The
+    /// builder's own chunks are left untouched.
+    pub fn add_file(&mut self, name: &str, reader: impl Read) -> Result<Cid> {
+        let mut size = 0;
+        let chunks = ChunkReader::new(reader).inspect(|chunk| size += chunk.len() as u64);
+        let file = self.child_builder(chunks).trickle()?;
+        self.files.push((
+            (name.to_string(), file.root, size),
+            file.leaves,
+            file.total_size,
+        ));
+        Ok(file.root)
+    }
+
+    /// Stores a directory node linking every file added with `add_file`
+    /// since the last call, in the order they were added.
+    pub fn finish_directory(&mut self) -> Result<DagInfo> {
+        let files = std::mem::take(&mut self.files);
+        let mut node = Node::with_links_cap(files.len());
+        let mut leaves = 0;
+        let mut total_size = 0;
+        for ((name, cid, size), file_leaves, file_size) in files {
+            leaves += file_leaves;
+            total_size += file_size;
+            node.links.push(Link {
+                cid,
+                name: Some(name),
+                size: Some(size),
+            });
+        }
+        let (root, root_size) = self.store_node(&node)?;
+        Ok(DagInfo {
+            root,
+            leaves,
+            root_size,
+            total_size: total_size + root_size as u64,
+            deduplicated_chunks: 0,
+        })
+    }
+
+    /// Same as `build_from_dir`, consuming the builder.
+    pub fn add_dir<P: AsRef<Path>>(self, dir: P) -> Result<DagInfo> {
+        self.build_from_dir(dir)
+    }
+
+    /// Skips storing chunks whose CID was already stored by this builder,
+    /// while still linking them from the root.
+    pub fn with_deduplication(self) -> DedupDagBuilder<C, S> {
+        DedupDagBuilder {
+            inner: self,
+            stored: HashSet::new(),
+        }
+    }
+
+    /// Calls `f` with the CID of each leaf as soon as it is stored, so a
+    /// caller can start sending leaves before the root is known.
+    pub fn with_leaf_callback<F: FnMut(Cid) + 'static>(
+        self,
+        f: F,
+    ) -> DagBuilderWithCallback<C, S, F> {
+        DagBuilderWithCallback {
+            inner: self,
+            callback: f,
+        }
+    }
+
+    /// Stops the build with a `Cancelled` error once `token` is set, right
+    /// after the chunk being processed is stored. Chunks stored so far
+    /// stay in the store.
+    pub fn with_cancel_token(mut self, token: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Encodes intermediate nodes with `f` rather than dag-cbor. The codec
+    /// returned alongside the bytes is used for the node's CID.
+    pub fn with_node_serializer<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Node) -> Result<(u64, Vec<u8>)> + 'static,
+    {
+        self.serializer = Some(Rc::new(f));
+        self
+    }
+
+    /// Encodes intermediate nodes with `codec`, replacing any serializer
+    /// set with `with_node_serializer`.
+    pub fn with_codec(mut self, codec: DagCodec) -> Self {
+        self.serializer = match codec {
+            DagCodec::DagCbor => None,
+            DagCodec::DagJson => Some(Rc::new(|node| Ok((DAG_JSON, node.to_dag_json()?)))),
+        };
+        self
+    }
+
+    /// Yields a partial root after every `max_links` chunks, each linking
+    /// all the chunks read so far, and finally the root of the whole DAG.
+    /// Items are paired with the number of chunks consumed.
+    pub fn trickle_lazy(self) -> impl Iterator<Item = Result<(usize, DagInfo)>> {
+        TrickleLazy {
+            node: Node::with_links_cap(self.max_links),
+            chunk_bytes: 0,
+            builder: self,
+            pending: true,
+            done: false,
+        }
+    }
+
+    fn validate(&self, chunk_index: usize, data: &[u8]) -> Result<()> {
+        match &self.validator {
+            Some(validator) => validator(data).map_err(|e| {
+                ChunkValidationError {
+                    chunk_index,
+                    reason: e.to_string(),
+                }
+                .into()
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn leaf_cid(&self, data: &[u8]) -> Cid {
+        leaf_cid(&self.hash, data)
+    }
+
+    /// A builder over `chunks` storing into the same store, with every
+    /// setting of this one but its link names, for the files of
+    /// `add_file` and `build_from_dir`.
+    fn child_builder<D: Chunker>(&self, chunks: D) -> DagBuilder<D, &S> {
+        DagBuilder {
+            chunks,
+            store: &self.store,
+            max_links: self.max_links,
+            validator: self.validator.clone(),
+            serializer: self.serializer.clone(),
+            cancel: self.cancel.clone(),
+            hash: self.hash.clone(),
+            names: vec![],
+            files: vec![],
+        }
+    }
+
+    /// Stores a leaf, then honours the cancel token.
+    fn store_leaf(&self, cid: &Cid, data: &[u8]) -> Result<()> {
+        self.store.put_keyed(cid, data)?;
+        match &self.cancel {
+            Some(token) if token.load(atomic::Ordering::Relaxed) => Err(Cancelled.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encodes and stores a node, returning its CID and encoded size.
+    fn store_node(&self, node: &Node) -> Result<(Cid, usize)> {
+        let (codec, enc) = match &self.serializer {
+            Some(serializer) => serializer(node)?,
+            None => (DAG_CBOR, node.to_dag_cbor()?),
+        };
+        let cid = Cid::new_v1(codec, (self.hash)(&enc));
+        self.store.put_keyed(&cid, &enc)?;
+        Ok((cid, enc.len()))
+    }
+
+    /// Builds the DAG, returning its root node along with its info.
+    /// `on_leaf` sees the CID of every leaf right after it is stored.
+    fn build_trickle(
+        &mut self,
+        mut stored: Option<&mut HashSet<Cid>>,
+        mut on_leaf: Option<&mut dyn FnMut(Cid)>,
+    ) -> Result<(DagInfo, Node)> {
+        let mut node = Node::with_links_cap(self.max_links);
+        let mut deduplicated_chunks = 0;
+        let mut total_size = 0;
+        while let Some(data) = self.chunks.next() {
+            self.validate(node.links.len(), &data)?;
+            let cid = self.leaf_cid(&data);
+            let duplicate = match stored.as_deref_mut() {
+                Some(stored) => !stored.insert(cid),
+                None => false,
+            };
+            if duplicate {
+                deduplicated_chunks += 1;
+            } else {
+                self.store_leaf(&cid, &data)?;
+                if let Some(on_leaf) = on_leaf.as_deref_mut() {
+                    on_leaf(cid);
+                }
+            }
+            total_size += data.len() as u64;
+            let mut link = Link::sized(cid, data.len() as u64);
+            link.name = self.names.get(node.links.len()).cloned();
+            node.links.push(link);
+        }
+        let (root, root_size) = self.store_node(&node)?;
+        let info = DagInfo {
+            root,
+            leaves: node.links.len(),
+            root_size,
+            total_size: total_size + root_size as u64,
+            deduplicated_chunks,
+        };
+        Ok((info, node))
+    }
+}
+
+/// The raw CID of a chunk hashed with `hash`.
+fn leaf_cid(hash: &HashFn, data: &[u8]) -> Cid {
+    Cid::new_v1(0x55, hash(data))
+}
+
+/// Reads back the content of the DAG under `root`, concatenating its leaves
+/// in link order. Works for every layout `DagBuilder` produces, holes from
+/// `build_sparse` and UnixFS files included.
+pub fn read_from_dag<S: Getter>(root: &Cid, store: &S) -> Result<Vec<u8>> {
+    let mut content = vec![];
+    read_block(root, store, &mut content, None)?;
+    Ok(content)
+}
+
+/// CIDs of the stored blocks `block` links to, in link order, `cid`
+/// naming the codec it is encoded with. dag-cbor, dag-json and dag-pb
+/// nodes are decoded; blocks of any other codec are leaves. Holes are
+/// left out, standing for zeros that are never stored.
+pub fn links_of(cid: &Cid, block: &[u8]) -> Result<Vec<Cid>> {
+    let node = match cid.codec() {
+        unixfs::DAG_PB => return Ok(unixfs::decode_node(block)?.0),
+        DAG_CBOR => Node::from_dag_cbor(block)?,
+        DAG_JSON => Node::from_dag_json(block)?,
+        _ => return Ok(vec![]),
+    };
+    Ok(node
+        .links
+        .into_iter()
+        .filter(|link| !link.is_hole())
+        .map(|link| link.cid)
+        .collect())
+}
+
+/// Appends the content under `cid` to `content`. `declared` is the size
+/// the link to it gives, if any, which its holes must fit within.
+fn read_block<S: Getter>(
+    cid: &Cid,
+    store: &S,
+    content: &mut Vec<u8>,
+    declared: Option<u64>,
+) -> Result<()> {
+    let block = store
+        .get(cid)?
+        .ok_or_else(|| anyhow!("block {} is missing from the store", cid))?;
+    if cid.codec() == unixfs::DAG_PB {
+        let (links, data) = unixfs::decode_node(&block)?;
+        content.extend_from_slice(&data);
+        for link in &links {
+            read_block(link, store, content, None)?;
+        }
+        return Ok(());
+    }
+    let node = match cid.codec() {
+        DAG_CBOR => Node::from_dag_cbor(&block)?,
+        DAG_JSON => Node::from_dag_json(&block)?,
+        _ => {
+            content.extend_from_slice(&block);
+            return Ok(());
+        }
+    };
+    // a root has no link declaring its size, so its own link sizes bound it
+    let declared = match declared {
+        Some(size) => size,
+        None => node
+            .links()
+            .iter()
+            .try_fold(
+                node.data().map_or(0, |data| data.len() as u64),
+                |sum, link| sum.checked_add(link.size.unwrap_or(0)),
+            )
+            .ok_or_else(|| anyhow!("the links of {} declare more than 2^64 bytes", cid))?,
+    };
+    let start = content.len();
+    if let Some(data) = node.data() {
+        content.extend_from_slice(data);
+    }
+    for link in node.links() {
+        match link.size {
+            Some(size) if link.is_hole() => {
+                let end = usize::try_from(size)
+                    .ok()
+                    .and_then(|size| content.len().checked_add(size))
+                    .filter(|end| (end - start) as u64 <= declared)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "hole of {} bytes in {} overruns its declared {} bytes",
+                            size,
+                            cid,
+                            declared
+                        )
+                    })?;
+                content.try_reserve(end - content.len())?;
+                content.resize(end, 0);
+            }
+            size => read_block(&link.cid, store, content, size)?,
+        }
+    }
+    Ok(())
+}
+
+/// The offset of the first data at or after `offset` in `file`, `len` when
+/// only holes follow. Filesystems without hole support report data
+/// everywhere.
+#[cfg(target_os = "linux")]
+fn next_data(file: &File, offset: u64, len: u64) -> Result<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    let at = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+    if at >= 0 {
+        return Ok(at as u64);
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENXIO) => Ok(len),
+        Some(libc::EINVAL) => Ok(offset),
+        _ => Err(err.into()),
+    }
+}
+
+/// Appends the path of every file under `dir` to `paths`.
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+struct TrickleLazy<C, S> {
+    builder: DagBuilder<C, S>,
+    node: Node,
+    chunk_bytes: u64,
+    // whether chunks were read since the last yielded root
+    pending: bool,
+    done: bool,
+}
+
+impl<C, S> TrickleLazy<C, S>
+where
+    C: Chunker,
+    S: Storer,
+{
+    fn step(&mut self) -> Result<Option<(usize, DagInfo)>> {
+        let builder = &mut self.builder;
+        while let Some(data) = builder.chunks.next() {
+            builder.validate(self.node.links.len(), &data)?;
+            let cid = builder.leaf_cid(&data);
+            builder.store_leaf(&cid, &data)?;
+            self.node.links.push(Link::sized(cid, data.len() as u64));
+            self.chunk_bytes += data.len() as u64;
+            self.pending = true;
+            if self.node.links.len().is_multiple_of(builder.max_links) {
+                return self.emit().map(Some);
+            }
+        }
+        self.done = true;
+        if self.pending {
+            return self.emit().map(Some);
+        }
+        Ok(None)
+    }
+
+    fn emit(&mut self) -> Result<(usize, DagInfo)> {
+        self.pending = false;
+        let (root, root_size) = self.builder.store_node(&self.node)?;
+        let leaves = self.node.links.len();
+        let info = DagInfo {
+            root,
+            leaves,
+            root_size,
+            total_size: self.chunk_bytes + root_size as u64,
+            deduplicated_chunks: 0,
+        };
+        Ok((leaves, info))
+    }
+}
+
+impl<C, S> Iterator for TrickleLazy<C, S>
+where
+    C: Chunker,
+    S: Storer,
+{
+    type Item = Result<(usize, DagInfo)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step();
+        if item.is_err() {
+            self.done = true;
+        }
+        item.transpose()
+    }
+}
+
+/// A `DagBuilder` that stores each distinct chunk only once.
+pub struct DedupDagBuilder<C, S> {
+    inner: DagBuilder<C, S>,
+    stored: HashSet<Cid>,
+}
+
+impl<C, S> DedupDagBuilder<C, S>
+where
+    C: Chunker,
+    S: Storer,
+{
+    pub fn trickle(&mut self) -> Result<DagInfo> {
+        Ok(self.inner.build_trickle(Some(&mut self.stored), None)?.0)
+    }
+}
+
+/// A `DagBuilder` reporting each leaf CID as it goes.
+pub struct DagBuilderWithCallback<C, S, F> {
+    inner: DagBuilder<C, S>,
+    callback: F,
+}
+
+impl<C, S, F> DagBuilderWithCallback<C, S, F>
+where
+    C: Chunker,
+    S: Storer,
+    F: FnMut(Cid),
+{
+    pub fn trickle(&mut self) -> Result<DagInfo> {
+        Ok(self.inner.build_trickle(None, Some(&mut self.callback))?.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DagInfo {
+    pub root: Cid,
+    pub leaves: usize,
+    pub root_size: usize,
+    /// Bytes of every block making up the DAG: the chunks and the encoded
+    /// nodes linking them. Holes count for nothing.
+    pub total_size: u64,
+    pub deduplicated_chunks: usize,
+}
+
+/// A chunk rejected by the validator set with
+/// `DagBuilder::with_chunk_validator`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkValidationError {
+    pub chunk_index: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ChunkValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "chunk {} is invalid: {}", self.chunk_index, self.reason)
+    }
+}
+
+impl std::error::Error for ChunkValidationError {}
+
+/// A build stopped through the token set with `DagBuilder::with_cancel_token`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dag build cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{prop_assert, prop_assert_eq, proptest};
+    use rand::prelude::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::rc::Rc;
+
+    /// A store for the tests of every module.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct MemoryBlockstore {
+        pub(crate) blocks: RefCell<HashMap<Cid, Vec<u8>>>,
+    }
+
+    impl MemoryBlockstore {
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    impl Storer for MemoryBlockstore {
+        fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+            self.blocks.borrow_mut().insert(*k, block.into());
+            Ok(())
+        }
+    }
+
+    impl Getter for MemoryBlockstore {
+        fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+            Ok(self.blocks.borrow().get(k).cloned())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingBlockstore {
+        inner: MemoryBlockstore,
+        puts: std::cell::Cell<usize>,
+    }
+
+    impl Storer for CountingBlockstore {
+        fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+            self.puts.set(self.puts.get() + 1);
+            self.inner.put_keyed(k, block)
+        }
+    }
+
+    /// Chunks `bytes` with its content size set, as the trickle builders
+    /// need it.
+    fn sized_chunks(chunk_size: usize, bytes: &[u8]) -> ChunkReader<&[u8]> {
+        let mut reader = ChunkReader::with_chunk_size(chunk_size, bytes);
+        reader.set_content_size(bytes.len() as u64);
+        reader
+    }
+
+    #[test]
+    fn chunk_file() {
         let dir = env!("CARGO_MANIFEST_DIR");
         let mut reader =
             ChunkReader::<File>::from_file(format!("{}/src/fixture.txt", dir)).unwrap();
@@ -223,6 +1781,993 @@ mod tests {
         }
     }
 
+    #[test]
+    fn chunk_multiple_files() {
+        let dir = std::env::temp_dir().join(format!("wiresaw_multi_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<_> = [1000usize, 2500, 700]
+            .iter()
+            .enumerate()
+            .map(|(i, len)| {
+                let path = dir.join(format!("part{}", i));
+                fs::write(&path, vec![i as u8; *len]).unwrap();
+                path
+            })
+            .collect();
+
+        let mut reader = MultiSourceChunkReader::from_files(&paths).unwrap();
+        reader.set_chunk_size(1 << 10);
+        let chunks: Vec<Vec<u8>> = reader.collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let sizes: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![1024, 1024, 1024, 1024, 104]);
+        let bytes = chunks.concat();
+        assert_eq!(bytes.len(), 4200);
+        // the first chunk crosses from the first file into the second
+        assert_eq!(&chunks[0][999..1001], &[0, 1]);
+        assert_eq!(bytes[3500..], vec![2u8; 700][..]);
+    }
+
+    #[test]
+    fn chunk_lines() {
+        let path = format!("{}/src/fixture.txt", env!("CARGO_MANIFEST_DIR"));
+        let text = fs::read(&path).unwrap();
+
+        let mut reader = ChunkReader::<File>::from_file(&path).unwrap();
+        reader.set_chunk_size(1 << 9);
+        let fixed = DagBuilder::new(reader, MemoryBlockstore::new())
+            .trickle()
+            .unwrap();
+
+        let file = File::open(&path).unwrap();
+        let chunks: Vec<Vec<u8>> =
+            LineChunker::with_lines_per_chunk(2, BufReader::new(file)).collect();
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            assert_eq!(chunk.last(), Some(&b'\n'));
+            assert_eq!(chunk.iter().filter(|b| **b == b'\n').count(), 2);
+        }
+        assert!(last.iter().filter(|b| **b == b'\n').count() <= 2);
+        assert_eq!(chunks.concat(), text);
+
+        let lines = LineChunker::with_lines_per_chunk(2, &text[..]);
+        let by_line = DagBuilder::new(lines, MemoryBlockstore::new())
+            .trickle()
+            .unwrap();
+        assert_eq!(by_line.leaves, chunks.len());
+        assert_ne!(fixed.root, by_line.root);
+    }
+
+    proptest! {
+        #[test]
+        fn chunk_size_hint(len in 0usize..1 << 16, chunk_size in 1usize..4096) {
+            let bytes = vec![1u8; len];
+            let mut reader = ChunkReader::with_chunk_size(chunk_size, &bytes[..]);
+            reader.set_content_size(len as u64);
+            loop {
+                let (_, upper) = reader.size_hint();
+                let upper = upper.unwrap();
+                if reader.next().is_none() {
+                    prop_assert_eq!(upper, 0);
+                    break;
+                }
+                prop_assert!(upper >= 1);
+            }
+            let mut reader = ChunkReader::with_chunk_size(chunk_size, &bytes[..]);
+            reader.set_content_size(len as u64);
+            let hint = reader.size_hint().1.unwrap();
+            prop_assert_eq!(reader.count(), hint);
+        }
+    }
+
+    #[test]
+    fn chunk_with_progress() {
+        let bytes = vec![7u8; 2500];
+        let reports = Rc::new(RefCell::new(vec![]));
+        let sink = reports.clone();
+        let reader = sized_chunks(1000, &bytes);
+        let chunks = reader
+            .with_progress(move |consumed, total| sink.borrow_mut().push((consumed, total)))
+            .count();
+        assert_eq!(chunks, 3);
+        assert_eq!(
+            *reports.borrow(),
+            vec![(1000, 2500), (2000, 2500), (2500, 2500)]
+        );
+
+        // without a content size, only the consumed bytes are known
+        let mut reader = ChunkReader::with_chunk_size(1000, &bytes[..]);
+        reader.next();
+        assert_eq!(reader.progress(), (1000, 0));
+    }
+
+    #[test]
+    fn chunk_accessors() {
+        let bytes = vec![7u8; 2500];
+        let mut reader = sized_chunks(1000, &bytes);
+        assert_eq!(
+            (reader.remaining_bytes(), reader.chunks_consumed()),
+            (2500, 0)
+        );
+        reader.next();
+        assert_eq!(
+            (reader.remaining_bytes(), reader.chunks_consumed()),
+            (1500, 1)
+        );
+        reader.by_ref().count();
+        assert_eq!((reader.remaining_bytes(), reader.chunks_consumed()), (0, 3));
+
+        let mut reader = ChunkReader::with_chunk_size(1000, &bytes[..]);
+        assert_eq!(reader.remaining_bytes(), 0);
+        reader.next();
+        assert_eq!((reader.remaining_bytes(), reader.chunks_consumed()), (0, 1));
+    }
+
+    #[test]
+    fn chunk_skip_to() {
+        let dir = std::env::temp_dir().join(format!("wiresaw_skip_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        let mut bytes = vec![0u8; 1 << 20];
+        thread_rng().fill(&mut bytes[..]);
+        fs::write(&path, &bytes).unwrap();
+
+        let mut reader = ChunkReader::<File>::from_file(&path).unwrap();
+        reader.set_chunk_size(1 << 16);
+        let all: Vec<Vec<u8>> = reader.collect();
+
+        let mut reader = ChunkReader::<File>::from_file(&path).unwrap();
+        reader.set_chunk_size(1 << 16);
+        reader.skip_to(262144).unwrap();
+        assert_eq!(reader.remaining_bytes(), (1 << 20) - 262144);
+        assert_eq!(reader.chunks_consumed(), 4);
+        assert_eq!(reader.size_hint(), (12, Some(12)));
+        assert_eq!(reader.collect::<Vec<_>>(), all[4..]);
+
+        let mut reader = sized_chunks(1 << 16, &bytes);
+        reader.next();
+        reader.discard_to(262144).unwrap();
+        assert_eq!(reader.chunks_consumed(), 4);
+        assert!(reader.discard_to(0).is_err());
+        assert_eq!(reader.collect::<Vec<_>>(), all[4..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chunk_content_defined() {
+        let mut bytes = vec![0u8; 1 << 20];
+        StdRng::seed_from_u64(7).fill(&mut bytes[..]);
+        let chunker =
+            || ContentDefinedChunker::with_chunk_sizes(1 << 10, 4 << 10, 16 << 10, &bytes[..]);
+        let chunks: Vec<Vec<u8>> = chunker().collect();
+        assert_eq!(chunks.concat(), bytes);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| (1 << 10..=16 << 10).contains(&c.len())));
+        assert!(last.len() <= 16 << 10);
+        assert!(chunks.len() > 100);
+
+        // an edit near the start only changes the chunks around it
+        let mut edited = b"inserted".to_vec();
+        edited.extend_from_slice(&bytes);
+        let shifted: HashSet<Vec<u8>> =
+            ContentDefinedChunker::with_chunk_sizes(1 << 10, 4 << 10, 16 << 10, &edited[..])
+                .collect();
+        let shared = chunks.iter().filter(|c| shifted.contains(*c)).count();
+        assert!(shared >= chunks.len() - 2);
+
+        let store = MemoryBlockstore::new();
+        let info = DagBuilder::new(chunker(), &store).trickle().unwrap();
+        assert_eq!(info.leaves, chunks.len());
+        let mut fixed = FixedSizeChunker::with_chunk_size(4 << 10, &bytes[..]);
+        fixed.set_content_size(bytes.len() as u64);
+        let fixed = DagBuilder::new(fixed, &store).trickle().unwrap();
+        assert_ne!(info.root, fixed.root);
+    }
+
+    #[test]
+    fn chunk_fastcdc() {
+        let mut bytes = vec![0u8; 1 << 20];
+        StdRng::seed_from_u64(11).fill(&mut bytes[..]);
+        let chunks: Vec<Vec<u8>> =
+            FastCdcChunker::new(&bytes[..], 2 << 10, 8 << 10, 32 << 10).collect();
+        assert_eq!(chunks.concat(), bytes);
+        let (_, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|c| (2 << 10..=32 << 10).contains(&c.len())));
+        let mean = bytes.len() / chunks.len();
+        assert!(
+            (4 << 10..=16 << 10).contains(&mean),
+            "mean chunk size {}",
+            mean
+        );
+
+        let mut edited = bytes[..1000].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&bytes[1000..]);
+        let shifted: HashSet<Vec<u8>> =
+            FastCdcChunker::new(&edited[..], 2 << 10, 8 << 10, 32 << 10).collect();
+        let shared = chunks.iter().filter(|c| shifted.contains(*c)).count();
+        assert!(shared >= chunks.len() - 2);
+    }
+
+    fn node_with_links(data: Option<&[u8]>, seeds: std::ops::Range<u8>) -> Node {
+        Node {
+            data: data.map(|d| d.to_vec()),
+            links: seeds
+                .map(|i| Cid::new_v1(0x55, Code::Sha2_256.digest(&[i])).into())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn merge_nodes() {
+        let a = node_with_links(Some(b"left "), 0..5);
+        let b = node_with_links(None, 3..8);
+
+        let merged = a.merge(&b);
+        assert!(merged.links.len() <= 10);
+        assert_eq!(merged.links, node_with_links(None, 0..8).links);
+        assert_eq!(merged.data(), Some(&b"left "[..]));
+
+        let rest = merged.subtract(&b);
+        assert_eq!(rest.links, node_with_links(None, 0..3).links);
+        assert_eq!(a.subtract(&a).links.len(), 0);
+    }
+
+    #[test]
+    fn sort_links() {
+        let named = |name: Option<&str>, i: u8| Link {
+            cid: Cid::new_v1(0x55, Code::Sha2_256.digest(&[i])),
+            name: name.map(String::from),
+            size: None,
+        };
+        let sorted = Node {
+            data: None,
+            links: vec![
+                named(Some("alpha"), 0),
+                named(Some("beta"), 1),
+                named(Some("gamma"), 2),
+                named(None, 3),
+            ],
+        };
+        assert!(sorted.is_sorted_by_name());
+
+        let mut node = sorted.clone();
+        node.links.shuffle(&mut thread_rng());
+        node.sort_links_by_name();
+        assert!(node.is_sorted_by_name());
+        let cid = |node: &Node| Code::Sha2_256.digest(&node.to_dag_cbor().unwrap());
+        assert_eq!(cid(&node), cid(&sorted));
+
+        node.links.reverse();
+        assert!(!node.is_sorted_by_name());
+        node.sort_links_by_cid();
+        let keys: Vec<Vec<u8>> = node.links.iter().map(|link| link.cid.to_bytes()).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn build_deduplicated() {
+        let mut bytes = vec![0u8; 8 << 10];
+        thread_rng().fill(&mut bytes[..]);
+        bytes.extend_from_within(..);
+
+        let reader = sized_chunks(1 << 10, &bytes);
+
+        let store = CountingBlockstore::default();
+        let info = DagBuilder::new(reader, &store)
+            .with_deduplication()
+            .trickle()
+            .unwrap();
+
+        assert_eq!(info.leaves, 16);
+        assert_eq!(info.deduplicated_chunks, 8);
+        assert_eq!(store.puts.get(), 9);
+        assert!(store.inner.blocks.borrow().len() - 1 < info.leaves);
+    }
+
+    #[test]
+    fn build_trickle_parallel() {
+        let mut bytes = vec![0u8; 100 << 10];
+        thread_rng().fill(&mut bytes[..]);
+
+        let store = MemoryBlockstore::new();
+        let parallel = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .trickle_parallel()
+            .unwrap();
+        let sequential = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .trickle()
+            .unwrap();
+        assert_eq!(parallel.root, sequential.root);
+        assert_eq!(parallel.leaves, 100);
+        assert_eq!(parallel.total_size, sequential.total_size);
+        assert_eq!(read_from_dag(&parallel.root, &store).unwrap(), bytes);
+    }
+
+    #[test]
+    fn build_with_leaf_callback() {
+        let mut bytes = vec![0u8; 8 << 10];
+        thread_rng().fill(&mut bytes[..]);
+        let reader = sized_chunks(1 << 10, &bytes);
+
+        let store = MemoryBlockstore::new();
+        let emitted = Rc::new(RefCell::new(vec![]));
+        let sink = emitted.clone();
+        let info = DagBuilder::new(reader, &store)
+            .with_leaf_callback(move |cid| sink.borrow_mut().push(cid))
+            .trickle()
+            .unwrap();
+
+        let root = Node::from_dag_cbor(&store.get(&info.root).unwrap().unwrap()).unwrap();
+        let linked: Vec<Cid> = root.links().iter().map(|link| link.cid).collect();
+        assert_eq!(*emitted.borrow(), linked);
+        assert_eq!(linked.len(), info.leaves);
+    }
+
+    #[test]
+    fn build_directory() {
+        let store = MemoryBlockstore::new();
+        let entries: Vec<directory::Entry> = ["a.txt", "b.txt", "c.bin"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let data = vec![i as u8; 100 * (i + 1)];
+                let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+                store.put_keyed(&cid, &data).unwrap();
+                (name.to_string(), cid, data.len() as u64)
+            })
+            .collect();
+
+        let builder = DagBuilder::new(std::iter::empty(), &store);
+        let root = builder.build_with_links(entries.clone()).unwrap();
+
+        assert_eq!(directory::list(&root, &store).unwrap(), entries);
+        assert!(directory::list(&entries[0].1, &store).is_err());
+    }
+
+    #[test]
+    fn build_multi_file_directory() {
+        let store = MemoryBlockstore::new();
+        let mut builder = DagBuilder::new(std::iter::empty(), &store);
+        let big = vec![7u8; DEFAULT_CHUNK_SIZE * 2 + 5];
+        let readme = builder.add_file("README", &b"read me"[..]).unwrap();
+        let data = builder.add_file("data.bin", &big[..]).unwrap();
+        let info = builder.finish_directory().unwrap();
+
+        assert_eq!(
+            directory::list(&info.root, &store).unwrap(),
+            vec![
+                ("README".to_string(), readme, 7),
+                ("data.bin".to_string(), data, big.len() as u64),
+            ]
+        );
+        assert_eq!(info.leaves, 4);
+        assert_eq!(read_from_dag(&data, &store).unwrap(), big);
+        assert_eq!(read_from_dag(&readme, &store).unwrap(), b"read me");
+
+        // entries don't carry over to the next directory
+        let empty = builder.finish_directory().unwrap();
+        assert!(directory::list(&empty.root, &store).unwrap().is_empty());
+    }
+
+    #[test]
+    fn files_inherit_builder_settings() {
+        let store = MemoryBlockstore::new();
+        let big = vec![7u8; DEFAULT_CHUNK_SIZE * 2 + 5];
+        let mut builder = DagBuilder::new(std::iter::empty(), &store).with_codec(DagCodec::DagJson);
+        builder.set_hash_fn(Code::Blake3_256);
+        let data = builder.add_file("data.bin", &big[..]).unwrap();
+        assert_eq!(data.codec(), DAG_JSON);
+        assert_eq!(data.hash().code(), u64::from(Code::Blake3_256));
+        assert_eq!(read_from_dag(&data, &store).unwrap(), big);
+
+        let dir = std::env::temp_dir().join(format!("wiresaw_inherit_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.bin"), &big).unwrap();
+        let info = builder.build_from_dir(&dir).unwrap();
+        let root = store.get(&info.root).unwrap().unwrap();
+        assert_eq!(links_of(&info.root, &root).unwrap(), vec![data]);
+
+        let token = Arc::new(AtomicBool::new(true));
+        let mut builder = DagBuilder::new(std::iter::empty(), &store).with_cancel_token(token);
+        let err = builder.add_file("data.bin", &big[..]).unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+        let err = builder.build_from_dir(&dir).unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn build_trickle_lazy() {
+        let mut bytes = vec![0u8; 30 << 10];
+        thread_rng().fill(&mut bytes[..]);
+
+        let store = MemoryBlockstore::new();
+        let full = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .trickle()
+            .unwrap();
+
+        let partial: Vec<(usize, DagInfo)> = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .trickle_lazy()
+            .collect::<Result<_>>()
+            .unwrap();
+        let consumed: Vec<usize> = partial.iter().map(|(n, _)| *n).collect();
+        assert_eq!(consumed, vec![11, 22, 30]);
+
+        let (_, last) = partial.last().unwrap();
+        assert_eq!(last.root, full.root);
+        assert_eq!(last.leaves, full.leaves);
+        for (_, info) in &partial {
+            assert!(store.blocks.borrow().contains_key(&info.root));
+        }
+
+        let empty: Vec<_> = DagBuilder::new(std::iter::empty(), &store)
+            .trickle_lazy()
+            .collect();
+        assert_eq!(empty.len(), 1);
+    }
+
+    #[test]
+    fn build_with_chunk_validator() {
+        let mut bytes = vec![b'a'; 10 << 10];
+        bytes[5000..5009].copy_from_slice(b"FORBIDDEN");
+        let reader = sized_chunks(1 << 10, &bytes);
+
+        let store = MemoryBlockstore::new();
+        let err = DagBuilder::new(reader, &store)
+            .with_chunk_validator(|chunk| {
+                if chunk.windows(9).any(|w| w == b"FORBIDDEN") {
+                    return Err(anyhow::anyhow!("forbidden pattern"));
+                }
+                Ok(())
+            })
+            .trickle()
+            .unwrap_err();
+        let err = err.downcast_ref::<ChunkValidationError>().unwrap();
+        assert_eq!(err.chunk_index, 4);
+        assert_eq!(err.reason, "forbidden pattern");
+    }
+
+    #[test]
+    fn build_with_node_serializer() {
+        let bytes = vec![7u8; 4 << 10];
+
+        let store = MemoryBlockstore::new();
+        let default = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .trickle()
+            .unwrap();
+        let custom = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .with_node_serializer(|node| Ok((0x0129, node.to_dag_cbor()?)))
+            .trickle()
+            .unwrap();
+
+        assert_eq!(default.root.codec(), DAG_CBOR);
+        assert_eq!(custom.root.codec(), 0x0129);
+        assert_eq!(custom.root.hash(), default.root.hash());
+        assert_eq!(
+            store.get(&custom.root).unwrap(),
+            store.get(&default.root).unwrap()
+        );
+
+        // raw leaves under a UnixFS root, the way kubo lays out a file
+        let dag_pb = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .with_node_serializer(|node| {
+                let children: Vec<(Cid, u64, u64)> = node
+                    .links()
+                    .iter()
+                    .map(|link| {
+                        let size = link.size().unwrap_or(0);
+                        (*link.cid(), size, size)
+                    })
+                    .collect();
+                Ok((unixfs::DAG_PB, unixfs::encode_node(&children)))
+            })
+            .trickle()
+            .unwrap();
+        assert_eq!(dag_pb.root.codec(), unixfs::DAG_PB);
+        let root = store.get(&dag_pb.root).unwrap().unwrap();
+        assert_eq!(
+            links_of(&dag_pb.root, &root).unwrap(),
+            links_of(&default.root, &store.get(&default.root).unwrap().unwrap()).unwrap()
+        );
+        assert_eq!(read_from_dag(&dag_pb.root, &store).unwrap(), bytes);
+    }
+
+    #[test]
+    fn build_with_dag_json() {
+        let mut bytes = vec![0u8; 30 << 10];
+        thread_rng().fill(&mut bytes[..]);
+        let reader = sized_chunks(1 << 10, &bytes);
+
+        let store = MemoryBlockstore::new();
+        let info = DagBuilder::new(reader, &store)
+            .with_codec(DagCodec::DagJson)
+            .balanced()
+            .unwrap();
+        assert_eq!(info.root.codec(), DAG_JSON);
+
+        let enc = store.get(&info.root).unwrap().unwrap();
+        let node = Node::from_dag_json(&enc).unwrap();
+        assert_eq!(node.to_dag_json().unwrap(), enc);
+        assert!(node
+            .links()
+            .iter()
+            .all(|link| link.cid().codec() == DAG_JSON));
+        assert_eq!(read_from_dag(&info.root, &store).unwrap(), bytes);
+    }
+
+    #[test]
+    fn build_from_dir() {
+        let dir = std::env::temp_dir().join(format!("wiresaw_dir_{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let files = [
+            ("a.bin", vec![1u8; 300 << 10]),
+            ("b.bin", vec![2u8; 10]),
+            ("nested/c.bin", vec![3u8; 1000]),
+        ];
+        for (name, bytes) in &files {
+            fs::write(dir.join(name), bytes).unwrap();
+        }
+
+        let store = MemoryBlockstore::new();
+        let info = DagBuilder::new(std::iter::empty(), &store)
+            .add_dir(&dir)
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(info.leaves, 4);
+
+        let entries = directory::list(&info.root, &store).unwrap();
+        assert_eq!(entries.len(), 3);
+        for (name, bytes) in &files {
+            let (_, cid, size) = entries.iter().find(|(n, _, _)| n == name).unwrap();
+            assert_eq!(*size, bytes.len() as u64);
+            assert_eq!(&read_from_dag(cid, &store).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn read_rejects_oversized_holes() {
+        let store = MemoryBlockstore::new();
+        let put = |links: Vec<Link>| {
+            let enc = Node { data: None, links }.to_dag_cbor().unwrap();
+            let cid = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&enc));
+            store.put_keyed(&cid, &enc).unwrap();
+            cid
+        };
+
+        let child = put(vec![Link::hole(1 << 20).unwrap()]);
+        let honest = put(vec![Link::sized(child, 1 << 20)]);
+        assert_eq!(read_from_dag(&honest, &store).unwrap(), vec![0; 1 << 20]);
+
+        // the link to the child claims less than the child's hole
+        let understated = put(vec![Link::sized(child, 16)]);
+        assert!(read_from_dag(&understated, &store).is_err());
+        let overflowing = put(vec![Link::hole(u64::MAX).unwrap(), Link::hole(1).unwrap()]);
+        assert!(read_from_dag(&overflowing, &store).is_err());
+        let unallocatable = put(vec![Link::hole(u64::MAX).unwrap()]);
+        assert!(read_from_dag(&unallocatable, &store).is_err());
+    }
+
+    #[test]
+    fn build_sparse() {
+        let path = std::env::temp_dir().join(format!("wiresaw_sparse_{}", std::process::id()));
+        let mut data = vec![0u8; 5 << 20];
+        thread_rng().fill(&mut data[..]);
+        fs::write(&path, &data).unwrap();
+        // extending the file leaves a 5 MiB hole at its end
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(10 << 20)
+            .unwrap();
+
+        let stored = |store: &MemoryBlockstore| -> usize {
+            store
+                .blocks
+                .borrow()
+                .values()
+                .map(|block| block.len())
+                .sum()
+        };
+        let sparse_store = MemoryBlockstore::new();
+        let sparse = DagBuilder::new(std::iter::empty(), &sparse_store)
+            .build_sparse(File::open(&path).unwrap())
+            .unwrap();
+        let dense_store = MemoryBlockstore::new();
+        let dense = DagBuilder::new(ChunkReader::<File>::from_file(&path).unwrap(), &dense_store)
+            .trickle()
+            .unwrap();
+        #[cfg(target_os = "linux")]
+        {
+            let file_store = MemoryBlockstore::new();
+            let from_file = DagBuilder::new(std::iter::empty(), &file_store)
+                .build_sparse_file(&File::open(&path).unwrap())
+                .unwrap();
+            assert_eq!(from_file.root, sparse.root);
+            assert_eq!(*file_store.blocks.borrow(), *sparse_store.blocks.borrow());
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(sparse.leaves, dense.leaves);
+        assert!(stored(&sparse_store) < stored(&dense_store));
+        let node = Node::from_dag_cbor(&sparse_store.get(&sparse.root).unwrap().unwrap()).unwrap();
+        let holes: u64 = node
+            .links()
+            .iter()
+            .filter(|link| link.cid().hash().code() == 0x00)
+            .map(|link| link.size().unwrap())
+            .sum();
+        assert_eq!(holes, 5 << 20);
+    }
+
+    #[test]
+    fn trickle_link_fields() {
+        let bytes = vec![5u8; 2500];
+        let store = MemoryBlockstore::new();
+
+        let info = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .with_named_links(vec!["a".into(), "b".into()])
+            .trickle()
+            .unwrap();
+        let root = Node::from_dag_cbor(&store.get(&info.root).unwrap().unwrap()).unwrap();
+        let sizes: Vec<_> = root.links().iter().map(Link::size).collect();
+        assert_eq!(sizes, vec![Some(1024), Some(1024), Some(452)]);
+        let names: Vec<_> = root.links().iter().map(Link::name).collect();
+        assert_eq!(names, vec![Some("a"), Some("b"), None]);
+        assert_eq!(root.content_size(), 2500);
+
+        // the optional fields survive encoding, and are left out when unset
+        assert_eq!(
+            Node::from_dag_cbor(&root.to_dag_cbor().unwrap()).unwrap(),
+            root
+        );
+        let bare = Node {
+            data: None,
+            links: vec![root.links()[0].cid.into()],
+        };
+        let enc = bare.to_dag_cbor().unwrap();
+        assert_eq!(Node::from_dag_cbor(&enc).unwrap(), bare);
+        assert!(enc.len() < root.to_dag_cbor().unwrap().len() / 3);
+
+        let unnamed = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .trickle()
+            .unwrap();
+        assert_ne!(unnamed.root, info.root);
+    }
+
+    #[test]
+    fn build_unixfs_file() {
+        let store = MemoryBlockstore::new();
+        let empty = DagBuilder::new(std::iter::empty(), &store)
+            .unixfs_file()
+            .unwrap();
+        let v0 = |cid: &Cid| Cid::new_v0(*cid.hash()).unwrap().to_string();
+        // the CIDs `ipfs add` gives these files
+        assert_eq!(
+            v0(&empty.root),
+            "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+        );
+        let hello = DagBuilder::new(std::iter::once(b"hello world\n".to_vec()), &store)
+            .unixfs_file()
+            .unwrap();
+        assert_eq!(
+            v0(&hello.root),
+            "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o"
+        );
+
+        let mut bytes = vec![0u8; 100 << 10];
+        thread_rng().fill(&mut bytes[..]);
+        let reader = sized_chunks(16 << 10, &bytes);
+        let info = DagBuilder::new(reader, &store).unixfs_file().unwrap();
+        assert_eq!(info.root.codec(), unixfs::DAG_PB);
+        assert_eq!(info.leaves, 7);
+        assert_eq!(read_from_dag(&info.root, &store).unwrap(), bytes);
+    }
+
+    #[test]
+    fn build_padded() {
+        let mut bytes = vec![0u8; 2500];
+        thread_rng().fill(&mut bytes[..]);
+        let store = MemoryBlockstore::new();
+        let leaf_sizes = |info: &DagInfo| -> Vec<u64> {
+            let node = Node::from_dag_cbor(&store.get(&info.root).unwrap().unwrap()).unwrap();
+            node.links()
+                .iter()
+                .map(|link| match link.size() {
+                    Some(size) => size,
+                    None => store.get(link.cid()).unwrap().unwrap().len() as u64,
+                })
+                .collect()
+        };
+
+        let info = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .build_padded(10 << 10)
+            .unwrap();
+        assert_eq!(info.leaves, 10);
+        assert_eq!(leaf_sizes(&info), vec![1024; 10]);
+        // only the three chunks holding content and the root were stored
+        assert_eq!(store.blocks.borrow().len(), 4);
+
+        let info = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .build_padded(10_000)
+            .unwrap();
+        assert_eq!(info.leaves, 10);
+        assert_eq!(*leaf_sizes(&info).last().unwrap(), 10_000 - 9 * 1024);
+
+        assert!(DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .build_padded(2000)
+            .is_err());
+    }
+
+    #[test]
+    fn build_cancelled() {
+        let mut bytes = vec![0u8; 1 << 20];
+        thread_rng().fill(&mut bytes[..]);
+        let reader = sized_chunks(1 << 10, &bytes);
+
+        let token = Arc::new(AtomicBool::new(false));
+        let cancel = token.clone();
+        let chunks = reader.enumerate().map(move |(i, chunk)| {
+            if i == 9 {
+                cancel.store(true, atomic::Ordering::Relaxed);
+            }
+            chunk
+        });
+
+        let store = MemoryBlockstore::new();
+        let err = DagBuilder::new(chunks, &store)
+            .with_cancel_token(token)
+            .trickle()
+            .unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+        assert_eq!(store.blocks.borrow().len(), 10);
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn build_with_poseidon() {
+        use crate::hash::{PoseidonHasher, POSEIDON_CODE};
+
+        let bytes: Vec<u8> = (0..8u32 << 10).map(|i| (i % 253) as u8).collect();
+        let build = || {
+            let reader = sized_chunks(1 << 10, &bytes);
+            let store = MemoryBlockstore::new();
+            let info = DagBuilder::new(reader, &store)
+                .with_hasher(PoseidonHasher)
+                .trickle()
+                .unwrap();
+            (info, store)
+        };
+        let (info, store) = build();
+        assert_eq!(info.root, build().0.root);
+        for (cid, block) in store.blocks.borrow().iter() {
+            assert_eq!(cid.hash().code(), POSEIDON_CODE);
+            assert_eq!(*cid.hash(), PoseidonHasher.digest(block));
+        }
+        // varint of 0x30b401, then the digest size
+        assert_eq!(
+            &info.root.hash().to_bytes()[..5],
+            &[0x81, 0xe8, 0xc2, 0x01, 32]
+        );
+    }
+
+    #[test]
+    fn build_with_proofs() {
+        let mut bytes = vec![0u8; 20 << 10];
+        thread_rng().fill(&mut bytes[..]);
+        let reader = sized_chunks(1 << 10, &bytes);
+
+        let store = MemoryBlockstore::new();
+        let (info, proofs) = DagBuilder::new(reader, &store)
+            .trickle_with_proofs()
+            .unwrap();
+        assert_eq!(proofs.len(), info.leaves);
+        for (leaf, proof) in &proofs {
+            assert!(proof.verify(leaf, &info.root, &store).unwrap());
+        }
+
+        let (leaf, proof) = proofs.iter().next().unwrap();
+        let (other, _) = proofs.iter().find(|(cid, _)| *cid != leaf).unwrap();
+        assert!(!proof.verify(other, &info.root, &store).unwrap());
+        assert!(!proof.verify(leaf, other, &store).unwrap());
+    }
+
+    #[test]
+    fn build_trickle_with_depth() {
+        // 11 leaves under the root, 4 depth 1 subtrees of 11 leaves, and 4
+        // depth 2 subtrees of 11 leaves and 4 depth 1 subtrees each
+        let fits = 11 + 4 * 11 + 4 * (11 + 4 * 11);
+        // fixed content, so the roots are pinned and a layout change shows
+        let bytes: Vec<u8> = (0..(fits + 1) << 8).map(|i| (i % 251) as u8).collect();
+        let chunks = |len: usize| sized_chunks(1 << 8, &bytes[..len << 8]);
+        let store = MemoryBlockstore::new();
+
+        let info = DagBuilder::new(chunks(fits), &store)
+            .trickle_with_depth(3)
+            .unwrap();
+        assert_eq!(info.leaves, fits);
+        assert_eq!(
+            info.root.to_string(),
+            "bafyreifnn5gap3ekymoqz332p7e6nq2eiehy4z2og7vccnkdnpubpdag3a"
+        );
+        let root = Node::from_dag_cbor(&store.get(&info.root).unwrap().unwrap()).unwrap();
+        assert_eq!(root.links().len(), 11 + 2 * 4);
+        assert!(root.links()[..11]
+            .iter()
+            .all(|link| link.cid.codec() == 0x55));
+        let subtree =
+            Node::from_dag_cbor(&store.get(&root.links()[18].cid).unwrap().unwrap()).unwrap();
+        assert_eq!(subtree.links().len(), 11 + 4);
+
+        assert_eq!(
+            read_from_dag(&info.root, &store).unwrap(),
+            bytes[..fits << 8]
+        );
+
+        assert!(DagBuilder::new(chunks(fits + 1), &store)
+            .trickle_with_depth(3)
+            .is_err());
+        let unbounded = DagBuilder::new(chunks(fits + 1), &store)
+            .trickle_with_depth(usize::MAX)
+            .unwrap();
+        assert_eq!(unbounded.leaves, fits + 1);
+        assert_eq!(
+            unbounded.root.to_string(),
+            "bafyreifhmecw6qtmhx4y34hb3dnlhogwxyd6mtwzoa2bpq3vja7x2nhswm"
+        );
+
+        assert_eq!(
+            DagBuilder::new(chunks(11), &store)
+                .trickle_with_depth(1)
+                .unwrap()
+                .root,
+            DagBuilder::new(chunks(11), &store).trickle().unwrap().root
+        );
+    }
+
+    #[test]
+    fn build_with_hash_fn() {
+        let mut bytes = vec![0u8; 64 << 10];
+        thread_rng().fill(&mut bytes[..]);
+
+        let sha2_store = MemoryBlockstore::new();
+        let sha2 = DagBuilder::new(sized_chunks(4 << 10, &bytes), &sha2_store)
+            .trickle()
+            .unwrap();
+        let mut blocks = vec![];
+        for code in [Code::Blake3_256, Code::Sha3_256] {
+            let store = MemoryBlockstore::new();
+            let mut builder = DagBuilder::new(sized_chunks(4 << 10, &bytes), &store);
+            builder.set_hash_fn(code);
+            let info = builder.trickle().unwrap();
+            assert_ne!(info.root, sha2.root);
+            assert_eq!(info.root.hash().code(), u64::from(code));
+            if code == Code::Blake3_256 {
+                let blake3 =
+                    DagBuilder::with_blake3(sized_chunks(4 << 10, &bytes), MemoryBlockstore::new())
+                        .trickle()
+                        .unwrap();
+                assert_eq!(blake3.root, info.root);
+                assert_eq!(info.root.hash().code(), 0x1e);
+            }
+
+            let mut stored: Vec<Vec<u8>> = store.blocks.borrow().values().cloned().collect();
+            stored.sort();
+            blocks.push(stored);
+        }
+        let mut stored: Vec<Vec<u8>> = sha2_store.blocks.borrow().values().cloned().collect();
+        stored.sort();
+        // leaves are the same bytes, only the links in the root differ
+        let leaves = |blocks: &[Vec<u8>]| {
+            blocks
+                .iter()
+                .filter(|block| block.len() == 4 << 10)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(leaves(&blocks[0]), leaves(&stored));
+        assert_eq!(leaves(&blocks[1]), leaves(&stored));
+        assert_eq!(blocks[0].len(), stored.len());
+    }
+
+    #[test]
+    fn build_with_blake2b() {
+        let store = MemoryBlockstore::new();
+        let mut builder = DagBuilder::new(std::iter::once(b"abc".to_vec()), &store);
+        builder.set_hash_fn(Code::Blake2b256);
+        let info = builder.trickle().unwrap();
+        assert_eq!(info.root.hash().code(), 0xb220);
+
+        // BLAKE2b-256 of "abc", as golang.org/x/crypto/blake2b computes it
+        let digest = [
+            0xbd, 0xdd, 0x81, 0x3c, 0x63, 0x42, 0x39, 0x72, 0x31, 0x71, 0xef, 0x3f, 0xee, 0x98,
+            0x57, 0x9b, 0x94, 0x96, 0x4e, 0x3b, 0xb1, 0xcb, 0x3e, 0x42, 0x72, 0x62, 0xc8, 0xc0,
+            0x68, 0xd5, 0x23, 0x19,
+        ];
+        let leaf = Cid::new_v1(0x55, Multihash::wrap(0xb220, &digest).unwrap());
+        assert_eq!(store.get(&leaf).unwrap(), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn read_back_dag() {
+        let mut bytes = vec![0u8; 4 << 20];
+        thread_rng().fill(&mut bytes[..]);
+        let store = MemoryBlockstore::new();
+
+        let trickle = DagBuilder::new(sized_chunks(16 << 10, &bytes), &store)
+            .trickle()
+            .unwrap();
+        assert_eq!(read_from_dag(&trickle.root, &store).unwrap(), bytes);
+        let balanced = DagBuilder::new(sized_chunks(16 << 10, &bytes), &store)
+            .balanced()
+            .unwrap();
+        assert_eq!(read_from_dag(&balanced.root, &store).unwrap(), bytes);
+        let recursive = DagBuilder::new(sized_chunks(16 << 10, &bytes), &store)
+            .trickle_with_depth(usize::MAX)
+            .unwrap();
+        assert_eq!(read_from_dag(&recursive.root, &store).unwrap(), bytes);
+
+        let mut sparse = vec![0u8; 3 * DEFAULT_CHUNK_SIZE];
+        sparse[DEFAULT_CHUNK_SIZE..][..5].copy_from_slice(b"hello");
+        let info = DagBuilder::new(std::iter::empty(), &store)
+            .build_sparse(std::io::Cursor::new(&sparse))
+            .unwrap();
+        assert_eq!(read_from_dag(&info.root, &store).unwrap(), sparse);
+
+        let missing = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(b"missing"));
+        assert!(read_from_dag(&missing, &store).is_err());
+    }
+
+    #[test]
+    fn build_balanced() {
+        let mut bytes = vec![0u8; 200 << 10];
+        thread_rng().fill(&mut bytes[..]);
+
+        let store = MemoryBlockstore::new();
+        let info = DagBuilder::new(sized_chunks(1 << 10, &bytes), &store)
+            .balanced()
+            .unwrap();
+        assert_eq!(info.leaves, 200);
+
+        // 200 leaves under 19 nodes, under 2 nodes, under the root
+        let mut level = vec![info.root];
+        let mut depth = 0;
+        while level.iter().all(|cid| cid.codec() == DAG_CBOR) {
+            let nodes: Vec<Node> = level
+                .iter()
+                .map(|cid| Node::from_dag_cbor(&store.get(cid).unwrap().unwrap()).unwrap())
+                .collect();
+            assert!(nodes.iter().all(|node| node.links().len() <= 11));
+            level = nodes
+                .iter()
+                .flat_map(|node| node.links().iter().map(|link| link.cid))
+                .collect();
+            depth += 1;
+        }
+        assert_eq!(depth, 3);
+        assert_eq!(level.len(), 200);
+        assert_eq!(read_from_dag(&info.root, &store).unwrap(), bytes);
+
+        let balanced = DagBuilder::new(sized_chunks(1 << 10, &bytes[..8 << 10]), &store)
+            .balanced()
+            .unwrap();
+        let trickle = DagBuilder::new(sized_chunks(1 << 10, &bytes[..8 << 10]), &store)
+            .trickle()
+            .unwrap();
+        assert_eq!(balanced.root, trickle.root);
+        assert_eq!(
+            DagBuilder::new(std::iter::empty(), &store)
+                .balanced()
+                .unwrap()
+                .root,
+            DagBuilder::new(std::iter::empty(), &store)
+                .trickle()
+                .unwrap()
+                .root
+        );
+    }
+
     #[test]
     fn build_trickle() {
         let mut bytes = vec![0u8; 1 << 20];
@@ -237,5 +2782,13 @@ mod tests {
         let mut dag = DagBuilder::new(reader, store);
         let root = dag.trickle().expect("failed to compute trickle dag");
         println!("root {:?}", root);
+        assert!(root.total_size >= bytes.len() as u64);
+        assert_eq!(root.total_size, (bytes.len() + root.root_size) as u64);
+
+        let reader = sized_chunks(1 << 10, &bytes);
+        let balanced = DagBuilder::new(reader, MemoryBlockstore::new())
+            .balanced()
+            .unwrap();
+        assert!(balanced.total_size > (bytes.len() + balanced.root_size) as u64);
     }
 }
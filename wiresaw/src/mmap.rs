@@ -0,0 +1,151 @@
+//! Chunking memory-mapped files, so chunks are slices of the mapping
+//! rather than copies read through a buffer.
+
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::slice;
+
+use crate::DEFAULT_CHUNK_SIZE;
+
+/// A file mapped read-only into memory, unmapped on drop.
+pub struct MappedFile {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// the mapping is read-only, it may be read from any thread
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    /// Maps the whole file at `path`.
+    ///
+    /// # Safety
+    ///
+    /// The mapping is shared with the file, so the file must not be written
+    /// to or truncated, by this process or any other, while the returned
+    /// `MappedFile` is alive. Changes would show through the `&[u8]` it
+    /// derefs to, and reading past a truncated end raises `SIGBUS`.
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<MappedFile> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            // empty mappings are invalid, an empty file maps to nothing
+            return Ok(MappedFile {
+                ptr: NonNull::dangling(),
+                len,
+            });
+        }
+        let ptr = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return Err(anyhow!("mmap failed: {}", std::io::Error::last_os_error()));
+        }
+        Ok(MappedFile {
+            ptr: NonNull::new(ptr as *mut u8).expect("mmap returned null"),
+            len,
+        })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len);
+            }
+        }
+    }
+}
+
+/// Yields fixed-size slices of a `MappedFile`, the last one possibly
+/// shorter. Feeding a `DagBuilder` costs one copy per chunk, through
+/// `.map(<[u8]>::to_vec)`.
+pub struct MmapChunkReader<'a> {
+    data: &'a [u8],
+    chunk_size: usize,
+}
+
+impl<'a> MmapChunkReader<'a> {
+    /// Creates a new `MmapChunkReader` with a default chunk size.
+    pub fn new(file: &'a MappedFile) -> MmapChunkReader<'a> {
+        MmapChunkReader::with_chunk_size(DEFAULT_CHUNK_SIZE, file)
+    }
+
+    /// Creates a new `MmapChunkReader` with a given chunk size.
+    pub fn with_chunk_size(size: usize, file: &'a MappedFile) -> MmapChunkReader<'a> {
+        MmapChunkReader {
+            data: file,
+            chunk_size: size,
+        }
+    }
+}
+
+impl<'a> Iterator for MmapChunkReader<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let (chunk, rest) = self.data.split_at(self.chunk_size.min(self.data.len()));
+        self.data = rest;
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let size = self.data.len().div_ceil(self.chunk_size);
+        (size, Some(size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkReader;
+    use rand::prelude::*;
+    use std::fs;
+
+    #[test]
+    fn chunk_mapped_file() {
+        let dir = std::env::temp_dir().join(format!("wiresaw_mmap_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        let mut bytes = vec![0u8; (1 << 20) + 123];
+        thread_rng().fill(&mut bytes[..]);
+        fs::write(&path, &bytes).unwrap();
+
+        // the file is only written once it is unmapped
+        let file = unsafe { MappedFile::open(&path) }.unwrap();
+        let reader = MmapChunkReader::with_chunk_size(1 << 16, &file);
+        assert_eq!(reader.size_hint(), (17, Some(17)));
+        let mapped: Vec<Vec<u8>> = reader.map(<[u8]>::to_vec).collect();
+        let mut read = ChunkReader::<File>::from_file(&path).unwrap();
+        read.set_chunk_size(1 << 16);
+        assert_eq!(mapped, read.collect::<Vec<_>>());
+
+        drop(file);
+        fs::write(&path, b"").unwrap();
+        let empty = unsafe { MappedFile::open(&path) }.unwrap();
+        assert_eq!(MmapChunkReader::new(&empty).count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
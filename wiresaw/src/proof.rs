@@ -0,0 +1,40 @@
+//! Inclusion proofs tying a leaf to the root of the DAG built over it.
+
+use crate::{Getter, Node};
+use anyhow::{anyhow, Result};
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use std::collections::HashMap;
+
+/// The proof of every leaf of a DAG, keyed by leaf CID.
+pub type ProofRegistry = HashMap<Cid, MerkleProof>;
+
+/// The chain of nodes linking a leaf up to a root. Each step names a node
+/// and the position, within that node's links, of the step below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub path: Vec<(Cid, u32)>,
+}
+
+impl MerkleProof {
+    /// Checks that every node along the path is intact, links the step
+    /// below it at the recorded position, and that the path ends at
+    /// `root`.
+    pub fn verify(&self, leaf: &Cid, root: &Cid, store: &impl Getter) -> Result<bool> {
+        let mut current = *leaf;
+        for (cid, position) in &self.path {
+            let block = store
+                .get(cid)?
+                .ok_or_else(|| anyhow!("proof node {} not found", cid))?;
+            if Code::try_from(cid.hash().code())?.digest(&block) != *cid.hash() {
+                return Ok(false);
+            }
+            let node = Node::from_dag_cbor(&block)?;
+            match node.links().get(*position as usize) {
+                Some(link) if *link.cid() == current => current = *cid,
+                _ => return Ok(false),
+            }
+        }
+        Ok(current == *root)
+    }
+}
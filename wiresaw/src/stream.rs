@@ -0,0 +1,106 @@
+//! Building DAGs over asynchronous byte streams, chunk by chunk, without
+//! buffering the whole content.
+
+use crate::{DagInfo, Link, Node, Storer, DAG_CBOR, DEFAULT_CHUNK_SIZE};
+use anyhow::Result;
+use cid::multihash::{Code, MultihashDigest};
+use cid::Cid;
+use futures_lite::io::{AsyncRead, AsyncReadExt};
+
+/// Same layout as `DagBuilder::trickle`, reading from an `AsyncRead`.
+/// Chunks are hashed on the blocking thread pool so large chunks don't
+/// stall the executor.
+pub struct AsyncDagBuilder<R, S> {
+    reader: R,
+    store: S,
+    chunk_size: usize,
+}
+
+impl<R, S> AsyncDagBuilder<R, S>
+where
+    R: AsyncRead + Unpin,
+    S: Storer,
+{
+    pub fn new(reader: R, store: S) -> AsyncDagBuilder<R, S> {
+        AsyncDagBuilder::with_chunk_size(DEFAULT_CHUNK_SIZE, reader, store)
+    }
+
+    /// Creates a new `AsyncDagBuilder<R, S>` with a given chunk size.
+    pub fn with_chunk_size(size: usize, reader: R, store: S) -> AsyncDagBuilder<R, S> {
+        AsyncDagBuilder {
+            reader,
+            store,
+            chunk_size: size,
+        }
+    }
+
+    pub async fn trickle(&mut self) -> Result<DagInfo> {
+        let mut node = Node::with_links_cap(11);
+        let mut total_size = 0;
+        while let Some(chunk) = self.read_chunk().await? {
+            let (chunk, cid) = blocking::unblock(move || {
+                let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&chunk));
+                (chunk, cid)
+            })
+            .await;
+            self.store.put_keyed(&cid, &chunk)?;
+            node.links.push(Link::sized(cid, chunk.len() as u64));
+            total_size += chunk.len() as u64;
+        }
+        let enc = node.to_dag_cbor()?;
+        let root = Cid::new_v1(DAG_CBOR, Code::Sha2_256.digest(&enc));
+        self.store.put_keyed(&root, &enc)?;
+        Ok(DagInfo {
+            root,
+            leaves: node.links.len(),
+            root_size: enc.len(),
+            total_size: total_size + enc.len() as u64,
+            deduplicated_chunks: 0,
+        })
+    }
+
+    /// Reads up to a full chunk, stopping short only at the end of the
+    /// stream.
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut chunk = vec![0u8; self.chunk_size];
+        let mut filled = 0;
+        while filled < self.chunk_size {
+            match self.reader.read(&mut chunk[filled..]).await? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            return Ok(None);
+        }
+        chunk.truncate(filled);
+        Ok(Some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::MemoryBlockstore;
+    use crate::{ChunkReader, DagBuilder};
+    use futures_lite::future::block_on;
+    use rand::prelude::*;
+
+    #[test]
+    fn async_trickle() {
+        let mut bytes = vec![0u8; (1 << 20) + 100];
+        thread_rng().fill(&mut bytes[..]);
+
+        let mut reader = ChunkReader::new(&bytes[..]);
+        reader.set_content_size(bytes.len() as u64);
+        let expected = DagBuilder::new(reader, MemoryBlockstore::default())
+            .trickle()
+            .unwrap();
+
+        let store = MemoryBlockstore::default();
+        let info = block_on(AsyncDagBuilder::new(&bytes[..], &store).trickle()).unwrap();
+        assert_eq!(info.root, expected.root);
+        assert_eq!(info.leaves, 5);
+        assert_eq!(store.blocks.borrow().len(), 6);
+    }
+}